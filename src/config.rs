@@ -0,0 +1,444 @@
+//! Config file of targets and shared options, which is written in a subset of TOML. Only
+//! integers, basic and literal strings, and the array of tables `target` are supported.
+//!
+//! ```toml
+//! interval = 1000
+//! timeout = 1000
+//! host = "www.google.com"
+//! type = "A"
+//!
+//! [[target]]
+//! server = "8.8.8.8"
+//!
+//! [[target]]
+//! server = "1.1.1.1"
+//! host = "www.example.com"
+//! type = "AAAA"
+//! ```
+
+use dns_parser::QueryType;
+use std::collections::HashSet;
+#[cfg(unix)]
+use std::ffi::CString;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Represents a transport given in the scheme of a server URL.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+/// Represents a server to ping with its own host and query type.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Target {
+    pub server: IpAddr,
+    pub scope_id: u32,
+    pub port: Option<u16>,
+    pub transport: Option<Transport>,
+    pub host: Option<String>,
+    pub query_type: Option<QueryType>,
+}
+
+impl Target {
+    pub fn new(server: IpAddr) -> Target {
+        Target {
+            server,
+            scope_id: 0,
+            port: None,
+            transport: None,
+            host: None,
+            query_type: None,
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+    /// Parses a server address, or a server URL like `udp://8.8.8.8:53` or `tcp://[::1]` whose
+    /// scheme and port override the transport and the port of the flags.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (transport, s) = match s.find("://") {
+            Some(index) => (Some(parse_transport(&s[..index])?), &s[index + 3..]),
+            None => (None, s),
+        };
+        let (server, port) = match transport {
+            Some(_) => split_port(s)?,
+            None => (s, None),
+        };
+        let (server, scope_id) = parse_server(server)?;
+
+        Ok(Target {
+            scope_id,
+            port,
+            transport,
+            ..Target::new(server)
+        })
+    }
+}
+
+fn parse_transport(s: &str) -> Result<Transport, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "udp" => Ok(Transport::Udp),
+        "tcp" => Ok(Transport::Tcp),
+        "tls" | "https" => Err(format!("unsupported transport {}", s)),
+        _ => Err(format!("unknown transport {}", s)),
+    }
+}
+
+/// Splits the authority of a server URL into the server and the optional port. An IPv6 server
+/// with a port is enclosed in brackets.
+fn split_port(s: &str) -> Result<(&str, Option<u16>), String> {
+    let (server, port) = match s.strip_prefix('[') {
+        Some(rest) => match rest.find(']') {
+            Some(index) => match &rest[index + 1..] {
+                "" => (&rest[..index], None),
+                port => match port.strip_prefix(':') {
+                    Some(port) => (&rest[..index], Some(port)),
+                    None => return Err(format!("invalid server {}", s)),
+                },
+            },
+            None => return Err(format!("invalid server {}", s)),
+        },
+        // An IPv6 server without brackets has no port
+        None => match s.rfind(':') {
+            Some(index) if !s[..index].contains(':') => (&s[..index], Some(&s[index + 1..])),
+            _ => (s, None),
+        },
+    };
+    let port = match port {
+        Some(port) => Some(port.parse().map_err(|_| format!("invalid port {}", port))?),
+        None => None,
+    };
+
+    Ok((server, port))
+}
+
+#[cfg(unix)]
+fn interface_index(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => None,
+        index => Some(index),
+    }
+}
+
+#[cfg(not(unix))]
+fn interface_index(_name: &str) -> Option<u32> {
+    None
+}
+
+/// Parses a server address with an optional zone of an IPv6 address, like `fe80::1%eth0` or
+/// `fe80::1%2`, and returns the address and its scope ID.
+pub fn parse_server(s: &str) -> Result<(IpAddr, u32), String> {
+    let (ip, zone) = match s.find('%') {
+        Some(index) => (&s[..index], Some(&s[index + 1..])),
+        None => (s, None),
+    };
+    let ip: IpAddr = ip.parse().map_err(|e| format!("{}", e))?;
+    let scope_id = match zone {
+        Some(_) if ip.is_ipv4() => {
+            return Err(format!("invalid zone of the IPv4 address {}", ip));
+        }
+        Some(zone) => match zone.parse() {
+            Ok(scope_id) => scope_id,
+            Err(_) => interface_index(zone).ok_or(format!("unknown zone {}", zone))?,
+        },
+        None => 0,
+    };
+
+    Ok((ip, scope_id))
+}
+
+/// Represents a config file.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Config {
+    pub interval: Option<u64>,
+    pub timeout: Option<u64>,
+    pub host: Option<String>,
+    pub query_type: Option<QueryType>,
+    pub targets: Vec<Target>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Value {
+    String(String),
+    Integer(u64),
+}
+
+/// Parses a basic string without the quotes, whose escapes are unescaped.
+fn parse_basic_string(s: &str) -> Option<String> {
+    let mut string = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return None,
+            '\\' => string.push(match chars.next()? {
+                '"' => '"',
+                '\\' => '\\',
+                't' => '\t',
+                'n' => '\n',
+                'r' => '\r',
+                _ => return None,
+            }),
+            c => string.push(c),
+        }
+    }
+
+    Some(string)
+}
+
+/// Parses an integer whose digits may be separated by underscores, like `1_000`.
+fn parse_integer(s: &str) -> Option<u64> {
+    if s.split('_').any(|digits| digits.is_empty()) {
+        return None;
+    }
+    let s = s.replace('_', "");
+    if !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    s.parse().ok()
+}
+
+fn parse_value(s: &str) -> Option<Value> {
+    if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') {
+        return parse_basic_string(&s[1..s.len() - 1]).map(Value::String);
+    }
+    // A literal string has no escapes
+    if s.len() >= 2 && s.starts_with('\'') && s.ends_with('\'') {
+        let s = &s[1..s.len() - 1];
+        if s.contains('\'') {
+            return None;
+        }
+
+        return Some(Value::String(String::from(s)));
+    }
+
+    parse_integer(s).map(Value::Integer)
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+        escaped = false;
+    }
+
+    line
+}
+
+/// Parses the name of a query type like `A` or `AAAA`.
+pub fn parse_query_type(s: &str) -> Result<QueryType, String> {
+    match s.to_ascii_uppercase().as_str() {
+        "A" => Ok(QueryType::A),
+        "NS" => Ok(QueryType::NS),
+        "CNAME" => Ok(QueryType::CNAME),
+        "SOA" => Ok(QueryType::SOA),
+        "PTR" => Ok(QueryType::PTR),
+        "MX" => Ok(QueryType::MX),
+        "TXT" => Ok(QueryType::TXT),
+        "AAAA" => Ok(QueryType::AAAA),
+        "SRV" => Ok(QueryType::SRV),
+        "ANY" => Ok(QueryType::All),
+        _ => Err(format!("invalid query type {}", s)),
+    }
+}
+
+/// Returns whether the line is the header of a target table, which may have whitespaces inside
+/// the brackets like `[[ target ]]`.
+fn is_target_table(line: &str) -> bool {
+    match line
+        .strip_prefix("[[")
+        .and_then(|line| line.strip_suffix("]]"))
+    {
+        Some(name) => name.trim() == "target",
+        None => false,
+    }
+}
+
+/// Parses a config file.
+pub fn parse(s: &str) -> Result<Config, String> {
+    let mut config = Config::default();
+    let mut target: Option<Target> = None;
+    let mut has_server = true;
+    let mut keys = HashSet::new();
+    for (i, line) in s.lines().enumerate() {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        // Table
+        if is_target_table(line) {
+            if !has_server {
+                return Err(format!("No server of the target before line {}", i + 1));
+            }
+            if let Some(target) = target.take() {
+                config.targets.push(target);
+            }
+            target = Some(Target::new(IpAddr::from([0, 0, 0, 0])));
+            has_server = false;
+            keys.clear();
+            continue;
+        }
+        if line.starts_with('[') {
+            return Err(format!("Unknown table {} at line {}", line, i + 1));
+        }
+
+        // Key/value pair
+        let (key, value) = match line.find('=') {
+            Some(index) => (line[..index].trim(), line[index + 1..].trim()),
+            None => return Err(format!("Invalid line {} at line {}", line, i + 1)),
+        };
+        let value = match parse_value(value) {
+            Some(value) => value,
+            None => return Err(format!("Invalid value {} at line {}", value, i + 1)),
+        };
+        if !keys.insert(key) {
+            return Err(format!("Duplicate key {} at line {}", key, i + 1));
+        }
+        let invalid = || format!("Invalid value of {} at line {}", key, i + 1);
+        match (&mut target, key, value) {
+            (None, "interval", Value::Integer(interval)) => config.interval = Some(interval),
+            (None, "timeout", Value::Integer(timeout)) => config.timeout = Some(timeout),
+            (None, "host", Value::String(host)) => config.host = Some(host),
+            (None, "type", Value::String(query_type)) => {
+                config.query_type = Some(parse_query_type(&query_type).map_err(|_| invalid())?)
+            }
+            (Some(target), "server", Value::String(server)) => {
+                let server = server.parse::<Target>().map_err(|_| invalid())?;
+                target.server = server.server;
+                target.scope_id = server.scope_id;
+                target.port = server.port;
+                target.transport = server.transport;
+                has_server = true;
+            }
+            (Some(target), "host", Value::String(host)) => target.host = Some(host),
+            (Some(target), "type", Value::String(query_type)) => {
+                target.query_type = Some(parse_query_type(&query_type).map_err(|_| invalid())?)
+            }
+            (_, "interval", _)
+            | (_, "timeout", _)
+            | (_, "host", _)
+            | (_, "type", _)
+            | (Some(_), "server", _) => return Err(invalid()),
+            _ => return Err(format!("Unknown key {} at line {}", key, i + 1)),
+        }
+    }
+    if !has_server {
+        return Err(String::from("No server of the last target"));
+    }
+    if let Some(target) = target {
+        config.targets.push(target);
+    }
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_parse() {
+        let s = r#"
+# Shared options
+interval = 500
+host = "www.google.com" # inline
+
+[[target]]
+server = "8.8.8.8"
+
+[[target]]
+server = "::1"
+host = "a.com#b"
+type = "aaaa"
+"#;
+        let config = parse(s).unwrap();
+        assert_eq!(config.interval, Some(500));
+        assert_eq!(config.timeout, None);
+        assert_eq!(config.host, Some(String::from("www.google.com")));
+        assert_eq!(config.targets.len(), 2);
+        assert_eq!(config.targets[0], Target::new("8.8.8.8".parse().unwrap()));
+        assert_eq!(config.targets[1].host, Some(String::from("a.com#b")));
+        assert_eq!(config.targets[1].query_type, Some(QueryType::AAAA));
+    }
+
+    #[test]
+    fn server_parse() {
+        let (server, scope_id) = parse_server("fe80::1%2").unwrap();
+        assert_eq!(server, "fe80::1".parse::<IpAddr>().unwrap());
+        assert_eq!(scope_id, 2);
+        assert_eq!(parse_server("8.8.8.8").unwrap().1, 0);
+        assert!(parse_server("8.8.8.8%2").is_err());
+        assert!(parse_server("fe80::1%nonexistent0").is_err());
+        #[cfg(target_os = "linux")]
+        assert_ne!(parse_server("fe80::1%lo").unwrap().1, 0);
+    }
+
+    #[test]
+    fn target_parse_url() {
+        let target = "tcp://8.8.8.8".parse::<Target>().unwrap();
+        assert_eq!(target.server, "8.8.8.8".parse::<IpAddr>().unwrap());
+        assert_eq!(target.port, None);
+        assert_eq!(target.transport, Some(Transport::Tcp));
+        let target = "UDP://[fe80::1%2]:5353".parse::<Target>().unwrap();
+        assert_eq!(target.scope_id, 2);
+        assert_eq!(target.port, Some(5353));
+        assert_eq!(target.transport, Some(Transport::Udp));
+        assert_eq!("udp://::1".parse::<Target>().unwrap().port, None);
+        assert_eq!("8.8.8.8".parse::<Target>().unwrap().transport, None);
+        assert!("8.8.8.8:53".parse::<Target>().is_err());
+        assert!("udp://8.8.8.8:x".parse::<Target>().is_err());
+        assert!("tls://8.8.8.8".parse::<Target>().is_err());
+        assert!("quic://8.8.8.8".parse::<Target>().is_err());
+    }
+
+    #[test]
+    fn config_parse_invalid() {
+        assert!(parse("interval = \"1\"").unwrap_err().contains("line 1"));
+        assert!(parse("[[target]]\nhost = \"a.com\"").is_err());
+        assert!(parse("[[target]]\nserver = \"a.com\"")
+            .unwrap_err()
+            .contains("line 2"));
+        assert!(parse("[options]").is_err());
+        assert!(parse("count = 1").unwrap_err().contains("Unknown key"));
+        assert!(parse("interval = 1\ninterval = 2")
+            .unwrap_err()
+            .contains("Duplicate key"));
+        assert!(parse("interval = 1__000").is_err());
+        assert!(parse("interval = _1").is_err());
+        assert!(parse("host = \"a\\qb\"").is_err());
+        assert!(parse("host = 'a'b'").is_err());
+    }
+
+    #[test]
+    fn config_parse_toml() {
+        let s = r##"
+interval = 1_000
+host = 'www.google.com' # literal
+
+[[ target ]]
+server = "8.8.8.8"
+host = "a\"#\\b" # escaped
+"##;
+        let config = parse(s).unwrap();
+        assert_eq!(config.interval, Some(1000));
+        assert_eq!(config.host, Some(String::from("www.google.com")));
+        assert_eq!(config.targets.len(), 1);
+        assert_eq!(config.targets[0].host, Some(String::from("a\"#\\b")));
+    }
+}
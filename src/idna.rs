@@ -0,0 +1,146 @@
+//! Conversion of internationalized domain names to their ASCII forms on the wire.
+//!
+//! Each non-ASCII label is lowercased and encoded in Punycode with the `xn--` prefix as described
+//! in RFC 3492, and ASCII labels, including the ones already encoded, are kept as is.
+
+use dnsping::MAX_LABEL_LEN;
+
+const BASE: u32 = 36;
+const TMIN: u32 = 1;
+const TMAX: u32 = 26;
+const SKEW: u32 = 38;
+const DAMP: u32 = 700;
+const INITIAL_BIAS: u32 = 72;
+const INITIAL_N: u32 = 128;
+
+/// Maximum length of a name without the trailing dot.
+const MAX_NAME_LEN: usize = 253;
+
+fn adapt(mut delta: u32, points: u32, first: bool) -> u32 {
+    delta /= match first {
+        true => DAMP,
+        false => 2,
+    };
+    delta += delta / points;
+    let mut k = 0;
+    while delta > ((BASE - TMIN) * TMAX) / 2 {
+        delta /= BASE - TMIN;
+        k += BASE;
+    }
+
+    k + (BASE - TMIN + 1) * delta / (delta + SKEW)
+}
+
+fn encode_digit(d: u32) -> char {
+    match d {
+        0..=25 => (b'a' + d as u8) as char,
+        _ => (b'0' + (d - 26) as u8) as char,
+    }
+}
+
+/// Encodes the label in Punycode without the prefix, `None` if it overflows.
+fn punycode(label: &str) -> Option<String> {
+    let input = label.chars().map(|c| c as u32).collect::<Vec<_>>();
+    let mut output = label.chars().filter(char::is_ascii).collect::<String>();
+    let basic = output.len() as u32;
+    if basic > 0 {
+        output.push('-');
+    }
+
+    let mut n = INITIAL_N;
+    let mut delta: u32 = 0;
+    let mut bias = INITIAL_BIAS;
+    let mut h = basic;
+    while (h as usize) < input.len() {
+        let m = input.iter().copied().filter(|c| *c >= n).min()?;
+        delta = delta.checked_add((m - n).checked_mul(h + 1)?)?;
+        n = m;
+        for c in input.iter().copied() {
+            if c < n {
+                delta = delta.checked_add(1)?;
+            }
+            if c == n {
+                let mut q = delta;
+                let mut k = BASE;
+                loop {
+                    let t = if k <= bias {
+                        TMIN
+                    } else if k >= bias + TMAX {
+                        TMAX
+                    } else {
+                        k - bias
+                    };
+                    if q < t {
+                        break;
+                    }
+                    output.push(encode_digit(t + (q - t) % (BASE - t)));
+                    q = (q - t) / (BASE - t);
+                    k += BASE;
+                }
+                output.push(encode_digit(q));
+                bias = adapt(delta, h + 1, h == basic);
+                delta = 0;
+                h += 1;
+            }
+        }
+        delta += 1;
+        n += 1;
+    }
+
+    Some(output)
+}
+
+/// Converts the host to its ASCII form on the wire, with the trailing dot removed.
+pub fn to_ascii(host: &str) -> Result<String, String> {
+    let invalid = |reason: &str| format!("Invalid host {}: {}", host, reason);
+    let name = host.strip_suffix('.').unwrap_or(host);
+    if name.is_empty() {
+        return Err(invalid("empty name"));
+    }
+
+    let mut labels = Vec::new();
+    for label in name.split('.') {
+        if label.is_empty() {
+            return Err(invalid("empty label"));
+        }
+        let label = match label.is_ascii() {
+            true => String::from(label),
+            false => match punycode(&label.to_lowercase()) {
+                Some(label) => format!("xn--{}", label),
+                None => return Err(invalid("overflow in Punycode")),
+            },
+        };
+        if label.len() > MAX_LABEL_LEN {
+            return Err(invalid("label too long"));
+        }
+        labels.push(label);
+    }
+    let name = labels.join(".");
+    if name.len() > MAX_NAME_LEN {
+        return Err(invalid("name too long"));
+    }
+
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn punycode_encode() {
+        assert_eq!(punycode("münchen").unwrap(), "mnchen-3ya");
+        assert_eq!(punycode("bücher").unwrap(), "bcher-kva");
+        assert_eq!(punycode("ü").unwrap(), "tda");
+    }
+
+    #[test]
+    fn host_to_ascii() {
+        assert_eq!(to_ascii("MÜNCHEN.de.").unwrap(), "xn--mnchen-3ya.de");
+        assert_eq!(to_ascii("xn--mnchen-3ya.de").unwrap(), "xn--mnchen-3ya.de");
+        assert_eq!(to_ascii("www.Google.com").unwrap(), "www.Google.com");
+        assert!(to_ascii("a..b").is_err());
+        assert!(to_ascii(".").is_err());
+        assert!(to_ascii(&"ü".repeat(64)).is_err());
+    }
+}
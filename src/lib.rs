@@ -1,10 +1,43 @@
 //! Ping a server with DNS.
 
-use dns_parser::{Builder, Packet, QueryClass, QueryType};
-use socks::{Socks5Datagram, TargetAddr};
-use std::io::{Error, ErrorKind, Result};
-use std::net::{SocketAddr, UdpSocket};
-use std::time::{Duration, Instant};
+use dns_parser::{Builder, Packet, QueryClass, RData};
+use pcap::{Capture, PcapWriter};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use socks::{Socks5Datagram, Socks5Stream, TargetAddr};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::error;
+use std::fmt;
+#[cfg(unix)]
+use std::fs;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, TcpStream, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+pub use dns_parser::{Opcode, QueryType, ResponseCode};
+
+/// Default size of the receive buffer, which is enough for UDP responses without large EDNS
+/// payloads.
+pub const DEFAULT_BUFFER_SIZE: usize = 4096;
+
+/// Maximum size of a padded query, which is the largest payload of a UDP datagram over IPv4 after
+/// the IP and UDP headers.
+pub const MAX_QUERY_SIZE: usize = 65507;
+
+pub mod pcap;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 
 /// Represents an socket which can send data to and receive data from a certain address.
 pub trait RW: Send + Sync {
@@ -25,13 +58,209 @@ pub trait RW: Send + Sync {
 
     /// Returns the write timeout of this socket.
     fn write_timeout(&self) -> Result<Option<Duration>>;
+
+    /// Sets the value of the IP TTL or the IPv6 hop limit for outgoing packets.
+    fn set_ttl(&self, _ttl: u32) -> Result<()> {
+        Err(Error::from(ErrorKind::Unsupported))
+    }
+
+    /// Sets the value of the IP ToS or the IPv6 traffic class for outgoing packets.
+    fn set_tos(&self, _tos: u8) -> Result<()> {
+        Err(Error::from(ErrorKind::Unsupported))
+    }
+
+    /// Sets the size of the receive buffer of this socket.
+    fn set_recv_buffer_size(&self, _size: usize) -> Result<()> {
+        Err(Error::from(ErrorKind::Unsupported))
+    }
+
+    /// Sets the size of the send buffer of this socket.
+    fn set_send_buffer_size(&self, _size: usize) -> Result<()> {
+        Err(Error::from(ErrorKind::Unsupported))
+    }
+
+    /// Returns the local address of this socket.
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Err(Error::from(ErrorKind::Unsupported))
+    }
+
+    /// Returns the instant the first byte of the last message received arrives, which differs
+    /// from the one the whole message arrives only on a stream.
+    fn first_byte(&self) -> Result<Instant> {
+        Err(Error::from(ErrorKind::Unsupported))
+    }
+}
+
+#[cfg(unix)]
+fn set_socket_option<T: AsRawFd>(
+    socket: &T,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    match ret {
+        0 => Ok(()),
+        _ => Err(Error::last_os_error()),
+    }
+}
+
+#[cfg(unix)]
+fn set_tos<T: AsRawFd>(socket: &T, is_ipv6: bool, tos: u8) -> Result<()> {
+    match is_ipv6 {
+        true => set_socket_option(socket, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos as _),
+        false => set_socket_option(socket, libc::IPPROTO_IP, libc::IP_TOS, tos as _),
+    }
+}
+
+#[cfg(not(unix))]
+fn set_tos<T>(_socket: &T, _is_ipv6: bool, _tos: u8) -> Result<()> {
+    Err(Error::from(ErrorKind::Unsupported))
+}
+
+#[cfg(unix)]
+fn set_hop_limit<T: AsRawFd>(socket: &T, hop_limit: u32) -> Result<()> {
+    set_socket_option(
+        socket,
+        libc::IPPROTO_IPV6,
+        libc::IPV6_UNICAST_HOPS,
+        hop_limit as _,
+    )
+}
+
+#[cfg(not(unix))]
+fn set_hop_limit<T>(_socket: &T, _hop_limit: u32) -> Result<()> {
+    Err(Error::from(ErrorKind::Unsupported))
+}
+
+#[cfg(unix)]
+fn set_multicast_hop_limit<T: AsRawFd>(socket: &T, hop_limit: u32) -> Result<()> {
+    set_socket_option(
+        socket,
+        libc::IPPROTO_IPV6,
+        libc::IPV6_MULTICAST_HOPS,
+        hop_limit as _,
+    )
+}
+
+#[cfg(not(unix))]
+fn set_multicast_hop_limit<T>(_socket: &T, _hop_limit: u32) -> Result<()> {
+    Err(Error::from(ErrorKind::Unsupported))
+}
+
+#[cfg(target_os = "linux")]
+fn bind_to_device<T: AsRawFd>(socket: &T, interface: &str) -> Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            interface.as_ptr() as *const libc::c_void,
+            interface.len() as libc::socklen_t,
+        )
+    };
+
+    match ret {
+        0 => Ok(()),
+        _ => Err(Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_to_device<T>(_socket: &T, _interface: &str) -> Result<()> {
+    Err(Error::from(ErrorKind::Unsupported))
+}
+
+/// Returns the size of a socket buffer as the value of the socket option, which is an `int`.
+#[cfg(unix)]
+fn buffer_size(size: usize) -> Result<libc::c_int> {
+    match size > libc::c_int::MAX as usize {
+        true => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Buffer size {} is too large", size),
+        )),
+        false => Ok(size as libc::c_int),
+    }
+}
+
+#[cfg(unix)]
+fn set_recv_buffer_size<T: AsRawFd>(socket: &T, size: usize) -> Result<()> {
+    set_socket_option(
+        socket,
+        libc::SOL_SOCKET,
+        libc::SO_RCVBUF,
+        buffer_size(size)?,
+    )
+}
+
+#[cfg(not(unix))]
+fn set_recv_buffer_size<T>(_socket: &T, _size: usize) -> Result<()> {
+    Err(Error::from(ErrorKind::Unsupported))
+}
+
+#[cfg(unix)]
+fn set_send_buffer_size<T: AsRawFd>(socket: &T, size: usize) -> Result<()> {
+    set_socket_option(
+        socket,
+        libc::SOL_SOCKET,
+        libc::SO_SNDBUF,
+        buffer_size(size)?,
+    )
+}
+
+#[cfg(not(unix))]
+fn set_send_buffer_size<T>(_socket: &T, _size: usize) -> Result<()> {
+    Err(Error::from(ErrorKind::Unsupported))
 }
 
 /// Represents an UDP datagram, containing a TCP stream keeping the SOCKS proxy alive and an UDP
-/// socket sending and receiving data.
+/// socket sending and receiving data. The association is re-established if it is gone, like when
+/// the TCP stream drops, or if several datagrams in a row are unanswered, since a proxy may drop
+/// the TCP stream silently.
 #[derive(Debug)]
 pub struct Datagram {
-    datagram: Socks5Datagram,
+    datagram: RwLock<Option<Arc<Socks5Datagram>>>,
+    proxy: SocketAddr,
+    addr: SocketAddr,
+    auth: Option<(String, String)>,
+    read_timeout: Mutex<Option<Duration>>,
+    write_timeout: Mutex<Option<Duration>>,
+    unanswered: AtomicUsize,
+}
+
+/// Number of the datagrams sent in a row without any datagram received, after which the UDP
+/// association is re-established on a timeout.
+const MAX_UNANSWERED: usize = 3;
+
+fn associate(
+    proxy: SocketAddr,
+    addr: SocketAddr,
+    auth: &Option<(String, String)>,
+) -> Result<Socks5Datagram> {
+    match auth {
+        Some((username, password)) => {
+            Socks5Datagram::bind_with_password(proxy, addr, username.as_str(), password.as_str())
+        }
+        None => Socks5Datagram::bind(proxy, addr),
+    }
+}
+
+/// Returns whether the error means the UDP association is gone, since the relay of the proxy
+/// refuses datagrams once the association is closed.
+fn is_association_gone(e: &Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset | ErrorKind::NotConnected
+    )
 }
 
 impl Datagram {
@@ -41,48 +270,122 @@ impl Datagram {
         addr: SocketAddr,
         auth: Option<(String, String)>,
     ) -> Result<Datagram> {
-        let datagram = match auth {
-            Some((username, password)) => Socks5Datagram::bind_with_password(
-                proxy,
-                addr,
-                username.as_str(),
-                password.as_str(),
-            )?,
-            None => Socks5Datagram::bind(proxy, addr)?,
+        let datagram = associate(proxy, addr, &auth)?;
+
+        Ok(Datagram {
+            datagram: RwLock::new(Some(Arc::new(datagram))),
+            proxy,
+            addr,
+            auth,
+            read_timeout: Mutex::new(None),
+            write_timeout: Mutex::new(None),
+            unanswered: AtomicUsize::new(0),
+        })
+    }
+
+    /// Re-establishes the UDP association gone with the timeouts applied, unless another thread
+    /// has done it. The socket of the old one is closed first, so that the same local port can be
+    /// bound again. The lock is not held while associating, so that the operations blocking on
+    /// the old one are not blocked further.
+    fn reassociate(&self, gone: Option<Arc<Socks5Datagram>>) -> Result<()> {
+        {
+            let mut datagram = self.datagram.write().unwrap();
+            match (datagram.as_ref(), &gone) {
+                (Some(current), Some(gone)) if !Arc::ptr_eq(current, gone) => return Ok(()),
+                (Some(_), None) => return Ok(()),
+                _ => *datagram = None,
+            }
+        }
+        drop(gone);
+
+        let new = associate(self.proxy, self.addr, &self.auth)?;
+        new.get_ref()
+            .set_read_timeout(*self.read_timeout.lock().unwrap())?;
+        new.get_ref()
+            .set_write_timeout(*self.write_timeout.lock().unwrap())?;
+        let mut datagram = self.datagram.write().unwrap();
+        if datagram.is_none() {
+            *datagram = Some(Arc::new(new));
+        }
+
+        Ok(())
+    }
+
+    /// Runs the operation on the UDP association, which is re-established if the operation finds
+    /// it gone. The error is still returned, so that the query is counted as lost. The lock is
+    /// only held to get the association, but not during the operation which may block.
+    fn with_association<T, F: FnOnce(&Socks5Datagram) -> Result<T>>(&self, f: F) -> Result<T> {
+        let datagram = self.datagram.read().unwrap().clone();
+        let result = match datagram.as_ref() {
+            Some(datagram) => f(datagram),
+            None => Err(Error::new(
+                ErrorKind::NotConnected,
+                "The UDP association of the proxy is gone",
+            )),
         };
+        if let Err(ref e) = result {
+            if is_association_gone(e) {
+                let _ = self.reassociate(datagram);
+            }
+        }
 
-        Ok(Datagram { datagram })
+        result
     }
 }
 
 impl RW for Datagram {
     fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize> {
-        self.datagram.send_to(buf, addr)
+        let size = self.with_association(|datagram| datagram.send_to(buf, addr))?;
+        self.unanswered.fetch_add(1, Ordering::Relaxed);
+
+        Ok(size)
     }
 
     fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
-        let (size, addr) = self.datagram.recv_from(buf)?;
+        let result = self.with_association(|datagram| datagram.recv_from(buf));
+        match &result {
+            Ok(_) => self.unanswered.store(0, Ordering::Relaxed),
+            // The TCP stream may be dropped without an error, after which nothing is relayed
+            Err(e)
+                if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock)
+                    && self.unanswered.load(Ordering::Relaxed) >= MAX_UNANSWERED =>
+            {
+                self.unanswered.store(0, Ordering::Relaxed);
+                let datagram = self.datagram.read().unwrap().clone();
+                let _ = self.reassociate(datagram);
+            }
+            Err(_) => {}
+        }
+        let (size, addr) = result?;
 
-        return match addr {
+        match addr {
             TargetAddr::Ip(addr) => Ok((size, addr)),
             _ => unreachable!(),
-        };
+        }
     }
 
     fn set_read_timeout(&self, dur: Option<Duration>) -> Result<()> {
-        self.datagram.get_ref().set_read_timeout(dur)
+        *self.read_timeout.lock().unwrap() = dur;
+        match self.datagram.read().unwrap().as_ref() {
+            Some(datagram) => datagram.get_ref().set_read_timeout(dur),
+            None => Ok(()),
+        }
     }
 
     fn set_write_timeout(&self, dur: Option<Duration>) -> Result<()> {
-        self.datagram.get_ref().set_write_timeout(dur)
+        *self.write_timeout.lock().unwrap() = dur;
+        match self.datagram.read().unwrap().as_ref() {
+            Some(datagram) => datagram.get_ref().set_write_timeout(dur),
+            None => Ok(()),
+        }
     }
 
     fn read_timeout(&self) -> Result<Option<Duration>> {
-        self.datagram.get_ref().read_timeout()
+        Ok(*self.read_timeout.lock().unwrap())
     }
 
     fn write_timeout(&self) -> Result<Option<Duration>> {
-        self.datagram.get_ref().write_timeout()
+        Ok(*self.write_timeout.lock().unwrap())
     }
 }
 
@@ -90,6 +393,7 @@ impl RW for Datagram {
 #[derive(Debug)]
 pub struct Socket {
     socket: UdpSocket,
+    peer: Option<SocketAddr>,
 }
 
 impl Socket {
@@ -97,13 +401,65 @@ impl Socket {
     pub fn bind(addr: SocketAddr) -> Result<Socket> {
         let socket = UdpSocket::bind(addr)?;
 
-        Ok(Socket { socket })
+        Ok(Socket { socket, peer: None })
+    }
+
+    /// Creates a new dual-stack `Socket` on the IPv6 address with `IPV6_V6ONLY` cleared, which can
+    /// also reach IPv4 servers by their IPv4-mapped addresses. Some platforms like OpenBSD do not
+    /// support dual-stack sockets.
+    pub fn bind_dual_stack(addr: SocketAddrV6) -> Result<Socket> {
+        let socket = socket2::Socket::new(
+            socket2::Domain::ipv6(),
+            socket2::Type::dgram(),
+            Some(socket2::Protocol::udp()),
+        )?;
+        socket.set_only_v6(false)?;
+        socket.bind(&SocketAddr::V6(addr).into())?;
+
+        Ok(Socket {
+            socket: socket.into_udp_socket(),
+            peer: None,
+        })
+    }
+
+    /// Connects the socket to the address, so that only datagrams from the address are received
+    /// and errors like ICMP port unreachable are reported as `ConnectionRefused`.
+    pub fn connect(&mut self, addr: SocketAddr) -> Result<()> {
+        self.socket.connect(addr)?;
+        self.peer = Some(addr);
+
+        Ok(())
+    }
+
+    /// Binds the socket to the network interface, so that datagrams are sent and received only
+    /// through it regardless of the routing (Linux only).
+    pub fn bind_device(&self, interface: &str) -> Result<()> {
+        bind_to_device(&self.socket, interface)
+    }
+
+    /// Joins the multicast group on the default interface. The multicast TTL of IPv4 and the
+    /// multicast hop limit of IPv6 are set to `255` as mDNS requires.
+    pub fn join_multicast(&self, group: IpAddr) -> Result<()> {
+        match group {
+            IpAddr::V4(group) => {
+                self.socket
+                    .join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?;
+                self.socket.set_multicast_ttl_v4(255)
+            }
+            IpAddr::V6(group) => {
+                self.socket.join_multicast_v6(&group, 0)?;
+                set_multicast_hop_limit(&self.socket, 255)
+            }
+        }
     }
 }
 
 impl RW for Socket {
     fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize> {
-        self.socket.send_to(buf, addr)
+        match self.peer {
+            Some(peer) if peer == addr => self.socket.send(buf),
+            _ => self.socket.send_to(buf, addr),
+        }
     }
 
     fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
@@ -125,54 +481,4736 @@ impl RW for Socket {
     fn write_timeout(&self) -> Result<Option<Duration>> {
         self.socket.write_timeout()
     }
+
+    fn set_ttl(&self, ttl: u32) -> Result<()> {
+        match self.socket.local_addr()? {
+            SocketAddr::V4(_) => self.socket.set_ttl(ttl),
+            SocketAddr::V6(_) => set_hop_limit(&self.socket, ttl),
+        }
+    }
+
+    fn set_tos(&self, tos: u8) -> Result<()> {
+        set_tos(&self.socket, self.socket.local_addr()?.is_ipv6(), tos)
+    }
+
+    fn set_recv_buffer_size(&self, size: usize) -> Result<()> {
+        set_recv_buffer_size(&self.socket, size)
+    }
+
+    fn set_send_buffer_size(&self, size: usize) -> Result<()> {
+        set_send_buffer_size(&self.socket, size)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket.local_addr()
+    }
 }
 
-/// Pings a DNS server.
-pub fn ping(
-    rw: &Box<dyn RW>,
+/// Represents a TCP stream sending and receiving DNS messages prefixed with their lengths.
+#[derive(Debug)]
+pub struct Stream {
+    stream: TcpStream,
     addr: SocketAddr,
-    id: u16,
-    iterate: bool,
-    host: &String,
-) -> Result<(usize, Duration)> {
-    let is_ipv6 = match addr {
-        SocketAddr::V4(_) => false,
-        SocketAddr::V6(_) => true,
-    };
+    frame: Mutex<Frame>,
+    first_byte: Mutex<Option<Instant>>,
+}
 
-    // DNS query
-    let mut query = Builder::new_query(id, iterate);
-    if is_ipv6 {
-        query.add_question(&host, false, QueryType::AAAA, QueryClass::IN);
-    } else {
-        query.add_question(&host, false, QueryType::A, QueryClass::IN);
+impl Stream {
+    /// Creates a new `Stream` connecting to the address directly.
+    pub fn connect(addr: SocketAddr) -> Result<Stream> {
+        let stream = TcpStream::connect(addr)?;
+
+        Ok(Stream {
+            stream,
+            addr,
+            frame: Mutex::default(),
+            first_byte: Mutex::new(None),
+        })
     }
-    let buffer = match query.build() {
-        Ok(buffer) => buffer,
-        Err(_) => {
-            return Err(Error::from(ErrorKind::InvalidData));
+
+    /// Creates a new `Stream` connecting to the address through a SOCKS5 proxy.
+    pub fn connect_with_socks(
+        proxy: SocketAddr,
+        addr: SocketAddr,
+        auth: Option<(String, String)>,
+    ) -> Result<Stream> {
+        let stream = match auth {
+            Some((username, password)) => Socks5Stream::connect_with_password(
+                proxy,
+                addr,
+                username.as_str(),
+                password.as_str(),
+            )?,
+            None => Socks5Stream::connect(proxy, addr)?,
+        };
+
+        Ok(Stream {
+            stream: stream.into_inner(),
+            addr,
+            frame: Mutex::default(),
+            first_byte: Mutex::new(None),
+        })
+    }
+
+    /// Creates a new `Stream` connecting to the address through an HTTP proxy with the `CONNECT`
+    /// method.
+    pub fn connect_with_http(
+        proxy: SocketAddr,
+        addr: SocketAddr,
+        auth: Option<(String, String)>,
+    ) -> Result<Stream> {
+        let mut stream = TcpStream::connect(proxy)?;
+
+        // Request
+        let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", addr, addr);
+        if let Some((username, password)) = auth {
+            let credential = base64::encode(format!("{}:{}", username, password));
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", credential));
         }
-    };
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes())?;
 
-    // Send query
-    let mut recv_buffer = vec![0u8; u16::MAX as usize];
-    let instant = Instant::now();
-    let _ = rw.send_to(buffer.as_slice(), addr)?;
+        // Response
+        let mut response = Vec::new();
+        let mut byte = [0u8; 1];
+        while !response.ends_with(b"\r\n\r\n") {
+            if stream.read(&mut byte)? == 0 {
+                return Err(Error::from(ErrorKind::UnexpectedEof));
+            }
+            response.push(byte[0]);
+        }
+        let response = String::from_utf8_lossy(&response);
+        let status = response.lines().next().unwrap_or_default();
+        match status.split_whitespace().nth(1) {
+            Some("200") => Ok(Stream {
+                stream,
+                addr,
+                frame: Mutex::default(),
+                first_byte: Mutex::new(None),
+            }),
+            _ => Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("HTTP proxy responded {}", status),
+            )),
+        }
+    }
+}
 
-    // Receive
-    loop {
-        let (size, a) = rw.recv_from(recv_buffer.as_mut_slice())?;
-        if size <= 0 {
-            return Err(Error::from(ErrorKind::UnexpectedEof));
-        } else {
-            if a == addr {
-                // Parse the DNS answer
-                if let Ok(packet) = Packet::parse(&recv_buffer[..size]) {
-                    if packet.header.id == id {
-                        return Ok((size, instant.elapsed()));
-                    }
-                }
+/// Writes a DNS message prefixed with its length to the stream.
+fn write_message<W: Write>(mut stream: W, buf: &[u8]) -> Result<usize> {
+    let mut message = Vec::with_capacity(buf.len() + 2);
+    message.extend_from_slice(&(buf.len() as u16).to_be_bytes());
+    message.extend_from_slice(buf);
+    stream.write_all(&message)?;
+
+    Ok(buf.len())
+}
+
+/// Represents a DNS message prefixed with its length being read from a stream. The bytes read so
+/// far are kept if a read times out in the middle of the message, so that the next read resumes
+/// from them instead of misaligning the stream.
+#[derive(Debug, Default)]
+struct Frame {
+    data: Vec<u8>,
+    filled: usize,
+    first_byte: Option<Instant>,
+}
+
+impl Frame {
+    /// Reads the rest of the message from the stream, and returns its size and the instant its
+    /// first byte arrives. The part exceeding the buffer is discarded.
+    fn read<R: Read>(&mut self, mut stream: R, buf: &mut [u8]) -> Result<(usize, Instant)> {
+        loop {
+            let len = match self.filled {
+                0 | 1 => 2,
+                _ => 2 + u16::from_be_bytes([self.data[0], self.data[1]]) as usize,
+            };
+            if self.filled >= 2 && self.filled == len {
+                let size = (len - 2).min(buf.len());
+                buf[..size].copy_from_slice(&self.data[2..2 + size]);
+                self.filled = 0;
+
+                return Ok((size, self.first_byte.take().unwrap()));
+            }
+            self.data.resize(len, 0);
+
+            let n = match stream.read(&mut self.data[self.filled..]) {
+                Ok(0) => return Err(Error::from(ErrorKind::UnexpectedEof)),
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if self.filled == 0 {
+                self.first_byte = Some(Instant::now());
+            }
+            self.filled += n;
+        }
+    }
+}
+
+impl RW for Stream {
+    fn send_to(&self, buf: &[u8], _: SocketAddr) -> Result<usize> {
+        write_message(&self.stream, buf)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        let (size, first_byte) = self.frame.lock().unwrap().read(&self.stream, buf)?;
+        *self.first_byte.lock().unwrap() = Some(first_byte);
+
+        Ok((size, self.addr))
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> Result<()> {
+        self.stream.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> Result<()> {
+        self.stream.set_write_timeout(dur)
+    }
+
+    fn read_timeout(&self) -> Result<Option<Duration>> {
+        self.stream.read_timeout()
+    }
+
+    fn write_timeout(&self) -> Result<Option<Duration>> {
+        self.stream.write_timeout()
+    }
+
+    // The options follow the family of the socket, which is the one of the proxy if any instead of
+    // the server
+    fn set_ttl(&self, ttl: u32) -> Result<()> {
+        match self.stream.local_addr()? {
+            SocketAddr::V4(_) => self.stream.set_ttl(ttl),
+            SocketAddr::V6(_) => set_hop_limit(&self.stream, ttl),
+        }
+    }
+
+    fn set_tos(&self, tos: u8) -> Result<()> {
+        set_tos(&self.stream, self.stream.local_addr()?.is_ipv6(), tos)
+    }
+
+    fn set_recv_buffer_size(&self, size: usize) -> Result<()> {
+        set_recv_buffer_size(&self.stream, size)
+    }
+
+    fn set_send_buffer_size(&self, size: usize) -> Result<()> {
+        set_send_buffer_size(&self.stream, size)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+
+    fn first_byte(&self) -> Result<Instant> {
+        self.first_byte
+            .lock()
+            .unwrap()
+            .ok_or_else(|| Error::from(ErrorKind::NotFound))
+    }
+}
+
+/// Represents a Unix datagram socket connected to a local resolver, which is bound to a temporary
+/// path removed on drop so that the resolver can respond.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct Unix {
+    socket: UnixDatagram,
+    local: PathBuf,
+    addr: SocketAddr,
+}
+
+#[cfg(unix)]
+impl Unix {
+    /// Creates a new `Unix` connecting to the path. Responses are regarded as from the address.
+    pub fn connect(path: &Path, addr: SocketAddr) -> Result<Unix> {
+        let local = std::env::temp_dir().join(format!(
+            "dnsping-{}-{}.sock",
+            std::process::id(),
+            rand::random::<u32>()
+        ));
+        let socket = UnixDatagram::bind(&local)?;
+        let unix = Unix {
+            socket,
+            local,
+            addr,
+        };
+        unix.socket.connect(path)?;
+
+        Ok(unix)
+    }
+
+    /// Removes the temporary paths bound by the `Unix` sockets of the process, which are not
+    /// removed if the process exits without dropping them.
+    pub fn remove_temporary_paths() -> Result<()> {
+        let prefix = format!("dnsping-{}-", std::process::id());
+        for entry in fs::read_dir(std::env::temp_dir())? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with(&prefix) && name.ends_with(".sock") {
+                let _ = fs::remove_file(entry.path());
             }
         }
+
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Unix {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.local);
+    }
+}
+
+#[cfg(unix)]
+impl RW for Unix {
+    fn send_to(&self, buf: &[u8], _: SocketAddr) -> Result<usize> {
+        self.socket.send(buf)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        let size = self.socket.recv(buf)?;
+
+        Ok((size, self.addr))
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> Result<()> {
+        self.socket.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> Result<()> {
+        self.socket.set_write_timeout(dur)
+    }
+
+    fn read_timeout(&self) -> Result<Option<Duration>> {
+        self.socket.read_timeout()
+    }
+
+    fn write_timeout(&self) -> Result<Option<Duration>> {
+        self.socket.write_timeout()
+    }
+
+    fn set_recv_buffer_size(&self, size: usize) -> Result<()> {
+        set_recv_buffer_size(&self.socket, size)
+    }
+
+    fn set_send_buffer_size(&self, size: usize) -> Result<()> {
+        set_send_buffer_size(&self.socket, size)
+    }
+}
+
+/// Represents a Unix stream socket connected to a local resolver sending and receiving DNS
+/// messages prefixed with their lengths.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct UnixStream {
+    stream: std::os::unix::net::UnixStream,
+    addr: SocketAddr,
+    frame: Mutex<Frame>,
+    first_byte: Mutex<Option<Instant>>,
+}
+
+#[cfg(unix)]
+impl UnixStream {
+    /// Creates a new `UnixStream` connecting to the path. Responses are regarded as from the
+    /// address.
+    pub fn connect(path: &Path, addr: SocketAddr) -> Result<UnixStream> {
+        let stream = std::os::unix::net::UnixStream::connect(path)?;
+
+        Ok(UnixStream {
+            stream,
+            addr,
+            frame: Mutex::default(),
+            first_byte: Mutex::new(None),
+        })
+    }
+}
+
+#[cfg(unix)]
+impl RW for UnixStream {
+    fn send_to(&self, buf: &[u8], _: SocketAddr) -> Result<usize> {
+        write_message(&self.stream, buf)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        let (size, first_byte) = self.frame.lock().unwrap().read(&self.stream, buf)?;
+        *self.first_byte.lock().unwrap() = Some(first_byte);
+
+        Ok((size, self.addr))
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> Result<()> {
+        self.stream.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> Result<()> {
+        self.stream.set_write_timeout(dur)
+    }
+
+    fn read_timeout(&self) -> Result<Option<Duration>> {
+        self.stream.read_timeout()
+    }
+
+    fn write_timeout(&self) -> Result<Option<Duration>> {
+        self.stream.write_timeout()
+    }
+
+    fn set_recv_buffer_size(&self, size: usize) -> Result<()> {
+        set_recv_buffer_size(&self.stream, size)
+    }
+
+    fn set_send_buffer_size(&self, size: usize) -> Result<()> {
+        set_send_buffer_size(&self.stream, size)
+    }
+
+    fn first_byte(&self) -> Result<Instant> {
+        self.first_byte
+            .lock()
+            .unwrap()
+            .ok_or_else(|| Error::from(ErrorKind::NotFound))
+    }
+}
+
+/// Represents a reply of a DNS query.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Reply {
+    /// Transaction ID of the response.
+    pub id: u16,
+    /// Whether the response duplicates the one of an earlier query which was already received.
+    pub duplicate: bool,
+    /// Source address of the response.
+    pub source: SocketAddr,
+    /// Size of the response in bytes.
+    pub size: usize,
+    /// Round-trip time of the query until the whole response arrives.
+    pub duration: Duration,
+    /// Time until the first byte of the response arrives, which is only measured on a stream
+    /// transport and `None` otherwise.
+    pub first_byte: Option<Duration>,
+    /// Response code of the response.
+    pub rcode: ResponseCode,
+    /// Minimum TTL of the answer records, `None` if the response has no answer.
+    pub ttl: Option<u32>,
+    /// Number of the answer records.
+    pub answers: u16,
+    /// Addresses in the A and AAAA answer records.
+    pub addresses: Vec<IpAddr>,
+    /// Number of the authority records, which are the only records of a referral.
+    pub nameservers: u16,
+    /// Number of the additional records, excluding the OPT pseudo-record.
+    pub additional: u16,
+    /// CPU and OS of the HINFO answer record to an ANY query, which are `RFC8482` and empty in a
+    /// minimal response of a server refusing ANY queries by RFC 8482.
+    pub hinfo: Option<(String, String)>,
+    /// Name server identifier in the EDNS NSID option, `None` if the response has no NSID.
+    pub nsid: Option<Vec<u8>>,
+    /// Whether the response has the AD bit set, which indicates the data is validated by DNSSEC.
+    pub authenticated_data: bool,
+    /// Name of the question of the response as received without the trailing dot, `None` if the
+    /// response has no question.
+    pub question: Option<String>,
+    /// Time to establish the connection of a stream transport, including the handshake of a
+    /// proxy, which is only set on the first reply over the connection. The round-trip time of the
+    /// query excludes it.
+    pub connect: Option<Duration>,
+    /// Whether the response has the TC bit set, which indicates the response is truncated and
+    /// the full one is only available over TCP.
+    pub truncated: bool,
+    /// Whether the response arrives after the one of a later query, which only happens with
+    /// `PingReceiver`.
+    pub out_of_order: bool,
+}
+
+/// Represents an error of a DNS response, which is wrapped in an `io::Error` of kind
+/// `InvalidData`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ResponseError {
+    /// The response from the source cannot be parsed.
+    Malformed(SocketAddr),
+    /// The response is from a source other than the server, which is ignored.
+    Foreign(SocketAddr),
+}
+
+impl fmt::Display for ResponseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResponseError::Malformed(source) => write!(f, "Malformed response from {}", source),
+            ResponseError::Foreign(source) => {
+                write!(f, "Ignored response from an unexpected source {}", source)
+            }
+        }
+    }
+}
+
+impl error::Error for ResponseError {}
+
+thread_local! {
+    /// Receive buffer of `ping`, which is reused by the calls on the thread.
+    static BUFFER: RefCell<Vec<u8>> = RefCell::new(vec![0u8; DEFAULT_BUFFER_SIZE]);
+}
+
+/// Pings a DNS server. Responses from sources other than the server are ignored unless
+/// `accept_any_source` is set.
+pub fn ping(
+    rw: &dyn RW,
+    addr: SocketAddr,
+    id: u16,
+    iterate: bool,
+    host: &str,
+    accept_any_source: bool,
+) -> Result<Reply> {
+    let options = Options {
+        recursion_desired: !iterate,
+        query_type: default_query_type(addr),
+        opcode: Opcode::StandardQuery,
+        size: None,
+        nsid: false,
+        authenticated_data: false,
+        checking_disabled: false,
+        dnssec_ok: false,
+        accept_any_source,
+        report_malformed: false,
+        payload: None,
+    };
+
+    BUFFER.with(|buffer| {
+        let mut buffer = buffer.borrow_mut();
+
+        query(rw, addr, id, host, &options, &mut buffer, &VecDeque::new())
+    })
+}
+
+/// Length of the random label prepended by `random_subdomain`.
+pub const RANDOM_LABEL_LEN: usize = 12;
+
+/// Returns a subdomain of the host with a random lowercase alphanumeric label prepended, which is
+/// unlikely to be cached by the server.
+pub fn random_subdomain<R: Rng>(rng: &mut R, host: &str) -> String {
+    let label = rng
+        .sample_iter(&Alphanumeric)
+        .take(RANDOM_LABEL_LEN)
+        .collect::<String>()
+        .to_ascii_lowercase();
+
+    format!("{}.{}", label, host)
+}
+
+/// Returns the host with the case of each letter randomized, known as the 0x20 encoding. A server
+/// echoing the question as is returns the same case, which a spoofed response can hardly guess.
+pub fn randomize_case<R: Rng>(rng: &mut R, host: &str) -> String {
+    host.chars()
+        .map(|c| match rng.gen::<bool>() {
+            true => c.to_ascii_uppercase(),
+            false => c.to_ascii_lowercase(),
+        })
+        .collect()
+}
+
+/// Returns whether the question of the response echoes the exact case of the host queried,
+/// regardless of the trailing dots.
+pub fn is_case_echoed(host: &str, question: &str) -> bool {
+    host.trim_end_matches('.') == question.trim_end_matches('.')
+}
+
+/// Returns the hex dump of the data with 16 bytes and their offset in each line.
+pub fn hex_dump(data: &[u8]) -> String {
+    data.chunks(16)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let bytes = chunk
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            format!("{:04x}  {}", i * 16, bytes)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Represents options of a DNS query.
+#[derive(Clone, Debug)]
+struct Options {
+    recursion_desired: bool,
+    query_type: QueryType,
+    opcode: Opcode,
+    size: Option<usize>,
+    nsid: bool,
+    authenticated_data: bool,
+    checking_disabled: bool,
+    dnssec_ok: bool,
+    accept_any_source: bool,
+    report_malformed: bool,
+    payload: Option<Vec<u8>>,
+}
+
+fn default_query_type(addr: SocketAddr) -> QueryType {
+    match addr {
+        SocketAddr::V4(_) => QueryType::A,
+        SocketAddr::V6(_) => QueryType::AAAA,
+    }
+}
+
+fn is_question_matched(packet: &Packet, host: &str, options: &Options) -> bool {
+    // A raw payload may carry any question, so its response is matched by the ID only
+    if options.payload.is_some() {
+        return true;
+    }
+
+    match packet.questions.as_slice() {
+        [question] => {
+            question.qtype == options.query_type
+                && question
+                    .qname
+                    .to_string()
+                    .trim_end_matches('.')
+                    .eq_ignore_ascii_case(host.trim_end_matches('.'))
+        }
+        // A server which does not implement the opcode, cannot parse the query or refuses it may
+        // respond without the question
+        [] => matches!(
+            packet.header.response_code,
+            ResponseCode::NotImplemented | ResponseCode::FormatError | ResponseCode::Refused
+        ),
+        _ => false,
+    }
+}
+
+/// Represents an answered query whose duplicate responses can be recognized.
+#[derive(Clone, Debug)]
+struct Answered {
+    id: u16,
+    host: String,
+    instant: Instant,
+}
+
+/// Number of the latest answered queries recognizing duplicate responses.
+const ANSWERED_LEN: usize = 16;
+
+/// Number of the latest answered queries recognizing duplicate responses in a `PingReceiver`,
+/// which is larger as queries are sent without waiting.
+const RECEIVER_ANSWERED_LEN: usize = 1024;
+
+fn query(
+    rw: &dyn RW,
+    addr: SocketAddr,
+    id: u16,
+    host: &str,
+    options: &Options,
+    recv_buffer: &mut [u8],
+    answered: &VecDeque<Answered>,
+) -> Result<Reply> {
+    let instant = send_query(rw, addr, id, host, options)?;
+    loop {
+        match recv_reply(rw, addr, id, host, options, recv_buffer, instant, answered)? {
+            Response::Reply(reply, _) | Response::Duplicate(reply) => return Ok(reply),
+            Response::Foreign(_) | Response::Unmatched => {}
+        }
+    }
+}
+
+/// Returns the transaction ID following the given one, or a random one, skipping the IDs still in
+/// flight. An ID in flight is reused only if all the IDs are in flight.
+fn next_id<F: Fn(u16) -> bool>(id: u16, random_id: bool, in_flight: F) -> u16 {
+    let mut next = id;
+    for _ in 0..=u16::MAX {
+        next = match random_id {
+            true => rand::random(),
+            false => next.wrapping_add(1),
+        };
+        if !in_flight(next) {
+            break;
+        }
+    }
+
+    next
+}
+
+/// Size of an OPT pseudo-record with an EDNS padding option of no padding.
+pub const PADDING_OVERHEAD: usize = 15;
+
+/// Code of the EDNS NSID option.
+pub const NSID_CODE: u16 = 3;
+/// Code of the EDNS padding option.
+pub const PADDING_CODE: u16 = 12;
+
+/// Returns the codes and the data of the EDNS options in the data of an OPT pseudo-record. A
+/// truncated option and the rest are dropped.
+pub fn edns_options(mut data: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut options = Vec::new();
+    while data.len() >= 4 {
+        let code = u16::from_be_bytes([data[0], data[1]]);
+        let len = u16::from_be_bytes([data[2], data[3]]) as usize;
+        let option = match data.get(4..4 + len) {
+            Some(option) => option,
+            None => break,
+        };
+        options.push((code, option));
+        data = &data[4 + len..];
+    }
+
+    options
+}
+
+/// Returns the data of the EDNS option of the code in the OPT pseudo-record of the packet.
+fn edns_option<'a>(packet: &Packet<'a>, code: u16) -> Option<&'a [u8]> {
+    let data = match &packet.opt {
+        Some(opt) => match opt.data {
+            RData::Unknown(data) => data,
+            _ => return None,
+        },
+        None => return None,
+    };
+
+    edns_options(data)
+        .into_iter()
+        .find(|(option_code, _)| *option_code == code)
+        .map(|(_, option)| option)
+}
+
+/// Splits the character-string at the beginning of the data from the rest.
+fn character_string(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (&len, data) = data.split_first()?;
+    if data.len() < len as usize {
+        return None;
+    }
+
+    Some(data.split_at(len as usize))
+}
+
+/// Returns the offset after the name at the offset of the message, which ends at a root label or
+/// a compression pointer.
+fn skip_name(data: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *data.get(offset)? as usize;
+        match len {
+            0 => return Some(offset + 1),
+            _ if len & 0xc0 == 0xc0 => return Some(offset + 2),
+            _ => offset += 1 + len,
+        }
+    }
+}
+
+/// Returns the types of the answer records in the message. The parser loses the type of a record
+/// unknown to it, which is read from the message instead.
+fn answer_types(data: &[u8]) -> Option<Vec<u16>> {
+    let count = |offset: usize| {
+        Some(u16::from_be_bytes([
+            *data.get(offset)?,
+            *data.get(offset + 1)?,
+        ]))
+    };
+    let questions = count(4)?;
+    let answers = count(6)?;
+
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = skip_name(data, offset)? + 4;
+    }
+    let mut types = Vec::with_capacity(answers as usize);
+    for _ in 0..answers {
+        offset = skip_name(data, offset)?;
+        types.push(count(offset)?);
+        offset += 10 + count(offset + 8)? as usize;
+    }
+
+    Some(types)
+}
+
+/// Type of a HINFO record, which is unknown to the parser.
+const HINFO_TYPE: u16 = 13;
+
+/// Returns the CPU and the OS of the HINFO answer record to an ANY query in the message.
+fn hinfo(packet: &Packet, data: &[u8]) -> Option<(String, String)> {
+    if !matches!(packet.questions.as_slice(), [question] if question.qtype == QueryType::All) {
+        return None;
+    }
+
+    let types = answer_types(data)?;
+    packet
+        .answers
+        .iter()
+        .zip(types)
+        .find_map(|(answer, rtype)| match answer.data {
+            RData::Unknown(data) if rtype == HINFO_TYPE => {
+                let (cpu, data) = character_string(data)?;
+                let (os, _) = character_string(data)?;
+
+                Some((
+                    String::from_utf8_lossy(cpu).into_owned(),
+                    String::from_utf8_lossy(os).into_owned(),
+                ))
+            }
+            _ => None,
+        })
+}
+
+/// Maximum length of a label.
+pub const MAX_LABEL_LEN: usize = 63;
+/// Maximum length of a name on the wire.
+const MAX_NAME_LEN: usize = 255;
+
+/// Appends the host as a name on the wire. A host of `.` is the root.
+fn write_name(buffer: &mut Vec<u8>, host: &str) -> Result<()> {
+    let invalid = |reason: &str| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid host {}: {}", host, reason),
+        )
+    };
+
+    let start = buffer.len();
+    let name = host.strip_suffix('.').unwrap_or(host);
+    if !name.is_empty() {
+        for label in name.split('.') {
+            if label.is_empty() {
+                return Err(invalid("empty label"));
+            }
+            if label.len() > MAX_LABEL_LEN {
+                return Err(invalid("label too long"));
+            }
+            buffer.push(label.len() as u8);
+            buffer.extend_from_slice(label.as_bytes());
+        }
+    }
+    buffer.push(0);
+    if buffer.len() - start > MAX_NAME_LEN {
+        return Err(invalid("name too long"));
+    }
+
+    Ok(())
+}
+
+/// Returns the transaction ID in the first two bytes of a raw payload.
+fn payload_id(payload: &[u8]) -> u16 {
+    u16::from_be_bytes([payload[0], payload[1]])
+}
+
+fn build_query(id: u16, host: &str, options: &Options) -> Result<Vec<u8>> {
+    if let Some(payload) = &options.payload {
+        return Ok(payload.clone());
+    }
+
+    // The question is written without the builder, which panics on a label of 63 bytes and
+    // encodes an invalid host as is
+    let mut buffer = Builder::new_query(id, options.recursion_desired)
+        .build()
+        .unwrap_or_else(|buffer| buffer);
+    write_name(&mut buffer, host)?;
+    buffer.extend_from_slice(&(options.query_type as u16).to_be_bytes());
+    buffer.extend_from_slice(&(QueryClass::IN as u16).to_be_bytes());
+    buffer[4..6].copy_from_slice(&1u16.to_be_bytes());
+
+    // The builder always sets the opcode as a standard query
+    let opcode: u16 = options.opcode.into();
+    buffer[2] = (buffer[2] & 0x87) | ((opcode as u8 & 0x0f) << 3);
+
+    // The builder does not set the AD and CD bits
+    if options.authenticated_data {
+        buffer[3] |= 0x20;
+    }
+    if options.checking_disabled {
+        buffer[3] |= 0x10;
+    }
+
+    // Request the NSID with an empty EDNS NSID option
+    let mut edns = Vec::new();
+    if options.nsid {
+        edns.extend_from_slice(&NSID_CODE.to_be_bytes());
+        edns.extend_from_slice(&0u16.to_be_bytes());
+    }
+
+    // Pad the query with an EDNS padding option
+    if let Some(size) = options.size {
+        if size > MAX_QUERY_SIZE {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Query size {} is larger than {}", size, MAX_QUERY_SIZE),
+            ));
+        }
+        let padding = size.saturating_sub(buffer.len() + PADDING_OVERHEAD + edns.len());
+        edns.extend_from_slice(&PADDING_CODE.to_be_bytes());
+        edns.extend_from_slice(&(padding as u16).to_be_bytes());
+        edns.resize(edns.len() + padding, 0);
+    }
+
+    // Append the options in an OPT pseudo-record, whose TTL holds the DO bit
+    if options.nsid || options.size.is_some() || options.dnssec_ok {
+        let arcount = u16::from_be_bytes([buffer[10], buffer[11]]) + 1;
+        buffer[10..12].copy_from_slice(&arcount.to_be_bytes());
+        buffer.push(0);
+        buffer.extend_from_slice(&41u16.to_be_bytes());
+        buffer.extend_from_slice(&(DEFAULT_BUFFER_SIZE as u16).to_be_bytes());
+        let ttl: u32 = match options.dnssec_ok {
+            true => 0x8000,
+            false => 0,
+        };
+        buffer.extend_from_slice(&ttl.to_be_bytes());
+        buffer.extend_from_slice(&(edns.len() as u16).to_be_bytes());
+        buffer.extend_from_slice(&edns);
+    }
+
+    Ok(buffer)
+}
+
+fn send_query(
+    rw: &dyn RW,
+    addr: SocketAddr,
+    id: u16,
+    host: &str,
+    options: &Options,
+) -> Result<Instant> {
+    // DNS query
+    let buffer = build_query(id, host, options)?;
+
+    // Send query
+    let instant = Instant::now();
+    let _ = rw.send_to(buffer.as_slice(), addr)?;
+
+    Ok(instant)
+}
+
+/// Receives a datagram, retrying on a spurious `WouldBlock` returned before the read timeout.
+/// `WouldBlock` returned after the read timeout, which is how a timeout is reported on some
+/// platforms, is converted to `TimedOut`.
+fn recv_from(rw: &dyn RW, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+    loop {
+        let instant = Instant::now();
+        match rw.recv_from(buf) {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => match rw.read_timeout()? {
+                Some(timeout) if instant.elapsed() >= timeout => {
+                    return Err(Error::from(ErrorKind::TimedOut))
+                }
+                _ => continue,
+            },
+            result => return result,
+        }
+    }
+}
+
+/// Returns the reply of the packet received at the arrival instant to the query sent at the
+/// instant.
+fn new_reply(
+    rw: &dyn RW,
+    packet: &Packet,
+    data: &[u8],
+    source: SocketAddr,
+    instant: Instant,
+    arrival: Instant,
+    duplicate: bool,
+) -> Reply {
+    let duration = arrival.saturating_duration_since(instant);
+    let first_byte = rw
+        .first_byte()
+        .ok()
+        .map(|first_byte| first_byte.saturating_duration_since(instant));
+    let rcode = packet.header.response_code;
+    let ttl = packet.answers.iter().map(|answer| answer.ttl).min();
+    let addresses = packet
+        .answers
+        .iter()
+        .filter_map(|answer| match answer.data {
+            RData::A(ref a) => Some(IpAddr::V4(a.0)),
+            RData::AAAA(ref aaaa) => Some(IpAddr::V6(aaaa.0)),
+            _ => None,
+        })
+        .collect();
+    let nsid = edns_option(packet, NSID_CODE).map(|nsid| nsid.to_vec());
+    let question = packet
+        .questions
+        .first()
+        .map(|question| question.qname.to_string().trim_end_matches('.').to_string());
+
+    Reply {
+        id: packet.header.id,
+        duplicate,
+        source,
+        size: data.len(),
+        duration,
+        first_byte,
+        rcode,
+        ttl,
+        answers: packet.answers.len() as u16,
+        addresses,
+        hinfo: hinfo(packet, data),
+        nameservers: packet.nameservers.len() as u16,
+        additional: packet.additional.len() as u16,
+        nsid,
+        authenticated_data: packet.header.authenticated_data,
+        question,
+        connect: None,
+        truncated: packet.header.truncated,
+        out_of_order: false,
+    }
+}
+
+/// Receives the reply of the query, a duplicate reply of an answered query, or a response from a
+/// foreign source. The read timeout is the deadline since the query is sent, so mismatched
+/// responses do not extend the wait.
+#[allow(clippy::too_many_arguments)]
+fn recv_reply(
+    rw: &dyn RW,
+    addr: SocketAddr,
+    id: u16,
+    host: &str,
+    options: &Options,
+    recv_buffer: &mut [u8],
+    instant: Instant,
+    answered: &VecDeque<Answered>,
+) -> Result<Response> {
+    let timeout = rw.read_timeout()?;
+    let result = recv_reply_until(
+        rw,
+        addr,
+        id,
+        host,
+        options,
+        recv_buffer,
+        instant,
+        answered,
+        timeout,
+    );
+    rw.set_read_timeout(timeout)?;
+
+    result
+}
+
+#[allow(clippy::too_many_arguments)]
+fn recv_reply_until(
+    rw: &dyn RW,
+    addr: SocketAddr,
+    id: u16,
+    host: &str,
+    options: &Options,
+    recv_buffer: &mut [u8],
+    instant: Instant,
+    answered: &VecDeque<Answered>,
+    timeout: Option<Duration>,
+) -> Result<Response> {
+    loop {
+        // Wait for the rest of the timeout only
+        if let Some(timeout) = timeout {
+            match timeout.checked_sub(instant.elapsed()) {
+                Some(remain) if remain > Duration::from_millis(0) => {
+                    rw.set_read_timeout(Some(remain))?
+                }
+                _ => return Err(Error::from(ErrorKind::TimedOut)),
+            }
+        }
+
+        let response =
+            recv_response(rw, addr, options, recv_buffer, answered, |packet| {
+                match packet.header.id == id && is_question_matched(packet, host, options) {
+                    true => Some((String::from(host), instant)),
+                    false => None,
+                }
+            })?;
+        if !matches!(response, Response::Unmatched) {
+            return Ok(response);
+        }
+    }
+}
+
+/// Represents a response received by `recv_response`.
+enum Response {
+    /// Reply of a query waiting for it, which is answered from now on.
+    Reply(Reply, Answered),
+    /// Duplicate reply of an answered query.
+    Duplicate(Reply),
+    /// Response from a source other than the server, which is ignored.
+    Foreign(SocketAddr),
+    /// Response of no query, which is ignored.
+    Unmatched,
+}
+
+/// Receives a response and matches it against the queries waiting for their replies, whose host
+/// and send instant are returned by `pending` for the packet answering one of them, and then
+/// against the answered queries for a duplicate. The query takes precedence over an answered one
+/// with the same ID.
+fn recv_response<F: FnMut(&Packet) -> Option<(String, Instant)>>(
+    rw: &dyn RW,
+    addr: SocketAddr,
+    options: &Options,
+    buffer: &mut [u8],
+    answered: &VecDeque<Answered>,
+    mut pending: F,
+) -> Result<Response> {
+    let (size, source) = recv_from(rw, buffer)?;
+    let arrival = Instant::now();
+    if size == 0 {
+        return Err(Error::from(ErrorKind::UnexpectedEof));
+    }
+    if !options.accept_any_source && source != addr {
+        return Ok(Response::Foreign(source));
+    }
+
+    // Parse the DNS answer
+    let packet = match Packet::parse(&buffer[..size]) {
+        Ok(packet) => packet,
+        Err(_) if options.report_malformed => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                ResponseError::Malformed(source),
+            ));
+        }
+        Err(_) => return Ok(Response::Unmatched),
+    };
+    let id = packet.header.id;
+    if let Some((host, instant)) = pending(&packet) {
+        let reply = new_reply(
+            rw,
+            &packet,
+            &buffer[..size],
+            source,
+            instant,
+            arrival,
+            false,
+        );
+
+        return Ok(Response::Reply(reply, Answered { id, host, instant }));
+    }
+
+    match answered
+        .iter()
+        .rev()
+        .find(|answered| id == answered.id && is_question_matched(&packet, &answered.host, options))
+    {
+        Some(answered) => Ok(Response::Duplicate(new_reply(
+            rw,
+            &packet,
+            &buffer[..size],
+            source,
+            answered.instant,
+            arrival,
+            true,
+        ))),
+        None => Ok(Response::Unmatched),
+    }
+}
+
+/// Represents a proxy with its authentication.
+#[derive(Clone, Debug)]
+enum Proxy {
+    Socks(SocketAddr, Option<(String, String)>),
+    Http(SocketAddr, Option<(String, String)>),
+}
+
+/// Represents a pinger which pings a DNS server repeatedly with increasing or random transaction
+/// IDs.
+///
+/// Duplicate responses of the latest answered queries received in the meantime are collected and
+/// can be taken with `take_duplicates`.
+///
+/// The transport is bound on the first ping if it is not bound explicitly with `bind`.
+pub struct Pinger {
+    addr: SocketAddr,
+    host: String,
+    options: Options,
+    timeout: Option<Duration>,
+    tos: Option<u8>,
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+    source_port: u16,
+    interface: Option<String>,
+    dual_stack: bool,
+    random_source_port: bool,
+    proxy: Option<Proxy>,
+    tcp: bool,
+    multicast: bool,
+    unix: Option<PathBuf>,
+    rw: Option<Box<dyn RW>>,
+    bound: bool,
+    connect: Option<Duration>,
+    buffer: Vec<u8>,
+    id: u16,
+    random_id: bool,
+    answered: VecDeque<Answered>,
+    duplicates: Vec<Reply>,
+    foreign: Vec<SocketAddr>,
+    unanswered: VecDeque<u16>,
+    pcap: Option<Arc<Mutex<PcapWriter>>>,
+}
+
+impl Pinger {
+    /// Creates a new `Pinger` pinging the given server for `www.google.com`.
+    pub fn new(addr: SocketAddr) -> Pinger {
+        Pinger {
+            addr,
+            host: String::from("www.google.com"),
+            options: Options {
+                recursion_desired: true,
+                query_type: default_query_type(addr),
+                opcode: Opcode::StandardQuery,
+                size: None,
+                nsid: false,
+                authenticated_data: false,
+                checking_disabled: false,
+                dnssec_ok: false,
+                accept_any_source: false,
+                report_malformed: false,
+                payload: None,
+            },
+            timeout: None,
+            tos: None,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            source_port: 0,
+            interface: None,
+            dual_stack: false,
+            random_source_port: false,
+            proxy: None,
+            tcp: false,
+            multicast: false,
+            unix: None,
+            rw: None,
+            bound: false,
+            connect: None,
+            buffer: vec![0u8; DEFAULT_BUFFER_SIZE],
+            id: 0,
+            random_id: false,
+            answered: VecDeque::new(),
+            duplicates: Vec::new(),
+            foreign: Vec::new(),
+            unanswered: VecDeque::new(),
+            pcap: None,
+        }
+    }
+
+    /// Sets the host to query.
+    pub fn host(mut self, host: &str) -> Pinger {
+        self.host = String::from(host);
+        self
+    }
+
+    /// Sets the query type, default as `A` for an IPv4 server and `AAAA` for an IPv6 server.
+    pub fn query_type(mut self, query_type: QueryType) -> Pinger {
+        self.options.query_type = query_type;
+        self
+    }
+
+    /// Sets the opcode of the query, default as `StandardQuery`.
+    pub fn opcode(mut self, opcode: Opcode) -> Pinger {
+        self.options.opcode = opcode;
+        self
+    }
+
+    /// Sets the size the query is padded to with an EDNS padding option. The query is at least
+    /// `PADDING_OVERHEAD` bytes larger than the one without padding.
+    pub fn size(mut self, size: Option<usize>) -> Pinger {
+        self.options.size = size;
+        self
+    }
+
+    /// Sets whether to request the name server identifier with an EDNS NSID option.
+    pub fn nsid(mut self, nsid: bool) -> Pinger {
+        self.options.nsid = nsid;
+        self
+    }
+
+    /// Sets whether to set the AD bit in the query, which requests the server to indicate whether
+    /// the data is validated by DNSSEC.
+    pub fn authenticated_data(mut self, authenticated_data: bool) -> Pinger {
+        self.options.authenticated_data = authenticated_data;
+        self
+    }
+
+    /// Sets whether to set the CD bit in the query, which disables the DNSSEC validation of the
+    /// server.
+    pub fn checking_disabled(mut self, checking_disabled: bool) -> Pinger {
+        self.options.checking_disabled = checking_disabled;
+        self
+    }
+
+    /// Sets whether to set the DO bit in an EDNS OPT pseudo-record of the query, which requests
+    /// the DNSSEC records.
+    pub fn dnssec_ok(mut self, dnssec_ok: bool) -> Pinger {
+        self.options.dnssec_ok = dnssec_ok;
+        self
+    }
+
+    /// Sets whether to set the RD bit in the query, which requests the server to query
+    /// recursively, default as `true`.
+    pub fn recursion_desired(mut self, recursion_desired: bool) -> Pinger {
+        self.options.recursion_desired = recursion_desired;
+        self
+    }
+
+    /// Sets whether to do query iteratively, which clears the RD bit. This is the opposite of
+    /// `recursion_desired`.
+    pub fn iterate(mut self, iterate: bool) -> Pinger {
+        self.options.recursion_desired = !iterate;
+        self
+    }
+
+    /// Sets whether to accept responses from any source.
+    pub fn accept_any_source(mut self, accept_any_source: bool) -> Pinger {
+        self.options.accept_any_source = accept_any_source;
+        self
+    }
+
+    /// Sets whether to return an error wrapping `ResponseError::Malformed` on a response which
+    /// cannot be parsed, instead of ignoring it and waiting for the next one.
+    pub fn report_malformed(mut self, report_malformed: bool) -> Pinger {
+        self.options.report_malformed = report_malformed;
+        self
+    }
+
+    /// Sets the timeout to wait for each response.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Pinger {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the IP ToS or the IPv6 traffic class of the queries. The ToS is not supported over a
+    /// SOCKS5 proxy.
+    pub fn tos(mut self, tos: Option<u8>) -> Pinger {
+        self.tos = tos;
+        self
+    }
+
+    /// Sets the size of the receive buffer of the socket, default as the system default. The size
+    /// is not supported over a SOCKS5 proxy.
+    pub fn recv_buffer_size(mut self, size: Option<usize>) -> Pinger {
+        self.recv_buffer_size = size;
+        self
+    }
+
+    /// Sets the size of the send buffer of the socket, default as the system default. The size is
+    /// not supported over a SOCKS5 proxy.
+    pub fn send_buffer_size(mut self, size: Option<usize>) -> Pinger {
+        self.send_buffer_size = size;
+        self
+    }
+
+    /// Sets the local port to bind, default as `0` as an ephemeral port. The port cannot be set
+    /// over TCP.
+    pub fn source_port(mut self, port: u16) -> Pinger {
+        self.source_port = port;
+        self
+    }
+
+    /// Sets whether to bind a fresh ephemeral port for each query, which can only be set over UDP
+    /// without a proxy or a source port. Late and duplicate responses to the previous queries are
+    /// not received since their sockets are closed, and the pinger cannot be split.
+    pub fn random_source_port(mut self, random_source_port: bool) -> Pinger {
+        self.random_source_port = random_source_port;
+        self
+    }
+
+    /// Sets the raw payload sent as is instead of the query built, whose response is matched by
+    /// the transaction ID in its first two bytes only. The payload must be at least 2 bytes, and
+    /// queries with a payload cannot be sent in bursts or by a split pinger since they share the
+    /// same ID.
+    pub fn payload(mut self, payload: Option<Vec<u8>>) -> Pinger {
+        self.options.payload = payload;
+        self
+    }
+
+    /// Sets the network interface to bind the socket to (Linux only). The interface can only be
+    /// set over UDP without a proxy.
+    pub fn interface(mut self, interface: Option<String>) -> Pinger {
+        self.interface = interface;
+        self
+    }
+
+    /// Sets whether to bind a dual-stack socket for an IPv6 server with `IPV6_V6ONLY` cleared,
+    /// which can only be set over UDP without a proxy. The option has no effect on an IPv4 server.
+    pub fn dual_stack(mut self, dual_stack: bool) -> Pinger {
+        self.dual_stack = dual_stack;
+        self
+    }
+
+    /// Sets the SOCKS5 proxy and its username/password authentication.
+    pub fn proxy(mut self, proxy: SocketAddr, auth: Option<(String, String)>) -> Pinger {
+        self.proxy = Some(Proxy::Socks(proxy, auth));
+        self
+    }
+
+    /// Sets the HTTP proxy and its basic authentication. An HTTP proxy can only be used with TCP.
+    pub fn http_proxy(mut self, proxy: SocketAddr, auth: Option<(String, String)>) -> Pinger {
+        self.proxy = Some(Proxy::Http(proxy, auth));
+        self
+    }
+
+    /// Sets whether to query over TCP.
+    pub fn tcp(mut self, tcp: bool) -> Pinger {
+        self.tcp = tcp;
+        self
+    }
+
+    /// Sets whether the server is a multicast group like mDNS. The socket joins the group, and
+    /// responses from any source are accepted. A multicast server can only be pinged over UDP
+    /// without a proxy.
+    pub fn multicast(mut self, multicast: bool) -> Pinger {
+        self.multicast = multicast;
+        self.options.accept_any_source |= multicast;
+        self
+    }
+
+    /// Sets the path of a Unix socket of a local resolver to query instead of the address, which is
+    /// only used to identify the server. The socket is a stream socket if querying over TCP, or a
+    /// datagram socket otherwise. A Unix socket can only be used without a proxy or a source port.
+    pub fn unix(mut self, path: &Path) -> Pinger {
+        self.unix = Some(path.to_path_buf());
+        self
+    }
+
+    /// Sets the size of the receive buffer, default as `DEFAULT_BUFFER_SIZE`. Responses larger
+    /// than the buffer are truncated and will be dropped. The buffer is grown to the maximum size
+    /// of a message when querying over TCP, where large responses like the ones of ANY queries are
+    /// expected.
+    pub fn buffer_size(mut self, size: usize) -> Pinger {
+        self.buffer = vec![0u8; size];
+        self
+    }
+
+    /// Sets whether to use random transaction IDs instead of increasing ones.
+    pub fn random_id(mut self, random_id: bool) -> Pinger {
+        self.random_id = random_id;
+        self
+    }
+
+    /// Sets the pcap writer to write the queries and responses to, which may be shared by several
+    /// pingers.
+    pub fn pcap(mut self, pcap: Arc<Mutex<PcapWriter>>) -> Pinger {
+        self.pcap = Some(pcap);
+        self
+    }
+
+    /// Sets the transport explicitly instead of binding one. The timeout is still applied to it.
+    pub fn rw(mut self, rw: Box<dyn RW>) -> Pinger {
+        self.rw = Some(rw);
+        self
+    }
+
+    /// Returns the address of the server.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the name of the transport, which is `UDP`, `TCP`, `UNIX-DGRAM` or `UNIX-STREAM`.
+    pub fn transport(&self) -> &'static str {
+        match (self.unix.is_some(), self.tcp) {
+            (true, true) => "UNIX-STREAM",
+            (true, false) => "UNIX-DGRAM",
+            (false, true) => "TCP",
+            (false, false) => "UDP",
+        }
+    }
+
+    /// Returns the transaction ID of the last query.
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Returns the duplicate responses received since the last call.
+    pub fn take_duplicates(&mut self) -> Vec<Reply> {
+        mem::take(&mut self.duplicates)
+    }
+
+    /// Returns the sources of the responses ignored since the last call as they are not from the
+    /// server.
+    pub fn take_foreign(&mut self) -> Vec<SocketAddr> {
+        mem::take(&mut self.foreign)
+    }
+
+    fn next_id(&mut self) {
+        if let Some(payload) = &self.options.payload {
+            self.id = payload_id(payload);
+            return;
+        }
+        let unanswered = &self.unanswered;
+        self.id = next_id(self.id, self.random_id, |id| unanswered.contains(&id));
+    }
+
+    /// Remembers the ID of a query without a reply, which is not reused while its late reply may
+    /// still arrive.
+    fn push_unanswered(&mut self, id: u16) {
+        if self.unanswered.len() >= ANSWERED_LEN {
+            self.unanswered.pop_front();
+        }
+        self.unanswered.push_back(id);
+    }
+
+    /// Returns the query for the given host with the next transaction ID without sending it. The
+    /// ID is unpredictable if random transaction IDs are used. An invalid host is returned as
+    /// `InvalidInput` with the reason.
+    pub fn build_query(&self, host: &str) -> Result<Vec<u8>> {
+        let id = next_id(self.id, false, |id| self.unanswered.contains(&id));
+
+        build_query(id, host, &self.options)
+    }
+
+    /// Binds the transport before a query if it is not bound yet, or binds a fresh one if the
+    /// source port is randomized.
+    fn bind_query(&mut self) -> Result<()> {
+        if self.bound && self.random_source_port {
+            self.rw = None;
+            self.bound = false;
+        }
+        if !self.bound {
+            self.bind()?;
+        }
+
+        Ok(())
+    }
+
+    /// Binds the transport if it is not bound yet and applies the timeout.
+    pub fn bind(&mut self) -> Result<()> {
+        if matches!(&self.options.payload, Some(payload) if payload.len() < 2) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "The payload must have a transaction ID of 2 bytes",
+            ));
+        }
+        if self.rw.is_none() {
+            let local = match self.addr {
+                SocketAddr::V4(_) => {
+                    SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), self.source_port)
+                }
+                // A link-local server is only reachable on the interface of its zone
+                SocketAddr::V6(addr) => SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::UNSPECIFIED,
+                    self.source_port,
+                    0,
+                    addr.scope_id(),
+                )),
+            };
+            if self.source_port != 0 && self.tcp {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "The source port cannot be set over TCP",
+                ));
+            }
+            if self.multicast && (self.tcp || self.proxy.is_some()) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "A multicast server can only be pinged over UDP without a proxy",
+                ));
+            }
+            if self.unix.is_some()
+                && (self.proxy.is_some() || self.multicast || self.source_port != 0)
+            {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "A Unix socket can only be used without a proxy or a source port",
+                ));
+            }
+            if self.interface.is_some() && (self.tcp || self.proxy.is_some() || self.unix.is_some())
+            {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "The interface can only be set over UDP without a proxy",
+                ));
+            }
+            if self.dual_stack && (self.tcp || self.proxy.is_some() || self.unix.is_some()) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "A dual-stack socket can only be bound over UDP without a proxy",
+                ));
+            }
+            let source_port = self.source_port;
+            let in_use = |e: Error| match e.kind() {
+                ErrorKind::AddrInUse => Error::new(
+                    ErrorKind::AddrInUse,
+                    format!("The source port {} is already in use", source_port),
+                ),
+                _ => e,
+            };
+            let instant = Instant::now();
+            let rw: Box<dyn RW> = match (&self.proxy, self.tcp) {
+                #[cfg(unix)]
+                (_, true) if self.unix.is_some() => {
+                    Box::new(UnixStream::connect(self.unix.as_ref().unwrap(), self.addr)?)
+                }
+                #[cfg(unix)]
+                (_, false) if self.unix.is_some() => {
+                    Box::new(Unix::connect(self.unix.as_ref().unwrap(), self.addr)?)
+                }
+                #[cfg(not(unix))]
+                _ if self.unix.is_some() => {
+                    return Err(Error::new(
+                        ErrorKind::Unsupported,
+                        "A Unix socket is not supported on the platform",
+                    ))
+                }
+                (Some(Proxy::Socks(proxy, auth)), false) => {
+                    Box::new(Datagram::bind(*proxy, local, auth.clone()).map_err(in_use)?)
+                }
+                (Some(Proxy::Socks(proxy, auth)), true) => {
+                    Box::new(Stream::connect_with_socks(*proxy, self.addr, auth.clone())?)
+                }
+                (Some(Proxy::Http(_, _)), false) => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        "An HTTP proxy can only be used with TCP",
+                    ))
+                }
+                (Some(Proxy::Http(proxy, auth)), true) => {
+                    Box::new(Stream::connect_with_http(*proxy, self.addr, auth.clone())?)
+                }
+                (None, false) => {
+                    let mut socket = match (self.dual_stack, local) {
+                        (true, SocketAddr::V6(local)) => Socket::bind_dual_stack(local),
+                        _ => Socket::bind(local),
+                    }
+                    .map_err(in_use)?;
+                    if let Some(interface) = &self.interface {
+                        socket.bind_device(interface).map_err(|e| match e.kind() {
+                            ErrorKind::Unsupported => Error::new(
+                                ErrorKind::Unsupported,
+                                "Binding to an interface is not supported on the platform",
+                            ),
+                            _ => Error::new(
+                                e.kind(),
+                                format!("Cannot bind to the interface {}: {}", interface, e),
+                            ),
+                        })?;
+                    }
+                    if self.multicast {
+                        socket.join_multicast(self.addr.ip())?;
+                    } else if !self.options.accept_any_source {
+                        socket.connect(self.addr)?;
+                    }
+
+                    Box::new(socket)
+                }
+                (None, true) => Box::new(Stream::connect(self.addr)?),
+            };
+            if self.tcp {
+                self.connect = Some(instant.elapsed());
+            }
+            self.rw = Some(rw);
+        } else if self.random_source_port {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "The source port cannot be randomized with a transport set explicitly",
+            ));
+        }
+        if self.tcp && self.buffer.len() < u16::MAX as usize {
+            self.buffer.resize(u16::MAX as usize, 0);
+        }
+        if let Some(pcap) = self.pcap.clone() {
+            let rw = self.rw.take().unwrap();
+            self.rw = Some(Box::new(Capture::new(
+                rw,
+                self.addr,
+                self.source_port,
+                pcap,
+            )));
+        }
+        if let Some(rw) = &self.rw {
+            let unsupported = |option: &'static str| {
+                move |e: Error| match e.kind() {
+                    ErrorKind::Unsupported => Error::new(
+                        ErrorKind::Unsupported,
+                        format!("Setting the {} is not supported by the transport", option),
+                    ),
+                    _ => e,
+                }
+            };
+            rw.set_read_timeout(self.timeout)?;
+            if let Some(tos) = self.tos {
+                rw.set_tos(tos).map_err(unsupported("ToS"))?;
+            }
+            if let Some(size) = self.recv_buffer_size {
+                rw.set_recv_buffer_size(size)
+                    .map_err(unsupported("receive buffer size"))?;
+            }
+            if let Some(size) = self.send_buffer_size {
+                rw.set_send_buffer_size(size)
+                    .map_err(unsupported("send buffer size"))?;
+            }
+        }
+        self.bound = true;
+
+        Ok(())
+    }
+
+    /// Pings the server with the next transaction ID.
+    pub fn ping(&mut self) -> Result<Reply> {
+        let host = self.host.clone();
+
+        self.ping_host(&host)
+    }
+
+    /// Pings the server for the given host with the next transaction ID. A stream closed by the
+    /// server or the proxy fails the query, and is connected again for the next one.
+    pub fn ping_host(&mut self, host: &str) -> Result<Reply> {
+        let result = self.query_host(host);
+        if let Err(e) = &result {
+            self.drop_closed(e);
+        }
+
+        result
+    }
+
+    /// Drops the stream if the error means it is closed, so that the next query reconnects.
+    fn drop_closed(&mut self, e: &Error) {
+        if self.tcp && is_connection_closed(e) {
+            self.rw = None;
+            self.bound = false;
+        }
+    }
+
+    fn query_host(&mut self, host: &str) -> Result<Reply> {
+        self.bind_query()?;
+        self.next_id();
+
+        let rw = self.rw.as_ref().unwrap().as_ref();
+        let instant = send_query(rw, self.addr, self.id, host, &self.options)?;
+        loop {
+            let response = match recv_reply(
+                rw,
+                self.addr,
+                self.id,
+                host,
+                &self.options,
+                &mut self.buffer,
+                instant,
+                &self.answered,
+            ) {
+                Ok(response) => response,
+                Err(e) => {
+                    if e.kind() == ErrorKind::TimedOut {
+                        self.push_unanswered(self.id);
+                    }
+                    return Err(e);
+                }
+            };
+            let (mut reply, answered) = match response {
+                Response::Reply(reply, answered) => (reply, answered),
+                Response::Duplicate(reply) => {
+                    self.duplicates.push(reply);
+                    continue;
+                }
+                Response::Foreign(source) => {
+                    self.foreign.push(source);
+                    continue;
+                }
+                Response::Unmatched => continue,
+            };
+
+            // Recognize the duplicate responses of the query from now on
+            if self.answered.len() >= ANSWERED_LEN {
+                self.answered.pop_front();
+            }
+            self.answered.push_back(answered);
+
+            reply.connect = self.connect.take();
+
+            return Ok(reply);
+        }
+    }
+
+    /// Receives the duplicate responses of the latest answered queries until the timeout, which is
+    /// useful to catch the duplicates of the last query before stopping.
+    pub fn recv_duplicates(&mut self, timeout: Duration) -> Result<Vec<Reply>> {
+        if !self.bound {
+            self.bind()?;
+        }
+
+        let rw = self.rw.as_ref().unwrap().as_ref();
+        let instant = Instant::now();
+        let e = loop {
+            // No query is waiting for its reply, so only duplicates are received until the timeout
+            match recv_reply_until(
+                rw,
+                self.addr,
+                self.id,
+                "",
+                &self.options,
+                &mut self.buffer,
+                instant,
+                &self.answered,
+                Some(timeout),
+            ) {
+                Ok(Response::Duplicate(reply)) => self.duplicates.push(reply),
+                Ok(Response::Foreign(source)) => self.foreign.push(source),
+                Ok(_) => {}
+                Err(e) => break e,
+            }
+        };
+        rw.set_read_timeout(self.timeout)?;
+
+        match e.kind() {
+            ErrorKind::TimedOut | ErrorKind::WouldBlock => Ok(self.take_duplicates()),
+            _ => Err(e),
+        }
+    }
+
+    /// Pings the server with the next transaction ID and collects all the replies until the
+    /// timeout, which is useful for a multicast server with several responders. An error is
+    /// returned if there is no reply.
+    pub fn ping_all(&mut self) -> Result<Vec<Reply>> {
+        let host = self.host.clone();
+
+        self.ping_all_host(&host)
+    }
+
+    /// Pings the server for the given host with the next transaction ID and collects all the
+    /// replies until the timeout.
+    pub fn ping_all_host(&mut self, host: &str) -> Result<Vec<Reply>> {
+        self.bind_query()?;
+        self.next_id();
+
+        let rw = self.rw.as_ref().unwrap().as_ref();
+        let instant = send_query(rw, self.addr, self.id, host, &self.options)?;
+
+        let mut replies = Vec::new();
+        let result = loop {
+            // Wait for the rest of the timeout only
+            if let Some(timeout) = self.timeout {
+                match timeout.checked_sub(instant.elapsed()) {
+                    Some(remain) if remain > Duration::from_millis(0) => {
+                        rw.set_read_timeout(Some(remain))?
+                    }
+                    _ => break Ok(()),
+                }
+            }
+
+            match recv_reply(
+                rw,
+                self.addr,
+                self.id,
+                host,
+                &self.options,
+                &mut self.buffer,
+                instant,
+                &VecDeque::new(),
+            ) {
+                Ok(Response::Reply(reply, _)) | Ok(Response::Duplicate(reply)) => {
+                    replies.push(reply)
+                }
+                Ok(Response::Foreign(source)) => self.foreign.push(source),
+                Ok(Response::Unmatched) => {}
+                Err(e) => break Err(e),
+            }
+        };
+        rw.set_read_timeout(self.timeout)?;
+
+        match result {
+            Err(e) if replies.is_empty() => Err(e),
+            _ => Ok(replies),
+        }
+    }
+
+    /// Pings the server with `n` queries of the next transaction IDs at once and collects their
+    /// replies until all of them are answered or the timeout. An error is returned if there is no
+    /// reply.
+    pub fn ping_burst(&mut self, n: usize) -> Result<Vec<Reply>> {
+        let host = self.host.clone();
+
+        self.ping_burst_host(&host, n)
+    }
+
+    /// Pings the server for the given host with `n` queries of the next transaction IDs at once
+    /// and collects their replies until all of them are answered or the timeout.
+    pub fn ping_burst_host(&mut self, host: &str, n: usize) -> Result<Vec<Reply>> {
+        let result = self.query_burst(host, n);
+        if let Err(e) = &result {
+            self.drop_closed(e);
+        }
+
+        result
+    }
+
+    fn query_burst(&mut self, host: &str, n: usize) -> Result<Vec<Reply>> {
+        if self.options.payload.is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Queries with a payload cannot be sent in bursts",
+            ));
+        }
+        // A lost query would be waited for forever
+        if self.timeout.is_none() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Queries can only be sent in bursts with a timeout",
+            ));
+        }
+        self.bind_query()?;
+
+        let instant = Instant::now();
+        let mut pending = HashMap::new();
+        for _ in 0..n {
+            self.next_id();
+            let rw = self.rw.as_ref().unwrap().as_ref();
+            let instant = send_query(rw, self.addr, self.id, host, &self.options)?;
+            pending.insert(self.id, instant);
+        }
+
+        let rw = self.rw.as_ref().unwrap().as_ref();
+        let mut replies = Vec::new();
+        let result = loop {
+            if pending.is_empty() {
+                break Ok(());
+            }
+
+            // Wait for the rest of the timeout only
+            if let Some(timeout) = self.timeout {
+                match timeout.checked_sub(instant.elapsed()) {
+                    Some(remain) if remain > Duration::from_millis(0) => {
+                        rw.set_read_timeout(Some(remain))?
+                    }
+                    _ => break Err(Error::from(ErrorKind::TimedOut)),
+                }
+            }
+
+            let options = &self.options;
+            let response = recv_response(
+                rw,
+                self.addr,
+                options,
+                &mut self.buffer,
+                &self.answered,
+                |packet| match is_question_matched(packet, host, options) {
+                    true => pending
+                        .remove(&packet.header.id)
+                        .map(|instant| (String::from(host), instant)),
+                    false => None,
+                },
+            );
+            match response {
+                Ok(Response::Reply(reply, answered)) => {
+                    replies.push(reply);
+
+                    // Recognize the duplicate responses of the query from now on
+                    if self.answered.len() >= ANSWERED_LEN {
+                        self.answered.pop_front();
+                    }
+                    self.answered.push_back(answered);
+                }
+                Ok(Response::Duplicate(reply)) => self.duplicates.push(reply),
+                Ok(Response::Foreign(source)) => self.foreign.push(source),
+                Ok(Response::Unmatched) => {}
+                Err(e) => break Err(e),
+            }
+        };
+        rw.set_read_timeout(self.timeout)?;
+        for id in pending.into_keys() {
+            self.push_unanswered(id);
+        }
+        if let Some(reply) = replies.first_mut() {
+            reply.connect = self.connect.take();
+        }
+
+        match result {
+            Err(e) if replies.is_empty() => Err(e),
+            _ => Ok(replies),
+        }
+    }
+
+    /// Splits the pinger into a sender and a receiver sharing the transport, so that queries can be
+    /// sent at a fixed rate without waiting for their replies. The transport is bound if it is not
+    /// bound yet.
+    pub fn split(mut self) -> Result<(PingSender, PingReceiver)> {
+        if self.random_source_port {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "A pinger with random source ports cannot be split",
+            ));
+        }
+        if self.options.payload.is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "A pinger with a payload cannot be split",
+            ));
+        }
+        if !self.bound {
+            self.bind()?;
+        }
+
+        let rw: Arc<dyn RW> = Arc::from(self.rw.take().unwrap());
+        let outstanding = Arc::new(Mutex::new(HashMap::new()));
+        let sender = PingSender {
+            rw: Arc::clone(&rw),
+            addr: self.addr,
+            host: self.host,
+            options: self.options.clone(),
+            id: self.id,
+            random_id: self.random_id,
+            outstanding: Arc::clone(&outstanding),
+        };
+        let receiver = PingReceiver {
+            rw,
+            addr: self.addr,
+            options: self.options,
+            buffer: self.buffer,
+            outstanding,
+            answered: VecDeque::new(),
+            latest: None,
+            foreign: Vec::new(),
+        };
+
+        Ok((sender, receiver))
+    }
+
+    /// Returns an iterator pinging the server every interval, `None` count as pinging without
+    /// limit.
+    pub fn iter(&mut self, interval: Duration, count: Option<usize>) -> Iter<'_> {
+        Iter {
+            pinger: self,
+            interval,
+            count,
+            send: 0,
+            deadline: None,
+        }
+    }
+}
+
+/// Represents an iterator pinging a DNS server every interval, created by `Pinger::iter`.
+pub struct Iter<'a> {
+    pinger: &'a mut Pinger,
+    interval: Duration,
+    count: Option<usize>,
+    send: usize,
+    deadline: Option<Instant>,
+}
+
+impl Iterator for Iter<'_> {
+    type Item = Result<Reply>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(count) = self.count {
+            if self.send >= count {
+                return None;
+            }
+        }
+
+        // Sleep until the next scheduled send, and reschedule from now if it is behind
+        let now = Instant::now();
+        let deadline = match self.deadline {
+            Some(deadline) => {
+                thread::sleep(deadline.saturating_duration_since(now));
+                deadline.max(now)
+            }
+            None => now,
+        };
+        self.deadline = Some(deadline + self.interval);
+        self.send += 1;
+
+        Some(self.pinger.ping())
+    }
+}
+
+/// Represents the queries waiting for their replies by their transaction IDs.
+type Outstanding = Arc<Mutex<HashMap<u16, (String, Instant)>>>;
+
+/// Represents the sending half of a pinger, created by `Pinger::split`.
+pub struct PingSender {
+    rw: Arc<dyn RW>,
+    addr: SocketAddr,
+    host: String,
+    options: Options,
+    id: u16,
+    random_id: bool,
+    outstanding: Outstanding,
+}
+
+impl PingSender {
+    /// Sends a query with the next transaction ID and returns the ID.
+    pub fn send(&mut self) -> Result<u16> {
+        let host = self.host.clone();
+
+        self.send_host(&host)
+    }
+
+    /// Sends a query for the given host with the next transaction ID and returns the ID. The IDs of
+    /// the queries still waiting for their replies are skipped, unless all the IDs are in flight
+    /// and the query replaces one of them.
+    pub fn send_host(&mut self, host: &str) -> Result<u16> {
+        {
+            let outstanding = self.outstanding.lock().unwrap();
+            self.id = next_id(self.id, self.random_id, |id| outstanding.contains_key(&id));
+        }
+
+        let buffer = build_query(self.id, host, &self.options)?;
+
+        // The query waits for its reply before it is sent, so that a fast reply received before
+        // the send returns is still matched
+        self.outstanding
+            .lock()
+            .unwrap()
+            .insert(self.id, (String::from(host), Instant::now()));
+        if let Err(e) = self.rw.send_to(buffer.as_slice(), self.addr) {
+            self.outstanding.lock().unwrap().remove(&self.id);
+            return Err(e);
+        }
+
+        Ok(self.id)
+    }
+}
+
+/// Represents the receiving half of a pinger, created by `Pinger::split`.
+pub struct PingReceiver {
+    rw: Arc<dyn RW>,
+    addr: SocketAddr,
+    options: Options,
+    buffer: Vec<u8>,
+    outstanding: Outstanding,
+    answered: VecDeque<Answered>,
+    latest: Option<Instant>,
+    foreign: Vec<SocketAddr>,
+}
+
+impl PingReceiver {
+    /// Sets the read timeout of the transport, which is the longest time `recv` blocks.
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> Result<()> {
+        self.rw.set_read_timeout(dur)
+    }
+
+    /// Receives the reply of any query waiting for it, or a duplicate reply of a recently answered
+    /// query. Responses of no such query are ignored.
+    pub fn recv(&mut self) -> Result<Reply> {
+        loop {
+            let outstanding = &self.outstanding;
+            let options = &self.options;
+            let response = recv_response(
+                self.rw.as_ref(),
+                self.addr,
+                options,
+                &mut self.buffer,
+                &self.answered,
+                |packet| {
+                    let mut outstanding = outstanding.lock().unwrap();
+                    match outstanding.get(&packet.header.id) {
+                        Some((host, _)) if is_question_matched(packet, host, options) => {
+                            outstanding.remove(&packet.header.id)
+                        }
+                        _ => None,
+                    }
+                },
+            )?;
+            let (mut reply, answered) = match response {
+                Response::Reply(reply, answered) => (reply, answered),
+                Response::Duplicate(reply) => return Ok(reply),
+                Response::Foreign(source) => {
+                    self.foreign.push(source);
+                    continue;
+                }
+                Response::Unmatched => continue,
+            };
+            let instant = answered.instant;
+
+            // A reply of a query sent before the latest answered one arrives out of order
+            reply.out_of_order = matches!(self.latest, Some(latest) if instant < latest);
+            self.latest = Some(self.latest.map_or(instant, |latest| latest.max(instant)));
+            if self.answered.len() >= RECEIVER_ANSWERED_LEN {
+                self.answered.pop_front();
+            }
+            self.answered.push_back(answered);
+
+            return Ok(reply);
+        }
+    }
+
+    /// Returns the sources of the responses ignored since the last call as they are not from the
+    /// server.
+    pub fn take_foreign(&mut self) -> Vec<SocketAddr> {
+        mem::take(&mut self.foreign)
+    }
+
+    /// Gives up the queries waiting for their replies longer than the timeout, and returns their
+    /// transaction IDs.
+    pub fn expire(&self, timeout: Duration) -> Vec<u16> {
+        let mut outstanding = self.outstanding.lock().unwrap();
+        let expired = outstanding
+            .iter()
+            .filter(|(_, (_, instant))| instant.elapsed() >= timeout)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in expired.iter() {
+            outstanding.remove(id);
+        }
+
+        expired
+    }
+
+    /// Returns the number of the queries waiting for their replies.
+    pub fn pending(&self) -> usize {
+        self.outstanding.lock().unwrap().len()
+    }
+}
+
+/// Represents the configuration of `run`.
+pub struct PingConfig {
+    /// Pinger pinging the server.
+    pub pinger: Pinger,
+    /// Hosts to query in turn, empty as querying the host of the pinger.
+    pub hosts: Vec<String>,
+    /// Interval between sending each query.
+    pub interval: Duration,
+    /// Upper bound of the random delay added to each interval, which also shifts the start.
+    pub jitter: Duration,
+    /// Number of queries to send, `None` as sending without limit.
+    pub count: Option<usize>,
+    /// Whether the count is of the replies received instead of the queries sent.
+    pub count_received: bool,
+    /// Number of NOERROR replies to stop after, `None` as not stopping early.
+    pub wait_for: Option<usize>,
+    /// Number of queries sent first, which are not counted in the statistics.
+    pub warmup: usize,
+    /// Queries to send per second regardless of the replies, `None` as waiting for the reply of
+    /// each query before the next interval. The run is aborted if the period between queries of
+    /// the rate does not fit a `Duration`.
+    pub rate: Option<f64>,
+    /// Number of queries to send at once of which only the fastest reply is counted.
+    pub best_of: Option<usize>,
+    /// Maximum interval to back off to exponentially on REFUSED or SERVFAIL, `None` as not backing
+    /// off.
+    pub backoff: Option<Duration>,
+    /// Whether to query a random subdomain of the host each time.
+    pub randomize_subdomain: bool,
+    /// Whether to randomize the case of the host each time.
+    pub randomize_case: bool,
+    /// Whether to wait for the duplicate replies or the replies in flight until the timeout when
+    /// stopping.
+    pub drain: bool,
+}
+
+impl PingConfig {
+    /// Creates a new `PingConfig` pinging with the pinger every second without limit.
+    pub fn new(pinger: Pinger) -> PingConfig {
+        PingConfig {
+            pinger,
+            hosts: Vec::new(),
+            interval: Duration::from_secs(1),
+            jitter: Duration::from_secs(0),
+            count: None,
+            count_received: false,
+            wait_for: None,
+            warmup: 0,
+            rate: None,
+            best_of: None,
+            backoff: None,
+            randomize_subdomain: false,
+            randomize_case: false,
+            drain: false,
+        }
+    }
+
+    fn queries(&self) -> Queries {
+        let hosts = match self.hosts.is_empty() {
+            true => vec![self.pinger.host.clone()],
+            false => self.hosts.clone(),
+        };
+
+        Queries {
+            hosts,
+            warmup: self.warmup,
+            randomize_subdomain: self.randomize_subdomain,
+            randomize_case: self.randomize_case,
+        }
+    }
+
+    fn limits(&self) -> Limits {
+        Limits {
+            count: self.count,
+            count_received: self.count_received,
+            wait_for: self.wait_for,
+        }
+    }
+}
+
+/// Generates the queries of a run, which cycle through the hosts.
+struct Queries {
+    hosts: Vec<String>,
+    warmup: usize,
+    randomize_subdomain: bool,
+    randomize_case: bool,
+}
+
+impl Queries {
+    fn query<R: Rng>(&self, rng: &mut R, seq: usize) -> Query {
+        let host = &self.hosts[seq % self.hosts.len()];
+        let mut host = match self.randomize_subdomain {
+            true => random_subdomain(rng, host),
+            false => host.clone(),
+        };
+        if self.randomize_case {
+            host = randomize_case(rng, &host);
+        }
+
+        Query {
+            seq,
+            host,
+            timestamp: SystemTime::now(),
+            warmup: seq < self.warmup,
+        }
+    }
+}
+
+/// Returns a random delay up to the jitter, which is none if the interval is 0.
+fn jitter<R: Rng>(rng: &mut R, jitter: Duration, interval: Duration) -> Duration {
+    let jitter = jitter.as_millis() as u64;
+    match interval.as_millis() == 0 || jitter == 0 {
+        true => Duration::from_millis(0),
+        false => Duration::from_millis(rng.gen_range(0, jitter)),
+    }
+}
+
+/// Represents when a run is finished.
+#[derive(Clone, Copy, Debug)]
+struct Limits {
+    count: Option<usize>,
+    count_received: bool,
+    wait_for: Option<usize>,
+}
+
+impl Limits {
+    /// Returns whether the run is finished after the queries sent, the replies received and the
+    /// NOERROR ones among them, not counting the warmup ones.
+    fn is_finished(&self, sent: usize, received: usize, succeeded: usize) -> bool {
+        let counted = match self.count_received {
+            true => received,
+            false => sent,
+        };
+
+        matches!(self.count, Some(count) if counted >= count)
+            || matches!(self.wait_for, Some(wait_for) if succeeded >= wait_for)
+    }
+}
+
+/// Represents the statistics of `run`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    /// Number of queries sent.
+    pub sent: usize,
+    /// Number of replies received.
+    pub received: usize,
+    /// Minimum round-trip time, `None` if there is no reply.
+    pub min: Option<Duration>,
+    /// Average round-trip time, `None` if there is no reply.
+    pub avg: Option<Duration>,
+    /// Maximum round-trip time, `None` if there is no reply.
+    pub max: Option<Duration>,
+    /// Packet loss in percentage.
+    pub loss: f64,
+}
+
+/// Represents a query sent by `run_events` or `race`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Query {
+    /// Sequence number of the query.
+    pub seq: usize,
+    /// Name queried, which is a random subdomain of the host or in a random case if enabled.
+    pub host: String,
+    /// Time the query is sent.
+    pub timestamp: SystemTime,
+    /// Whether the query warms the server up, which is not counted in the statistics.
+    pub warmup: bool,
+}
+
+/// Represents an event passed to the consumer of `run_events`.
+#[derive(Debug)]
+pub enum PingEvent {
+    /// Reply to the query. A query to a multicast server has a reply of each responder.
+    Reply(Query, Reply),
+    /// Duplicate reply to the query of the sequence number, `None` if the query is no longer
+    /// known.
+    Duplicate(Option<usize>, Reply),
+    /// The query timed out.
+    Timeout(Query),
+    /// The query failed with the error, which is counted as a loss.
+    Error(Query, Error),
+    /// The transport failed with the error without failing any query, like a malformed response
+    /// received while pinging at a fixed rate.
+    Warning(Error),
+    /// The interval is backed off to the duration after the consecutive failures, or recovered if
+    /// there is none.
+    Backoff(Duration, u32),
+    /// The run is aborted by the error, which fails the query if any.
+    Abort(Option<Query>, Error),
+    /// Statistics of the run, which is the last event.
+    Summary(Stats),
+}
+
+/// Records the statistics of the events of a run.
+#[derive(Debug, Default)]
+struct Recorder {
+    stats: Stats,
+    total: Duration,
+    replied: Option<usize>,
+    succeeded: usize,
+}
+
+impl Recorder {
+    fn record(&mut self, event: &PingEvent) {
+        match event {
+            // Only the first reply of a query to a multicast server is counted
+            PingEvent::Reply(query, reply) if !query.warmup && self.replied != Some(query.seq) => {
+                self.replied = Some(query.seq);
+                self.stats.sent += 1;
+                self.stats.received += 1;
+                if reply.rcode == ResponseCode::NoError {
+                    self.succeeded += 1;
+                }
+                self.total += reply.duration;
+                self.stats.min = Some(
+                    self.stats
+                        .min
+                        .map_or(reply.duration, |min| min.min(reply.duration)),
+                );
+                self.stats.max = Some(
+                    self.stats
+                        .max
+                        .map_or(reply.duration, |max| max.max(reply.duration)),
+                );
+            }
+            PingEvent::Timeout(query)
+            | PingEvent::Error(query, _)
+            | PingEvent::Abort(Some(query), _)
+                if !query.warmup =>
+            {
+                self.stats.sent += 1
+            }
+            _ => {}
+        }
+    }
+
+    /// Records the event and passes it to the consumer.
+    fn publish<F: FnMut(PingEvent)>(&mut self, publish: &mut F, event: PingEvent) {
+        self.record(&event);
+        publish(event);
+    }
+
+    fn finish(mut self) -> Stats {
+        if self.stats.received != 0 {
+            self.stats.avg = Some(self.total / self.stats.received as u32);
+        }
+        if self.stats.sent != 0 {
+            self.stats.loss =
+                (self.stats.sent - self.stats.received) as f64 / self.stats.sent as f64 * 100.0;
+        }
+
+        self.stats
+    }
+}
+
+/// Number of the latest queries whose sequence numbers are known for their duplicate replies.
+const DUPLICATE_SEQS_LEN: usize = 16;
+
+/// Returns whether the error is caused by the network, like an unreachable destination or a brief
+/// outage, which fails the query only instead of stopping pinging.
+pub fn is_network_error(e: &Error) -> bool {
+    match e.kind() {
+        ErrorKind::ConnectionRefused
+        | ErrorKind::ConnectionReset
+        | ErrorKind::HostUnreachable
+        | ErrorKind::NetworkUnreachable
+        | ErrorKind::NetworkDown
+        | ErrorKind::AddrNotAvailable => true,
+        #[cfg(unix)]
+        _ => e.raw_os_error() == Some(libc::ENOBUFS),
+        #[cfg(not(unix))]
+        _ => false,
+    }
+}
+
+/// Returns whether the error means the stream is closed by the peer, which is reconnected for the
+/// next query.
+fn is_connection_closed(e: &Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::UnexpectedEof
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+    )
+}
+
+/// Returns whether the error of the first query to the server means that the host has no IPv6
+/// connectivity, like a socket bound to `[::]` which cannot send anything.
+pub fn is_ipv6_unavailable(addr: SocketAddr, e: &Error) -> bool {
+    addr.is_ipv6()
+        && matches!(
+            e.kind(),
+            ErrorKind::NetworkUnreachable | ErrorKind::AddrNotAvailable
+        )
+}
+
+/// Returns the event of the failed query, which aborts the run unless the error is caused by a
+/// timeout, a malformed response or the network.
+fn failure(addr: SocketAddr, query: Query, e: Error) -> PingEvent {
+    let malformed = matches!(e.get_ref(), Some(e) if e.is::<ResponseError>());
+    if e.kind() == ErrorKind::TimedOut {
+        PingEvent::Timeout(query)
+    } else if query.seq == 0 && is_ipv6_unavailable(addr, &e) {
+        // The run is aborted if the first query cannot be sent over IPv6 at all
+        PingEvent::Abort(Some(query), e)
+    } else if malformed || is_network_error(&e) || is_connection_closed(&e) {
+        // The query is sent again in the next interval after a brief outage, or over a new
+        // connection if the stream is closed
+        PingEvent::Error(query, e)
+    } else {
+        PingEvent::Abort(Some(query), e)
+    }
+}
+
+/// Returns the warning of a response ignored as it is from the foreign source.
+fn foreign(source: SocketAddr) -> PingEvent {
+    PingEvent::Warning(Error::new(
+        ErrorKind::InvalidData,
+        ResponseError::Foreign(source),
+    ))
+}
+
+/// Returns the interval backed off exponentially after the consecutive failures, which is capped
+/// by the maximum but never shorter than the interval.
+fn backoff_interval(interval: Duration, failures: u32, max: Duration) -> Duration {
+    let factor = 1u32.checked_shl(failures).unwrap_or(u32::MAX);
+
+    interval.saturating_mul(factor).min(max).max(interval)
+}
+
+/// Sleeps until the deadline, and returns whether a stop signal is received. No stop signal is
+/// received if the sender is gone.
+fn sleep_until(stop: &Receiver<()>, deadline: Instant) -> bool {
+    match stop.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        Ok(()) => true,
+        Err(RecvTimeoutError::Timeout) => false,
+        Err(RecvTimeoutError::Disconnected) => {
+            thread::sleep(deadline.saturating_duration_since(Instant::now()));
+            false
+        }
+    }
+}
+
+/// Returns the sequence number of the latest query with the ID.
+fn find_seq(seqs: &VecDeque<(u16, usize)>, id: u16) -> Option<usize> {
+    seqs.iter()
+        .rev()
+        .find(|(seq_id, _)| *seq_id == id)
+        .map(|(_, seq)| *seq)
+}
+
+/// Pings the server every interval until the count is reached or a stop signal is received, and
+/// returns the statistics. Any error of a query is counted as a loss.
+pub fn run(config: PingConfig, stop: Receiver<()>) -> Stats {
+    run_events(config, stop, |_| {})
+}
+
+/// Pings the server like `run`, and passes the event of each query and the statistics to the
+/// consumer in the pinging thread, so that it can print or render each reply or timeout live, or
+/// forward the events to another thread over a channel.
+pub fn run_events<F: FnMut(PingEvent)>(
+    config: PingConfig,
+    stop: Receiver<()>,
+    mut publish: F,
+) -> Stats {
+    let mut recorder = Recorder::default();
+    match config.rate {
+        Some(rate) => ping_at_rate(config, rate, &stop, &mut recorder, &mut publish),
+        None => ping_every_interval(config, &stop, &mut recorder, &mut publish),
+    }
+
+    let stats = recorder.finish();
+    publish(PingEvent::Summary(stats.clone()));
+
+    stats
+}
+
+/// Pings the server every interval, waiting for the reply of each query before the next one.
+fn ping_every_interval<F: FnMut(PingEvent)>(
+    config: PingConfig,
+    stop: &Receiver<()>,
+    recorder: &mut Recorder,
+    publish: &mut F,
+) {
+    let queries = config.queries();
+    let limits = config.limits();
+    let mut pinger = config.pinger;
+    let addr = pinger.addr;
+    let mut rng = rand::thread_rng();
+    let mut deadline = Instant::now();
+    let mut interval = config.interval;
+    let mut failures = 0;
+    let mut seqs = VecDeque::new();
+
+    // Shift the schedule by a random delay, so instances started together do not send queries in
+    // step
+    deadline += jitter(&mut rng, config.jitter, interval);
+    if sleep_until(stop, deadline) {
+        return;
+    }
+    for seq in 0.. {
+        let query = queries.query(&mut rng, seq);
+        let warmup = query.warmup;
+        let result = match (pinger.multicast, config.best_of) {
+            (true, _) => pinger.ping_all_host(&query.host),
+            // Only the fastest reply of the queries sent at once is counted
+            (false, Some(n)) => pinger.ping_burst_host(&query.host, n).map(|replies| {
+                let fastest = replies.into_iter().min_by_key(|reply| reply.duration);
+                fastest.into_iter().collect()
+            }),
+            (false, None) => pinger.ping_host(&query.host).map(|reply| vec![reply]),
+        };
+        for source in pinger.take_foreign() {
+            recorder.publish(publish, foreign(source));
+        }
+        for reply in pinger.take_duplicates() {
+            let seq = find_seq(&seqs, reply.id);
+            recorder.publish(publish, PingEvent::Duplicate(seq, reply));
+        }
+        if seqs.len() >= DUPLICATE_SEQS_LEN {
+            seqs.pop_front();
+        }
+        seqs.push_back((pinger.id(), seq));
+        let rcode = match result {
+            Ok(replies) => {
+                let rcode = replies.first().map(|reply| reply.rcode);
+                for reply in replies {
+                    recorder.publish(publish, PingEvent::Reply(query.clone(), reply));
+                }
+
+                rcode
+            }
+            Err(e) => {
+                let event = failure(addr, query, e);
+                if let PingEvent::Abort(_, _) = event {
+                    recorder.publish(publish, event);
+                    return;
+                }
+                recorder.publish(publish, event);
+
+                None
+            }
+        };
+
+        // Reach max send count or wanted reply count
+        if !warmup
+            && limits.is_finished(
+                recorder.stats.sent,
+                recorder.stats.received,
+                recorder.succeeded,
+            )
+        {
+            break;
+        }
+
+        // Back off while the server refuses or fails, which may be rate limiting
+        if let Some(max) = config.backoff {
+            match rcode {
+                Some(ResponseCode::Refused) | Some(ResponseCode::ServerFailure) => failures += 1,
+                Some(_) => failures = 0,
+                None => {}
+            }
+            let next_interval = backoff_interval(config.interval, failures, max);
+            if next_interval != interval {
+                interval = next_interval;
+                recorder.publish(publish, PingEvent::Backoff(interval, failures));
+            }
+        }
+
+        // Sleep until the next scheduled send, and reschedule from now if it is behind
+        let now = Instant::now();
+        deadline += interval;
+        if deadline < now {
+            deadline = now;
+        }
+        if sleep_until(stop, deadline + jitter(&mut rng, config.jitter, interval)) {
+            break;
+        }
+    }
+
+    // Catch the duplicates of the last query while draining
+    if config.drain && !pinger.multicast {
+        let timeout = pinger.timeout.unwrap_or_default();
+        if let Ok(duplicates) = pinger.recv_duplicates(timeout) {
+            for reply in duplicates {
+                let seq = find_seq(&seqs, reply.id);
+                recorder.publish(publish, PingEvent::Duplicate(seq, reply));
+            }
+        }
+    }
+}
+
+/// Pings the server at a fixed rate in a sending thread regardless of the replies, which are
+/// received in the current thread.
+/// Sets the flag on drop, which tells the receiver of `ping_at_rate` that the sender is done.
+struct Done<'a>(&'a AtomicBool);
+
+impl Drop for Done<'_> {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+fn ping_at_rate<F: FnMut(PingEvent)>(
+    config: PingConfig,
+    rate: f64,
+    stop: &Receiver<()>,
+    recorder: &mut Recorder,
+    publish: &mut F,
+) {
+    let queries = &config.queries();
+    let limits = config.limits();
+    let drain = config.drain;
+    let addr = config.pinger.addr;
+    let timeout = config.pinger.timeout;
+    let (mut sender, mut receiver) = match config.pinger.split() {
+        Ok(pinger) => pinger,
+        Err(e) => return recorder.publish(publish, PingEvent::Abort(None, e)),
+    };
+    // A rate too low has a period longer than any duration
+    let period = match Duration::try_from_secs_f64(1.0 / rate) {
+        Ok(period) => period,
+        Err(_) => {
+            let e = Error::new(ErrorKind::InvalidInput, format!("Invalid rate {}", rate));
+            return recorder.publish(publish, PingEvent::Abort(None, e));
+        }
+    };
+    // Wake up periodically to give up the queries timed out
+    if let Err(e) = receiver.set_read_timeout(Some(Duration::from_millis(100))) {
+        return recorder.publish(publish, PingEvent::Abort(None, e));
+    }
+
+    // Queries waiting for their replies by their transaction IDs
+    let sent = &Mutex::new(HashMap::<u16, Query>::new());
+    let done = &AtomicBool::new(false);
+    let (halt, halted) = mpsc::channel::<()>();
+    let (failures_tx, failures) = mpsc::channel();
+    thread::scope(|scope| {
+        // Send
+        scope.spawn(move || {
+            // The receiver is signaled even if the sender panics
+            let _done = Done(done);
+            let mut rng = rand::thread_rng();
+            let mut next = Instant::now();
+            let mut counted = 0;
+            for seq in 0.. {
+                let query = queries.query(&mut rng, seq);
+                let warmup = query.warmup;
+                let mut sent = sent.lock().unwrap();
+                match sender.send_host(&query.host) {
+                    Ok(id) => {
+                        sent.insert(id, query);
+                    }
+                    // A query failed to send is counted as lost
+                    Err(e) => {
+                        let event = failure(addr, query, e);
+                        let aborted = matches!(event, PingEvent::Abort(_, _));
+                        let _ = failures_tx.send(event);
+                        if aborted {
+                            break;
+                        }
+                    }
+                }
+                drop(sent);
+
+                // The sender stops at the count of the queries sent, and the receiver at the
+                // others
+                if !warmup {
+                    counted += 1;
+                    if limits.is_finished(counted, 0, 0) {
+                        break;
+                    }
+                }
+
+                // Send on a fixed schedule, which does not drift with the time spent sending. A
+                // query beyond the range of instants is never due
+                next = match next.checked_add(period) {
+                    Some(next) => next,
+                    None => break,
+                };
+                match halted.recv_timeout(next.saturating_duration_since(Instant::now())) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => break,
+                }
+            }
+        });
+
+        // Receive until the sender is done and no query is waiting for its reply
+        let mut halt = Some(halt);
+        let mut answered = VecDeque::new();
+        loop {
+            for event in failures.try_iter() {
+                let aborted = matches!(event, PingEvent::Abort(_, _));
+                recorder.publish(publish, event);
+                if aborted {
+                    return;
+                }
+            }
+            match receiver.recv() {
+                Ok(reply) if reply.duplicate => {
+                    let seq = find_seq(&answered, reply.id);
+                    recorder.publish(publish, PingEvent::Duplicate(seq, reply));
+                }
+                Ok(reply) => {
+                    let query = sent.lock().unwrap().remove(&reply.id);
+                    if let Some(query) = query {
+                        if answered.len() >= DUPLICATE_SEQS_LEN {
+                            answered.pop_front();
+                        }
+                        answered.push_back((reply.id, query.seq));
+                        recorder.publish(publish, PingEvent::Reply(query, reply));
+                    }
+                }
+                Err(e) => match e.kind() {
+                    ErrorKind::TimedOut | ErrorKind::WouldBlock => {}
+                    ErrorKind::InvalidData => recorder.publish(publish, PingEvent::Warning(e)),
+                    _ if is_network_error(&e) => recorder.publish(publish, PingEvent::Warning(e)),
+                    _ => return recorder.publish(publish, PingEvent::Abort(None, e)),
+                },
+            }
+            for source in receiver.take_foreign() {
+                recorder.publish(publish, foreign(source));
+            }
+            if let Some(timeout) = timeout {
+                let expired = {
+                    let mut sent = sent.lock().unwrap();
+                    receiver
+                        .expire(timeout)
+                        .into_iter()
+                        .filter_map(|id| sent.remove(&id))
+                        .collect::<Vec<_>>()
+                };
+                for query in expired {
+                    recorder.publish(publish, PingEvent::Timeout(query));
+                }
+            }
+
+            if limits.is_finished(0, recorder.stats.received, recorder.succeeded) {
+                return;
+            }
+            // Stop sending on a stop signal, and wait for the replies in flight if draining
+            if stop.try_recv().is_ok() {
+                drop(halt.take());
+                if !drain {
+                    return;
+                }
+            }
+            if done.load(Ordering::Relaxed) && receiver.pending() == 0 {
+                return;
+            }
+        }
+        // The sender is woken up as soon as the halt is dropped
+    });
+}
+
+/// Represents an event passed to the consumer of `race`.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum RaceEvent {
+    /// Event of the configuration of the index.
+    Ping(usize, PingEvent),
+    /// End of the round of the sequence number, with the index of the configuration of the fastest
+    /// reply and its round-trip time if any.
+    Round(usize, Option<(usize, Duration)>),
+}
+
+/// Returns the index of the fastest reply, if any.
+fn race_winner(durations: &[Option<Duration>]) -> Option<usize> {
+    durations
+        .iter()
+        .enumerate()
+        .filter_map(|(i, duration)| duration.map(|duration| (i, duration)))
+        .min_by_key(|(_, duration)| *duration)
+        .map(|(i, _)| i)
+}
+
+/// Races the servers of the configurations by pinging all of them at once every round, and passes
+/// the events of their queries and the winner of each round to the consumer. The rounds are paced
+/// by the interval of the first configuration, and counted against its count and replies to wait
+/// for with the rounds won. Returns the statistics of each configuration.
+pub fn race<F: FnMut(RaceEvent)>(
+    mut configs: Vec<PingConfig>,
+    stop: Receiver<()>,
+    mut publish: F,
+) -> Vec<Stats> {
+    let (interval, limits) = match configs.first() {
+        Some(config) => (config.interval, config.limits()),
+        None => return Vec::new(),
+    };
+    let queries = configs.iter().map(PingConfig::queries).collect::<Vec<_>>();
+    let mut recorders = configs
+        .iter()
+        .map(|_| Recorder::default())
+        .collect::<Vec<_>>();
+    let mut rng = rand::thread_rng();
+    let mut deadline = Instant::now();
+    let mut won = 0;
+    'race: for seq in 0.. {
+        let round = queries
+            .iter()
+            .map(|queries| queries.query(&mut rng, seq))
+            .collect::<Vec<_>>();
+        let results = thread::scope(|scope| {
+            let handles = configs
+                .iter_mut()
+                .zip(round.iter())
+                .map(|(config, query)| {
+                    let pinger = &mut config.pinger;
+                    scope.spawn(move || pinger.ping_host(&query.host))
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        let mut durations = Vec::new();
+        let mut aborted = false;
+        for (i, (query, result)) in round.into_iter().zip(results).enumerate() {
+            for source in configs[i].pinger.take_foreign() {
+                publish(RaceEvent::Ping(i, foreign(source)));
+            }
+            let event = match result {
+                Ok(reply) => {
+                    // Only a reply which a resolver would accept wins the round
+                    match reply.rcode {
+                        ResponseCode::NoError | ResponseCode::NameError => {
+                            durations.push(Some(reply.duration))
+                        }
+                        _ => durations.push(None),
+                    }
+                    PingEvent::Reply(query, reply)
+                }
+                Err(e) => {
+                    durations.push(None);
+                    failure(configs[i].pinger.addr, query, e)
+                }
+            };
+            aborted |= matches!(event, PingEvent::Abort(_, _));
+            recorders[i].record(&event);
+            publish(RaceEvent::Ping(i, event));
+        }
+        if aborted {
+            break 'race;
+        }
+        let winner = race_winner(&durations).map(|i| (i, durations[i].unwrap()));
+        if winner.is_some() {
+            won += 1;
+        }
+        publish(RaceEvent::Round(seq, winner));
+
+        // Reach max round count or wanted won round count
+        if limits.is_finished(seq + 1, won, won) {
+            break;
+        }
+
+        // Sleep until the next round unless stopped
+        let now = Instant::now();
+        deadline += interval;
+        if deadline < now {
+            deadline = now;
+        }
+        if sleep_until(&stop, deadline) {
+            break;
+        }
+    }
+
+    recorders
+        .into_iter()
+        .enumerate()
+        .map(|(i, recorder)| {
+            let stats = recorder.finish();
+            publish(RaceEvent::Ping(i, PingEvent::Summary(stats.clone())));
+            stats
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{response, MockRW, Step};
+    use super::*;
+    use std::collections::HashSet;
+    use std::net::Ipv4Addr;
+    use std::sync::mpsc;
+
+    const HOST: &str = "www.google.com";
+
+    fn server() -> SocketAddr {
+        "127.0.0.1:53".parse().unwrap()
+    }
+
+    #[test]
+    fn ping_reply() {
+        let answers = [
+            (Ipv4Addr::new(127, 0, 0, 1), 300),
+            (Ipv4Addr::new(127, 0, 0, 2), 200),
+        ];
+        let data = response(1, HOST, 0, &answers);
+        let rw = MockRW::new(vec![Step::new(
+            Duration::from_millis(10),
+            server(),
+            data.clone(),
+        )]);
+
+        let reply = ping(&rw, server(), 1, false, HOST, false).unwrap();
+        assert_eq!(reply.size, data.len());
+        assert!(reply.duration >= Duration::from_millis(10));
+        assert_eq!(reply.rcode, ResponseCode::NoError);
+        assert_eq!(reply.ttl, Some(200));
+        assert_eq!(reply.answers, 2);
+        assert_eq!(reply.nameservers, 0);
+        assert_eq!(reply.additional, 0);
+
+        let sent = rw.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1, server());
+        assert_eq!(Packet::parse(&sent[0].0).unwrap().header.id, 1);
+    }
+
+    #[test]
+    fn ping_buffer_reused() {
+        let rw = MockRW::new(vec![
+            Step::new(
+                Duration::from_millis(0),
+                server(),
+                response(1, HOST, 0, &[]),
+            ),
+            Step::new(
+                Duration::from_millis(0),
+                server(),
+                response(2, HOST, 0, &[]),
+            ),
+        ]);
+
+        ping(&rw, server(), 1, false, HOST, false).unwrap();
+        let buffer = BUFFER.with(|buffer| buffer.borrow().as_ptr());
+        ping(&rw, server(), 2, false, HOST, false).unwrap();
+        assert_eq!(BUFFER.with(|buffer| buffer.borrow().as_ptr()), buffer);
+    }
+
+    #[test]
+    fn ping_id_mismatch() {
+        let rw = MockRW::new(vec![
+            Step::new(
+                Duration::from_millis(0),
+                server(),
+                response(2, HOST, 0, &[]),
+            ),
+            Step::new(
+                Duration::from_millis(0),
+                server(),
+                response(1, HOST, 3, &[]),
+            ),
+        ]);
+
+        let reply = ping(&rw, server(), 1, false, HOST, false).unwrap();
+        assert_eq!(reply.rcode, ResponseCode::NameError);
+        assert_eq!(reply.ttl, None);
+    }
+
+    #[test]
+    fn ping_question_mismatch() {
+        let rw = MockRW::new(vec![
+            Step::new(
+                Duration::from_millis(0),
+                server(),
+                response(1, "a.com", 3, &[]),
+            ),
+            Step::new(
+                Duration::from_millis(0),
+                server(),
+                response(1, HOST, 0, &[]),
+            ),
+        ]);
+
+        let reply = ping(&rw, server(), 1, false, HOST, false).unwrap();
+        assert_eq!(reply.rcode, ResponseCode::NoError);
+    }
+
+    #[test]
+    fn pinger_foreign() {
+        let foreign = "127.0.0.2:53".parse().unwrap();
+        let rw = MockRW::new(vec![
+            Step::new(Duration::from_millis(0), foreign, response(1, HOST, 3, &[])),
+            Step::new(
+                Duration::from_millis(0),
+                server(),
+                response(1, HOST, 0, &[]),
+            ),
+        ]);
+        let mut pinger = Pinger::new(server()).host(HOST).rw(Box::new(rw));
+
+        let reply = pinger.ping().unwrap();
+        assert_eq!(reply.rcode, ResponseCode::NoError);
+        assert_eq!(pinger.take_foreign(), vec![foreign]);
+        assert!(pinger.take_foreign().is_empty());
+    }
+
+    #[test]
+    fn ping_mismatch_deadline() {
+        let rw = MockRW::new(Vec::new());
+        for _ in 0..100 {
+            rw.push(Step::new(
+                Duration::from_millis(5),
+                server(),
+                response(2, HOST, 0, &[]),
+            ));
+        }
+        let mut pinger = Pinger::new(server())
+            .host(HOST)
+            .query_type(QueryType::A)
+            .timeout(Some(Duration::from_millis(50)))
+            .rw(Box::new(rw));
+
+        let instant = Instant::now();
+        let e = pinger.ping().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+        assert!(instant.elapsed() < Duration::from_millis(200));
+    }
+
+    #[test]
+    fn ping_source_mismatch() {
+        let other = "127.0.0.2:53".parse().unwrap();
+        let rw = MockRW::new(vec![
+            Step::new(Duration::from_millis(0), other, response(1, HOST, 3, &[])),
+            Step::new(
+                Duration::from_millis(0),
+                server(),
+                response(1, HOST, 0, &[]),
+            ),
+        ]);
+
+        let reply = ping(&rw, server(), 1, false, HOST, false).unwrap();
+        assert_eq!(reply.source, server());
+        assert_eq!(reply.rcode, ResponseCode::NoError);
+    }
+
+    #[test]
+    fn ping_any_source() {
+        let other = "127.0.0.2:53".parse().unwrap();
+        let rw = MockRW::new(vec![Step::new(
+            Duration::from_millis(0),
+            other,
+            response(1, HOST, 0, &[]),
+        )]);
+
+        let reply = ping(&rw, server(), 1, false, HOST, true).unwrap();
+        assert_eq!(reply.source, other);
+    }
+
+    #[test]
+    fn ping_truncated() {
+        let data = response(1, HOST, 0, &[]);
+        let rw = MockRW::new(vec![
+            Step::new(Duration::from_millis(0), server(), data[..8].to_vec()),
+            Step::new(Duration::from_millis(0), server(), data.clone()),
+        ]);
+
+        let reply = ping(&rw, server(), 1, false, HOST, false).unwrap();
+        assert_eq!(reply.size, data.len());
+    }
+
+    #[test]
+    fn ping_addresses() {
+        let answers = [
+            (Ipv4Addr::new(93, 184, 216, 34), 60),
+            (Ipv4Addr::new(93, 184, 216, 35), 60),
+        ];
+        let rw = MockRW::new(vec![Step::new(
+            Duration::from_millis(0),
+            server(),
+            response(1, HOST, 0, &answers),
+        )]);
+
+        let reply = ping(&rw, server(), 1, false, HOST, false).unwrap();
+        assert_eq!(
+            reply.addresses,
+            answers
+                .iter()
+                .map(|(ip, _)| IpAddr::V4(*ip))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn ping_truncated_flag() {
+        let mut data = response(1, HOST, 0, &[]);
+        data[2] |= 0x02;
+        let rw = MockRW::new(vec![Step::new(Duration::from_millis(0), server(), data)]);
+
+        let reply = ping(&rw, server(), 1, false, HOST, false).unwrap();
+        assert!(reply.truncated);
+    }
+
+    #[test]
+    fn pinger_tcp_large() {
+        let answers = (0..300)
+            .map(|i| (Ipv4Addr::from(i as u32), 60))
+            .collect::<Vec<_>>();
+        let data = response(1, HOST, 0, &answers);
+        assert!(data.len() > DEFAULT_BUFFER_SIZE);
+        let rw = MockRW::new(vec![Step::new(
+            Duration::from_millis(0),
+            server(),
+            data.clone(),
+        )]);
+        let mut pinger = Pinger::new(server()).host(HOST).tcp(true).rw(Box::new(rw));
+
+        let reply = pinger.ping().unwrap();
+        assert_eq!(reply.size, data.len());
+        assert_eq!(reply.answers, 300);
+        assert!(!reply.truncated);
+    }
+
+    #[test]
+    fn pinger_tcp_connect() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut buffer = [0; DEFAULT_BUFFER_SIZE];
+            let mut frame = Frame::default();
+            while frame.read(&stream, &mut buffer).is_ok() {
+                let id = u16::from_be_bytes([buffer[0], buffer[1]]);
+                write_message(&stream, &response(id, HOST, 0, &[])).unwrap();
+            }
+        });
+        let mut pinger = Pinger::new(addr)
+            .host(HOST)
+            .tcp(true)
+            .timeout(Some(Duration::from_secs(1)));
+        pinger.bind().unwrap();
+
+        let first = pinger.ping().unwrap();
+        assert!(first.connect.is_some());
+        let second = pinger.ping().unwrap();
+        assert_eq!(second.connect, None);
+    }
+
+    #[test]
+    fn pinger_tcp_reconnect() {
+        // The server closes each connection after a reply
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let mut buffer = [0; DEFAULT_BUFFER_SIZE];
+                Frame::default().read(&stream, &mut buffer).unwrap();
+                let id = u16::from_be_bytes([buffer[0], buffer[1]]);
+                write_message(&stream, &response(id, HOST, 0, &[])).unwrap();
+            }
+        });
+        let mut pinger = Pinger::new(addr)
+            .host(HOST)
+            .tcp(true)
+            .timeout(Some(Duration::from_secs(1)));
+
+        assert!(pinger.ping().unwrap().connect.is_some());
+        assert!(is_connection_closed(&pinger.ping().unwrap_err()));
+        assert!(pinger.ping().unwrap().connect.is_some());
+    }
+
+    #[test]
+    fn stream_http_connect() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut byte = [0u8; 1];
+            while !request.ends_with(b"\r\n\r\n") {
+                stream.read_exact(&mut byte).unwrap();
+                request.push(byte[0]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .unwrap();
+            let mut buffer = [0; DEFAULT_BUFFER_SIZE];
+            Frame::default().read(&stream, &mut buffer).unwrap();
+            let id = u16::from_be_bytes([buffer[0], buffer[1]]);
+            let data = response(id, HOST, 0, &[]);
+
+            // The response is split across the read timeout of the client
+            let mut message = (data.len() as u16).to_be_bytes().to_vec();
+            message.extend_from_slice(&data);
+            stream.write_all(&message[..5]).unwrap();
+            thread::sleep(Duration::from_millis(150));
+            stream.write_all(&message[5..]).unwrap();
+
+            String::from_utf8(request).unwrap()
+        });
+        let auth = Some((String::from("user"), String::from("pass")));
+        let stream = Stream::connect_with_http(proxy, server(), auth).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+
+        stream
+            .send_to(&response(1, HOST, 0, &[]), server())
+            .unwrap();
+        let mut buffer = [0; DEFAULT_BUFFER_SIZE];
+        let mut timeouts = 0;
+        let size = loop {
+            match stream.recv_from(&mut buffer) {
+                Ok((size, _)) => break size,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    timeouts += 1
+                }
+                Err(e) => panic!("{}", e),
+            }
+        };
+        assert!(timeouts > 0);
+        assert_eq!(Packet::parse(&buffer[..size]).unwrap().header.id, 1);
+        let request = handle.join().unwrap();
+        assert!(request.starts_with("CONNECT 127.0.0.1:53 HTTP/1.1\r\n"));
+        assert!(request.contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n"));
+    }
+
+    #[test]
+    fn stream_http_connect_refused() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = [0u8; 512];
+            let _ = stream.read(&mut buffer).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                .unwrap();
+        });
+
+        let e = Stream::connect_with_http(proxy, server(), None).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ConnectionRefused);
+        assert!(e.to_string().contains("407"));
+    }
+
+    #[test]
+    fn pinger_tcp_first_byte() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = [0; DEFAULT_BUFFER_SIZE];
+            Frame::default().read(&stream, &mut buffer).unwrap();
+            let id = u16::from_be_bytes([buffer[0], buffer[1]]);
+            let data = response(id, HOST, 0, &[]);
+
+            // The body of the response is delayed after its length
+            stream
+                .write_all(&(data.len() as u16).to_be_bytes())
+                .unwrap();
+            thread::sleep(Duration::from_millis(100));
+            stream.write_all(&data).unwrap();
+        });
+        let mut pinger = Pinger::new(addr)
+            .host(HOST)
+            .tcp(true)
+            .timeout(Some(Duration::from_secs(1)));
+
+        let reply = pinger.ping().unwrap();
+        let first_byte = reply.first_byte.unwrap();
+        assert!(reply.duration - first_byte >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn pinger_malformed() {
+        let data = response(1, HOST, 0, &[]);
+        let rw = MockRW::new(vec![Step::new(
+            Duration::from_millis(0),
+            server(),
+            data[..8].to_vec(),
+        )]);
+        let mut pinger = Pinger::new(server())
+            .host(HOST)
+            .report_malformed(true)
+            .rw(Box::new(rw));
+
+        let e = pinger.ping().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        let e = e.get_ref().unwrap().downcast_ref::<ResponseError>();
+        assert_eq!(e, Some(&ResponseError::Malformed(server())));
+    }
+
+    #[test]
+    fn pinger_duplicate() {
+        let step = |id| {
+            Step::new(
+                Duration::from_millis(0),
+                server(),
+                response(id, HOST, 0, &[]),
+            )
+        };
+        let rw = MockRW::new(vec![step(1), step(1), step(2), step(2)]);
+        let mut pinger = Pinger::new(server())
+            .host(HOST)
+            .query_type(QueryType::A)
+            .rw(Box::new(rw));
+
+        let reply = pinger.ping().unwrap();
+        assert!(!reply.duplicate);
+        assert!(pinger.take_duplicates().is_empty());
+
+        let reply = pinger.ping().unwrap();
+        assert_eq!(reply.id, 2);
+        let duplicates = pinger.take_duplicates();
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates[0].id, 1);
+        assert!(duplicates[0].duplicate);
+
+        let duplicates = pinger.recv_duplicates(Duration::from_millis(10)).unwrap();
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates[0].id, 2);
+    }
+
+    #[test]
+    fn pinger_recv_duplicates() {
+        let step = |delay| {
+            Step::new(
+                Duration::from_millis(delay),
+                server(),
+                response(1, HOST, 0, &[]),
+            )
+        };
+        let rw = MockRW::new(vec![step(0), step(80), step(80)]);
+        let mut pinger = Pinger::new(server())
+            .host(HOST)
+            .query_type(QueryType::A)
+            .rw(Box::new(rw));
+
+        pinger.ping().unwrap();
+
+        // The second duplicate arrives after more than half of the window but still within it
+        let duplicates = pinger.recv_duplicates(Duration::from_millis(200)).unwrap();
+        assert_eq!(duplicates.len(), 2);
+        assert!(duplicates.iter().all(|reply| reply.duplicate));
+    }
+
+    #[test]
+    fn pinger_burst() {
+        let step = |delay, id| {
+            Step::new(
+                Duration::from_millis(delay),
+                server(),
+                response(id, HOST, 0, &[]),
+            )
+        };
+        let rw = MockRW::new(vec![step(0, 2), step(0, 2), step(5, 1)]);
+        let mut pinger = Pinger::new(server())
+            .host(HOST)
+            .query_type(QueryType::A)
+            .timeout(Some(Duration::from_millis(100)))
+            .rw(Box::new(rw));
+
+        let replies = pinger.ping_burst(3).unwrap();
+        assert_eq!(pinger.id(), 3);
+        assert_eq!(
+            replies.iter().map(|reply| reply.id).collect::<Vec<_>>(),
+            vec![2, 1]
+        );
+        assert!(replies[0].duration < replies[1].duration);
+        let duplicates = pinger.take_duplicates();
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates[0].id, 2);
+
+        let e = pinger.ping_burst(2).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+
+        let mut pinger = Pinger::new(server())
+            .host(HOST)
+            .rw(Box::new(MockRW::new(Vec::new())));
+        let e = pinger.ping_burst(2).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn id_next() {
+        assert_eq!(next_id(1, false, |_| false), 2);
+        assert_eq!(next_id(u16::MAX, false, |id| id == 0), 1);
+        assert_eq!(next_id(1, false, |_| true), 1);
+        assert_ne!(next_id(1, true, |id| id == 7), 7);
+    }
+
+    #[test]
+    fn pinger_id_wrap() {
+        let step = |id| {
+            Step::new(
+                Duration::from_millis(0),
+                server(),
+                response(id, HOST, 0, &[]),
+            )
+        };
+        let rw = MockRW::new(vec![Step::error(
+            Duration::from_millis(50),
+            ErrorKind::TimedOut,
+        )]);
+        for id in (2..=u16::MAX).chain(0..1) {
+            rw.push(step(id));
+        }
+        // A late reply of the first query arrives after the IDs wrap around
+        rw.push(step(1));
+        rw.push(step(2));
+        let mut pinger = Pinger::new(server())
+            .host(HOST)
+            .query_type(QueryType::A)
+            .timeout(Some(Duration::from_millis(10)))
+            .rw(Box::new(rw));
+
+        assert!(pinger.ping().is_err());
+        for _ in 0..u16::MAX {
+            pinger.ping().unwrap();
+        }
+        assert_eq!(pinger.id(), 0);
+        let reply = pinger.ping().unwrap();
+        assert_eq!(pinger.id(), 2);
+        assert_eq!(reply.id, 2);
+    }
+
+    #[test]
+    fn pinger_split() {
+        let step = |id| {
+            Step::new(
+                Duration::from_millis(0),
+                server(),
+                response(id, HOST, 0, &[]),
+            )
+        };
+        let rw = MockRW::new(vec![step(2), step(1), step(1)]);
+        rw.set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        let pinger = Pinger::new(server())
+            .host(HOST)
+            .query_type(QueryType::A)
+            .rw(Box::new(rw));
+        let (mut sender, mut receiver) = pinger.split().unwrap();
+
+        assert_eq!(sender.send().unwrap(), 1);
+        assert_eq!(sender.send().unwrap(), 2);
+        assert_eq!(sender.send().unwrap(), 3);
+        assert_eq!(receiver.pending(), 3);
+        assert_eq!(receiver.recv().unwrap().id, 2);
+        let reply = receiver.recv().unwrap();
+        assert_eq!(reply.id, 1);
+        assert!(reply.out_of_order);
+        assert!(receiver.recv().unwrap().duplicate);
+        let e = receiver.recv().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+        assert_eq!(receiver.expire(Duration::from_millis(0)).len(), 1);
+        assert_eq!(receiver.pending(), 0);
+    }
+
+    /// Represents a transport recording whether the query is waiting for its reply when it is
+    /// sent, which refuses the sends once the outstanding queries are given.
+    #[derive(Default)]
+    struct Probe {
+        outstanding: Arc<Mutex<Option<Outstanding>>>,
+        waiting: Arc<Mutex<Vec<bool>>>,
+    }
+
+    impl RW for Probe {
+        fn send_to(&self, buf: &[u8], _: SocketAddr) -> Result<usize> {
+            let id = u16::from_be_bytes([buf[0], buf[1]]);
+            match self.outstanding.lock().unwrap().as_ref() {
+                Some(outstanding) => {
+                    let waiting = outstanding.lock().unwrap().contains_key(&id);
+                    self.waiting.lock().unwrap().push(waiting);
+                    Ok(buf.len())
+                }
+                None => Err(Error::from(ErrorKind::ConnectionRefused)),
+            }
+        }
+
+        fn recv_from(&self, _: &mut [u8]) -> Result<(usize, SocketAddr)> {
+            Err(Error::from(ErrorKind::TimedOut))
+        }
+
+        fn set_read_timeout(&self, _: Option<Duration>) -> Result<()> {
+            Ok(())
+        }
+
+        fn set_write_timeout(&self, _: Option<Duration>) -> Result<()> {
+            Ok(())
+        }
+
+        fn read_timeout(&self) -> Result<Option<Duration>> {
+            Ok(None)
+        }
+
+        fn write_timeout(&self) -> Result<Option<Duration>> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn sender_outstanding() {
+        let probe = Probe::default();
+        let outstanding = Arc::clone(&probe.outstanding);
+        let waiting = Arc::clone(&probe.waiting);
+        let pinger = Pinger::new(server()).host(HOST).rw(Box::new(probe));
+        let (mut sender, receiver) = pinger.split().unwrap();
+
+        // A query failed to send does not wait for its reply
+        assert!(sender.send().is_err());
+        assert_eq!(receiver.pending(), 0);
+
+        // A query waits for its reply before it is sent
+        *outstanding.lock().unwrap() = Some(Arc::clone(&sender.outstanding));
+        sender.send().unwrap();
+        assert_eq!(*waiting.lock().unwrap(), vec![true]);
+        assert_eq!(receiver.pending(), 1);
+    }
+
+    #[test]
+    fn ping_would_block() {
+        let rw = MockRW::new(vec![
+            Step::error(Duration::from_millis(0), ErrorKind::WouldBlock),
+            Step::new(
+                Duration::from_millis(0),
+                server(),
+                response(1, HOST, 0, &[]),
+            ),
+        ]);
+        rw.set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+
+        let reply = ping(&rw, server(), 1, false, HOST, false).unwrap();
+        assert_eq!(reply.id, 1);
+
+        // A timeout reported as WouldBlock
+        let rw = MockRW::new(vec![Step::error(
+            Duration::from_millis(10),
+            ErrorKind::WouldBlock,
+        )]);
+        rw.set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+
+        let e = ping(&rw, server(), 1, false, HOST, false).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn ping_timeout() {
+        let rw = MockRW::new(vec![Step::new(
+            Duration::from_millis(100),
+            server(),
+            response(1, HOST, 0, &[]),
+        )]);
+        rw.set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+
+        let e = ping(&rw, server(), 1, false, HOST, false).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn subdomain_random() {
+        let mut rng = rand::thread_rng();
+        let a = random_subdomain(&mut rng, HOST);
+        let b = random_subdomain(&mut rng, HOST);
+
+        assert_ne!(a, b);
+        let (label, host) = a.split_at(RANDOM_LABEL_LEN);
+        assert_eq!(host, format!(".{}", HOST));
+        assert!(label
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit()));
+    }
+
+    #[test]
+    fn case_random() {
+        let mut rng = rand::thread_rng();
+        let host = "www.example-1.com";
+        let randomized = (0..16)
+            .map(|_| randomize_case(&mut rng, host))
+            .collect::<HashSet<_>>();
+
+        assert!(randomized.len() > 1);
+        assert!(randomized.iter().all(|h| h.eq_ignore_ascii_case(host)));
+        assert!(randomized.iter().all(|h| h
+            .chars()
+            .filter(|c| !c.is_ascii_alphabetic())
+            .eq(".-1.".chars())));
+    }
+
+    #[test]
+    fn case_echoed() {
+        assert!(is_case_echoed("wWw.GooGle.com", "wWw.GooGle.com"));
+        assert!(is_case_echoed("wWw.GooGle.com.", "wWw.GooGle.com"));
+        assert!(!is_case_echoed("wWw.GooGle.com", "www.google.com"));
+    }
+
+    #[test]
+    fn ping_question_case() {
+        let host = "wWw.GooGle.com";
+        let rw = MockRW::new(vec![Step::new(
+            Duration::from_millis(0),
+            server(),
+            response(1, &host.to_lowercase(), 0, &[]),
+        )]);
+
+        let reply = ping(&rw, server(), 1, false, host, false).unwrap();
+        assert_eq!(reply.question.as_deref(), Some("www.google.com"));
+        assert!(!is_case_echoed(host, reply.question.as_deref().unwrap()));
+    }
+
+    #[test]
+    fn hex_dump_lines() {
+        let data = (0..20).collect::<Vec<u8>>();
+        assert_eq!(
+            hex_dump(&data),
+            "0000  00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f\n0010  10 11 12 13"
+        );
+        assert_eq!(hex_dump(&[]), "");
+    }
+
+    #[test]
+    fn query_oversized() {
+        let rw = MockRW::new(vec![]);
+        let options = Options {
+            recursion_desired: true,
+            query_type: QueryType::A,
+            opcode: Opcode::StandardQuery,
+            size: Some(600),
+            nsid: false,
+            authenticated_data: false,
+            checking_disabled: false,
+            dnssec_ok: false,
+            accept_any_source: false,
+            report_malformed: false,
+            payload: None,
+        };
+
+        send_query(&rw, server(), 1, HOST, &options).unwrap();
+        let sent = rw.sent();
+        assert!(sent[0].0.len() > 512);
+        assert!(!Packet::parse(&sent[0].0).unwrap().header.truncated);
+    }
+
+    #[test]
+    fn query_invalid_host() {
+        let pinger = Pinger::new(server());
+        let reason = |host: &str| pinger.build_query(host).unwrap_err().to_string();
+        assert!(reason("a..com").ends_with("empty label"));
+        assert!(reason(&format!("{}.com", "a".repeat(64))).ends_with("label too long"));
+        assert!(reason(&vec!["a".repeat(63); 4].join(".")).ends_with("name too long"));
+
+        let query = pinger
+            .build_query(&format!("{}.com.", "a".repeat(63)))
+            .unwrap();
+        let packet = Packet::parse(&query).unwrap();
+        assert_eq!(packet.questions[0].qname.to_string().len(), 67);
+        let query = pinger.query_type(QueryType::NS).build_query(".").unwrap();
+        assert_eq!(&query[12..], &[0, 0, 2, 0, 1]);
+    }
+
+    #[test]
+    fn query_padding() {
+        let pinger = Pinger::new(server()).host(HOST);
+        let size = pinger.build_query(HOST).unwrap().len();
+
+        let buffer = pinger.size(Some(size + 100)).build_query(HOST).unwrap();
+        assert_eq!(buffer.len(), size + 100);
+        let packet = Packet::parse(&buffer).unwrap();
+        assert_eq!(packet.header.id, 1);
+        assert!(packet.opt.is_some());
+
+        let pinger = Pinger::new(server()).host(HOST).size(Some(0));
+        assert_eq!(
+            pinger.build_query(HOST).unwrap().len(),
+            size + PADDING_OVERHEAD
+        );
+
+        let pinger = Pinger::new(server()).host(HOST).size(Some(MAX_QUERY_SIZE));
+        assert_eq!(pinger.build_query(HOST).unwrap().len(), MAX_QUERY_SIZE);
+        let pinger = Pinger::new(server())
+            .host(HOST)
+            .size(Some(MAX_QUERY_SIZE + 1));
+        assert_eq!(
+            pinger.build_query(HOST).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn pinger_nsid() {
+        let query = Pinger::new(server())
+            .nsid(true)
+            .size(Some(128))
+            .build_query(HOST)
+            .unwrap();
+        assert_eq!(query.len(), 128);
+        let packet = Packet::parse(&query).unwrap();
+        assert_eq!(edns_option(&packet, NSID_CODE), Some(&[][..]));
+
+        let mut data = response(1, HOST, 0, &[]);
+        data[11] = 1;
+        data.extend_from_slice(&[0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 13, 0, 3, 0, 9]);
+        data.extend_from_slice(b"gpdns-fra");
+        let rw = MockRW::new(vec![Step::new(Duration::from_millis(0), server(), data)]);
+        let mut pinger = Pinger::new(server())
+            .host(HOST)
+            .query_type(QueryType::A)
+            .nsid(true)
+            .rw(Box::new(rw));
+
+        let reply = pinger.ping().unwrap();
+        assert_eq!(reply.nsid, Some(b"gpdns-fra".to_vec()));
+    }
+
+    #[test]
+    fn query_recursion_desired() {
+        let rd = |pinger: Pinger| {
+            let query = pinger.build_query(HOST).unwrap();
+            Packet::parse(&query).unwrap().header.recursion_desired
+        };
+
+        assert!(rd(Pinger::new(server())));
+        assert!(!rd(Pinger::new(server()).iterate(true)));
+        assert!(!rd(Pinger::new(server()).recursion_desired(false)));
+    }
+
+    #[test]
+    fn query_dnssec() {
+        let query = Pinger::new(server())
+            .authenticated_data(true)
+            .checking_disabled(true)
+            .dnssec_ok(true)
+            .build_query(HOST)
+            .unwrap();
+        let packet = Packet::parse(&query).unwrap();
+        assert!(packet.header.authenticated_data);
+        assert!(packet.header.checking_disabled);
+        assert_eq!(packet.opt.unwrap().flags, 0x8000);
+
+        let mut data = response(1, HOST, 0, &[]);
+        data[3] |= 0x20;
+        let rw = MockRW::new(vec![Step::new(Duration::from_millis(0), server(), data)]);
+        let reply = ping(&rw, server(), 1, false, HOST, false).unwrap();
+        assert!(reply.authenticated_data);
+    }
+
+    #[test]
+    fn query_opcode() {
+        let rw = MockRW::new(vec![]);
+        let options = Options {
+            recursion_desired: true,
+            query_type: QueryType::A,
+            opcode: Opcode::ServerStatusRequest,
+            size: None,
+            nsid: false,
+            authenticated_data: false,
+            checking_disabled: false,
+            dnssec_ok: false,
+            accept_any_source: false,
+            report_malformed: false,
+            payload: None,
+        };
+
+        send_query(&rw, server(), 1, HOST, &options).unwrap();
+        let sent = rw.sent();
+        let packet = Packet::parse(&sent[0].0).unwrap();
+        assert_eq!(packet.header.opcode, Opcode::ServerStatusRequest);
+    }
+
+    #[test]
+    fn pinger_payload() {
+        // The response to a payload of any question is matched by the ID in the payload
+        let payload = response(0xabcd, "example.com", 0, &[]);
+        let rw = MockRW::new(vec![Step::new(
+            Duration::from_millis(0),
+            server(),
+            response(0xabcd, "example.com", 0, &[]),
+        )]);
+        let mut pinger = Pinger::new(server())
+            .host(HOST)
+            .payload(Some(payload.clone()))
+            .timeout(Some(Duration::from_millis(100)))
+            .rw(Box::new(rw));
+
+        assert_eq!(pinger.ping().unwrap().id, 0xabcd);
+        assert_eq!(pinger.build_query(HOST).unwrap(), payload);
+
+        let mut pinger = Pinger::new(server())
+            .payload(Some(vec![0]))
+            .rw(Box::new(MockRW::new(vec![])));
+        assert_eq!(pinger.bind().unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn reply_without_question() {
+        // A NOTIMP response without the question still matches by the ID
+        let mut data = response(1, HOST, 4, &[]);
+        data.truncate(12);
+        data[5] = 0;
+        let rw = MockRW::new(vec![Step::new(Duration::from_millis(0), server(), data)]);
+        let mut pinger = Pinger::new(server())
+            .host(HOST)
+            .opcode(Opcode::ServerStatusRequest)
+            .timeout(Some(Duration::from_millis(100)))
+            .rw(Box::new(rw));
+
+        assert_eq!(pinger.ping().unwrap().rcode, ResponseCode::NotImplemented);
+
+        // A successful response without the question does not
+        let mut data = response(1, HOST, 0, &[]);
+        data.truncate(12);
+        data[5] = 0;
+        let rw = MockRW::new(vec![Step::new(Duration::from_millis(0), server(), data)]);
+        let mut pinger = Pinger::new(server())
+            .host(HOST)
+            .timeout(Some(Duration::from_millis(100)))
+            .rw(Box::new(rw));
+
+        assert_eq!(pinger.ping().unwrap_err().kind(), ErrorKind::TimedOut);
+
+        // Neither does a REFUSED response, which is common to ANY queries
+        let mut data = response(1, HOST, 5, &[]);
+        data.truncate(12);
+        data[5] = 0;
+        let rw = MockRW::new(vec![Step::new(Duration::from_millis(0), server(), data)]);
+        let mut pinger = Pinger::new(server())
+            .host(HOST)
+            .query_type(QueryType::All)
+            .timeout(Some(Duration::from_millis(100)))
+            .rw(Box::new(rw));
+
+        assert_eq!(pinger.ping().unwrap().rcode, ResponseCode::Refused);
+    }
+
+    #[test]
+    fn reply_minimal_any() {
+        // A minimal response to an ANY query with a HINFO record by RFC 8482
+        let mut data = response(1, HOST, 0, &[]);
+        data[7] = 1;
+        let len = data.len();
+        data[len - 4..len - 2].copy_from_slice(&255u16.to_be_bytes());
+        data.extend_from_slice(&0xc00cu16.to_be_bytes());
+        data.extend_from_slice(&13u16.to_be_bytes());
+        data.extend_from_slice(&1u16.to_be_bytes());
+        data.extend_from_slice(&3600u32.to_be_bytes());
+        data.extend_from_slice(&9u16.to_be_bytes());
+        data.extend_from_slice(b"\x07RFC8482\x00");
+        let rw = MockRW::new(vec![Step::new(Duration::from_millis(0), server(), data)]);
+        let mut pinger = Pinger::new(server())
+            .host(HOST)
+            .query_type(QueryType::All)
+            .report_malformed(true)
+            .timeout(Some(Duration::from_millis(100)))
+            .rw(Box::new(rw));
+
+        let reply = pinger.ping().unwrap();
+        assert_eq!(reply.answers, 1);
+        assert_eq!(reply.hinfo, Some((String::from("RFC8482"), String::new())));
+        assert_eq!(character_string(b"\x03ab"), None);
+    }
+
+    #[test]
+    fn reply_any_unknown() {
+        // A MINFO record, which is also unknown to the parser, in the shape of a HINFO record
+        let mut data = response(1, HOST, 0, &[]);
+        data[7] = 1;
+        let len = data.len();
+        data[len - 4..len - 2].copy_from_slice(&255u16.to_be_bytes());
+        data.extend_from_slice(&0xc00cu16.to_be_bytes());
+        data.extend_from_slice(&14u16.to_be_bytes());
+        data.extend_from_slice(&1u16.to_be_bytes());
+        data.extend_from_slice(&3600u32.to_be_bytes());
+        data.extend_from_slice(&4u16.to_be_bytes());
+        data.extend_from_slice(b"\x01a\x01b");
+        assert_eq!(answer_types(&data), Some(vec![14]));
+        assert_eq!(answer_types(&data[..data.len() - 6]), None);
+        let rw = MockRW::new(vec![Step::new(Duration::from_millis(0), server(), data)]);
+        let mut pinger = Pinger::new(server())
+            .host(HOST)
+            .query_type(QueryType::All)
+            .timeout(Some(Duration::from_millis(100)))
+            .rw(Box::new(rw));
+
+        let reply = pinger.ping().unwrap();
+        assert_eq!(reply.answers, 1);
+        assert_eq!(reply.hinfo, None);
+    }
+
+    #[test]
+    fn pinger_tos_unsupported() {
+        let rw = MockRW::new(vec![]);
+        let mut pinger = Pinger::new(server()).tos(Some(0xb8)).rw(Box::new(rw));
+
+        assert_eq!(pinger.bind().unwrap_err().kind(), ErrorKind::Unsupported);
+
+        let rw = MockRW::new(vec![]);
+        let mut pinger = Pinger::new(server())
+            .send_buffer_size(Some(65536))
+            .rw(Box::new(rw));
+
+        let e = pinger.bind().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Unsupported);
+        assert!(e.to_string().contains("send buffer size"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn pinger_connection_refused() {
+        let addr = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut pinger = Pinger::new(addr).timeout(Some(Duration::from_millis(100)));
+
+        let e = pinger.ping().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn socket_tos_on_wire() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        set_socket_option(&receiver, libc::IPPROTO_IP, libc::IP_RECVTOS, 1).unwrap();
+        let socket = Socket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        socket.set_tos(46 << 2).unwrap();
+        socket
+            .send_to(b"ping", receiver.local_addr().unwrap())
+            .unwrap();
+
+        // Receive the datagram with the ToS in the control message
+        let mut buffer = [0u8; 16];
+        let mut control = [0u8; 64];
+        let mut iov = libc::iovec {
+            iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buffer.len(),
+        };
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = control.len() as _;
+        let ret = unsafe { libc::recvmsg(receiver.as_raw_fd(), &mut msg, 0) };
+        assert_eq!(ret, 4);
+
+        let cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+        assert!(!cmsg.is_null());
+        let cmsg = unsafe { &*cmsg };
+        assert_eq!(
+            (cmsg.cmsg_level, cmsg.cmsg_type),
+            (libc::IPPROTO_IP, libc::IP_TOS)
+        );
+        let tos = unsafe { *libc::CMSG_DATA(cmsg) };
+        assert_eq!(tos, 46 << 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stream_options_family() {
+        // A stream to an IPv6 server through an IPv4 proxy is an IPv4 socket
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = Stream {
+            stream: TcpStream::connect(listener.local_addr().unwrap()).unwrap(),
+            addr: "[::1]:53".parse().unwrap(),
+            frame: Mutex::default(),
+            first_byte: Mutex::new(None),
+        };
+
+        stream.set_ttl(5).unwrap();
+        assert_eq!(stream.stream.ttl().unwrap(), 5);
+        stream.set_tos(46 << 2).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pinger_unix() {
+        let path = std::env::temp_dir().join(format!("dnsping-test-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let server = UnixDatagram::bind(&path).unwrap();
+        let handle = thread::spawn(move || {
+            let mut buffer = [0u8; 512];
+            let (_, client) = server.recv_from(&mut buffer).unwrap();
+            let id = u16::from_be_bytes([buffer[0], buffer[1]]);
+            let data = response(id, HOST, 0, &[]);
+            server
+                .send_to(&data, client.as_pathname().unwrap())
+                .unwrap();
+        });
+        let mut pinger = Pinger::new("127.0.0.1:53".parse().unwrap())
+            .host(HOST)
+            .unix(&path)
+            .timeout(Some(Duration::from_secs(1)));
+
+        let reply = pinger.ping().unwrap();
+        assert_eq!(reply.rcode, ResponseCode::NoError);
+        handle.join().unwrap();
+
+        // The temporary path is left if the socket is not dropped before exiting
+        let _ = fs::remove_file(&path);
+        let _server = UnixDatagram::bind(&path).unwrap();
+        let unix = Unix::connect(&path, "127.0.0.1:53".parse().unwrap()).unwrap();
+        let local = unix.local.clone();
+        std::mem::forget(unix);
+        assert!(local.exists());
+        Unix::remove_temporary_paths().unwrap();
+        assert!(!local.exists());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pinger_unix_stream() {
+        let path =
+            std::env::temp_dir().join(format!("dnsping-test-stream-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut buffer = [0u8; 512];
+            Frame::default().read(&stream, &mut buffer).unwrap();
+            let id = u16::from_be_bytes([buffer[0], buffer[1]]);
+            write_message(&stream, &response(id, HOST, 0, &[])).unwrap();
+        });
+        let mut pinger = Pinger::new(server())
+            .host(HOST)
+            .unix(&path)
+            .tcp(true)
+            .timeout(Some(Duration::from_secs(1)));
+
+        let reply = pinger.ping().unwrap();
+        assert_eq!(reply.rcode, ResponseCode::NoError);
+        handle.join().unwrap();
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn pinger_source_port_in_use() {
+        let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let mut pinger = Pinger::new(server()).source_port(port);
+
+        let e = pinger.bind().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::AddrInUse);
+        assert!(e.to_string().contains(&port.to_string()));
+    }
+
+    #[test]
+    fn pinger_random_source_port() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let mut pinger = Pinger::new(server.local_addr().unwrap())
+            .host(HOST)
+            .random_source_port(true)
+            .timeout(Some(Duration::from_millis(10)));
+
+        let mut ports = HashSet::new();
+        let mut buffer = [0; DEFAULT_BUFFER_SIZE];
+        for _ in 0..3 {
+            assert_eq!(pinger.ping().unwrap_err().kind(), ErrorKind::TimedOut);
+            let (_, addr) = server.recv_from(&mut buffer).unwrap();
+            ports.insert(addr.port());
+        }
+        assert_eq!(ports.len(), 3);
+
+        let pinger = Pinger::new(server.local_addr().unwrap()).random_source_port(true);
+        assert!(matches!(pinger.split(), Err(e) if e.kind() == ErrorKind::InvalidInput));
+    }
+
+    /// Accepts UDP associations on a fake SOCKS5 proxy without authentication, each of which is
+    /// relayed by the next socket.
+    fn socks5_proxy(relays: Vec<SocketAddr>) -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut streams = Vec::new();
+            for (stream, relay) in listener.incoming().zip(relays) {
+                let mut stream = stream.unwrap();
+                let mut buffer = [0; 10];
+                stream.read_exact(&mut buffer[..3]).unwrap();
+                stream.write_all(&[5, 0]).unwrap();
+                stream.read_exact(&mut buffer).unwrap();
+                let mut response = vec![5, 0, 0, 1];
+                match relay {
+                    SocketAddr::V4(relay) => response.extend_from_slice(&relay.ip().octets()),
+                    SocketAddr::V6(_) => unreachable!(),
+                }
+                response.extend_from_slice(&relay.port().to_be_bytes());
+                stream.write_all(&response).unwrap();
+                streams.push(stream);
+            }
+        });
+
+        proxy
+    }
+
+    #[test]
+    fn datagram_reassociate() {
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let next_relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        next_relay
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let proxy = socks5_proxy(vec![
+            relay.local_addr().unwrap(),
+            next_relay.local_addr().unwrap(),
+        ]);
+        let datagram = Datagram::bind(proxy, "127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let timeout = Some(Duration::from_millis(100));
+        datagram.set_read_timeout(timeout).unwrap();
+        datagram.send_to(b"a", server()).unwrap();
+
+        // The relay refuses datagrams once the association is gone
+        drop(relay);
+        let refused = (0..3).any(|_| {
+            datagram.send_to(b"b", server()).map_err(|e| e.kind())
+                == Err(ErrorKind::ConnectionRefused)
+        });
+        assert!(refused);
+
+        datagram.send_to(b"c", server()).unwrap();
+        let mut buffer = [0; 16];
+        let (size, _) = next_relay.recv_from(&mut buffer).unwrap();
+        assert!(buffer[..size].ends_with(b"c"));
+        assert_eq!(datagram.read_timeout().unwrap(), timeout);
+    }
+
+    #[test]
+    fn datagram_reassociate_unanswered() {
+        // The relay keeps silent as if the TCP stream is dropped silently
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let next_relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        next_relay
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let proxy = socks5_proxy(vec![
+            relay.local_addr().unwrap(),
+            next_relay.local_addr().unwrap(),
+        ]);
+        let datagram = Datagram::bind(proxy, "127.0.0.1:0".parse().unwrap(), None).unwrap();
+        datagram
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        let mut buffer = [0; 16];
+        for _ in 0..MAX_UNANSWERED {
+            datagram.send_to(b"a", server()).unwrap();
+            assert!(datagram.recv_from(&mut buffer).is_err());
+        }
+
+        datagram.send_to(b"b", server()).unwrap();
+        let (size, _) = next_relay.recv_from(&mut buffer).unwrap();
+        assert!(buffer[..size].ends_with(b"b"));
+    }
+
+    #[test]
+    fn datagram_reassociate_receiving() {
+        // The association is re-established while another thread is blocked receiving
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let next_relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let proxy = socks5_proxy(vec![
+            relay.local_addr().unwrap(),
+            next_relay.local_addr().unwrap(),
+        ]);
+        let datagram =
+            Arc::new(Datagram::bind(proxy, "127.0.0.1:0".parse().unwrap(), None).unwrap());
+        datagram
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let receiver = Arc::clone(&datagram);
+        let handle = thread::spawn(move || {
+            let mut buffer = [0; 16];
+            let _ = receiver.recv_from(&mut buffer);
+        });
+        thread::sleep(Duration::from_millis(50));
+
+        let instant = Instant::now();
+        let gone = datagram.datagram.read().unwrap().clone();
+        datagram.reassociate(gone).unwrap();
+        assert!(instant.elapsed() < Duration::from_millis(500));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn pinger_dual_stack() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = server.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let mut buffer = [0; DEFAULT_BUFFER_SIZE];
+            let (_, a) = server.recv_from(&mut buffer).unwrap();
+            let id = u16::from_be_bytes([buffer[0], buffer[1]]);
+            server.send_to(&response(id, HOST, 0, &[]), a).unwrap();
+        });
+        let mapped = SocketAddr::new(Ipv4Addr::LOCALHOST.to_ipv6_mapped().into(), port);
+
+        let mut pinger = Pinger::new(mapped).host(HOST).dual_stack(true).tcp(true);
+        assert_eq!(pinger.bind().unwrap_err().kind(), ErrorKind::InvalidInput);
+
+        let mut pinger = Pinger::new(mapped)
+            .host(HOST)
+            .query_type(QueryType::A)
+            .dual_stack(true)
+            .timeout(Some(Duration::from_secs(1)));
+        let reply = pinger.ping().unwrap();
+        assert_eq!(reply.source, mapped);
+    }
+
+    #[test]
+    fn pinger_interface() {
+        let interface = Some(String::from("dnsping0"));
+        let mut pinger = Pinger::new(server()).interface(interface.clone()).tcp(true);
+        assert_eq!(pinger.bind().unwrap_err().kind(), ErrorKind::InvalidInput);
+
+        let mut pinger = Pinger::new(server()).interface(interface);
+        let e = pinger.bind().unwrap_err();
+        #[cfg(target_os = "linux")]
+        assert!(e.to_string().contains("dnsping0"));
+        #[cfg(not(target_os = "linux"))]
+        assert_eq!(e.kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn socket_options() {
+        let socket = Socket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        socket.set_ttl(32).unwrap();
+        socket.set_tos(0xb8).unwrap();
+        socket.set_recv_buffer_size(65536).unwrap();
+        socket.set_send_buffer_size(65536).unwrap();
+
+        let rw = MockRW::new(vec![]);
+        assert_eq!(rw.set_ttl(32).unwrap_err().kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn pinger_ids() {
+        let rw = MockRW::new(vec![
+            Step::new(
+                Duration::from_millis(0),
+                server(),
+                response(1, HOST, 0, &[]),
+            ),
+            Step::new(
+                Duration::from_millis(0),
+                server(),
+                response(2, HOST, 3, &[]),
+            ),
+        ]);
+        let mut pinger = Pinger::new(server()).host(HOST).rw(Box::new(rw));
+
+        assert_eq!(pinger.ping().unwrap().rcode, ResponseCode::NoError);
+        assert_eq!(pinger.id(), 1);
+        assert_eq!(pinger.ping().unwrap().rcode, ResponseCode::NameError);
+        assert_eq!(pinger.id(), 2);
+    }
+
+    #[test]
+    fn pinger_iter() {
+        let rw = MockRW::new(vec![
+            Step::new(
+                Duration::from_millis(0),
+                server(),
+                response(1, HOST, 0, &[]),
+            ),
+            Step::new(
+                Duration::from_millis(100),
+                server(),
+                response(2, HOST, 0, &[]),
+            ),
+            Step::new(
+                Duration::from_millis(0),
+                server(),
+                response(3, HOST, 0, &[]),
+            ),
+        ]);
+        let mut pinger = Pinger::new(server())
+            .host(HOST)
+            .timeout(Some(Duration::from_millis(5)))
+            .rw(Box::new(rw));
+
+        let instant = Instant::now();
+        let results = pinger
+            .iter(Duration::from_millis(10), Some(3))
+            .collect::<Vec<_>>();
+        assert!(instant.elapsed() >= Duration::from_millis(20));
+        assert_eq!(results.len(), 3);
+        assert!(results[0].is_ok());
+        assert_eq!(results[1].as_ref().unwrap_err().kind(), ErrorKind::TimedOut);
+        assert!(results[2].is_ok());
+    }
+
+    #[test]
+    fn run_stats() {
+        let rw = MockRW::new(vec![
+            Step::new(
+                Duration::from_millis(10),
+                server(),
+                response(1, HOST, 0, &[]),
+            ),
+            Step::new(
+                Duration::from_millis(100),
+                server(),
+                response(2, HOST, 0, &[]),
+            ),
+            Step::new(
+                Duration::from_millis(20),
+                server(),
+                response(3, HOST, 0, &[]),
+            ),
+            Step::new(
+                Duration::from_millis(0),
+                server(),
+                response(4, HOST, 0, &[]),
+            ),
+        ]);
+        let pinger = Pinger::new(server())
+            .host(HOST)
+            .timeout(Some(Duration::from_millis(50)))
+            .rw(Box::new(rw));
+        let config = PingConfig {
+            interval: Duration::from_millis(0),
+            count: Some(3),
+            ..PingConfig::new(pinger)
+        };
+        let (_tx, rx) = mpsc::channel();
+
+        let stats = run(config, rx);
+        assert_eq!(stats.sent, 3);
+        assert_eq!(stats.received, 2);
+        assert!(stats.min.unwrap() >= Duration::from_millis(10));
+        assert!(stats.max.unwrap() >= Duration::from_millis(20));
+        assert!(stats.avg.unwrap() >= Duration::from_millis(15));
+        assert!((stats.loss - 100.0 / 3.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn run_events_published() {
+        let rw = MockRW::new(vec![
+            Step::new(
+                Duration::from_millis(0),
+                server(),
+                response(1, HOST, 0, &[]),
+            ),
+            Step::new(
+                Duration::from_millis(100),
+                server(),
+                response(2, HOST, 0, &[]),
+            ),
+        ]);
+        let pinger = Pinger::new(server())
+            .host(HOST)
+            .timeout(Some(Duration::from_millis(50)))
+            .rw(Box::new(rw));
+        let config = PingConfig {
+            interval: Duration::from_millis(0),
+            count: Some(2),
+            ..PingConfig::new(pinger)
+        };
+        let (_tx, rx) = mpsc::channel();
+        let (events_tx, events_rx) = mpsc::channel();
+
+        let stats = run_events(config, rx, |event| events_tx.send(event).unwrap());
+        drop(events_tx);
+        let events = events_rx.iter().collect::<Vec<_>>();
+        assert_eq!(events.len(), 3);
+        assert!(
+            matches!(&events[0], PingEvent::Reply(query, reply) if query.seq == 0 && reply.id == 1)
+        );
+        assert!(matches!(&events[1], PingEvent::Timeout(query) if query.seq == 1));
+        assert!(matches!(&events[2], PingEvent::Summary(summary) if *summary == stats));
+    }
+
+    #[test]
+    fn run_count_received() {
+        // Every other query is lost
+        let rw = MockRW::new(
+            (1..=6)
+                .map(|id| match id % 2 {
+                    0 => Step::new(
+                        Duration::from_millis(0),
+                        server(),
+                        response(id, HOST, 0, &[]),
+                    ),
+                    _ => Step::error(Duration::from_millis(0), ErrorKind::TimedOut),
+                })
+                .collect(),
+        );
+        let pinger = Pinger::new(server()).host(HOST).rw(Box::new(rw));
+        let config = PingConfig {
+            interval: Duration::from_millis(0),
+            count: Some(3),
+            count_received: true,
+            ..PingConfig::new(pinger)
+        };
+        let (_tx, rx) = mpsc::channel();
+
+        let stats = run(config, rx);
+        assert_eq!((stats.sent, stats.received), (6, 3));
+    }
+
+    #[test]
+    fn run_wait_for() {
+        // Only the NOERROR replies are waited for
+        let rw = MockRW::new(
+            [2, 0, 3, 0, 0]
+                .iter()
+                .zip(1..)
+                .map(|(&rcode, id)| {
+                    Step::new(
+                        Duration::from_millis(0),
+                        server(),
+                        response(id, HOST, rcode, &[]),
+                    )
+                })
+                .collect(),
+        );
+        let pinger = Pinger::new(server()).host(HOST).rw(Box::new(rw));
+        let config = PingConfig {
+            interval: Duration::from_millis(0),
+            count: Some(5),
+            wait_for: Some(2),
+            ..PingConfig::new(pinger)
+        };
+        let (_tx, rx) = mpsc::channel();
+
+        let stats = run(config, rx);
+        assert_eq!((stats.sent, stats.received), (4, 4));
+    }
+
+    #[test]
+    fn run_rate_tcp() {
+        // The responses are split across the periodic wake-up of the receiver
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = [0; DEFAULT_BUFFER_SIZE];
+            let mut frame = Frame::default();
+            while frame.read(&stream, &mut buffer).is_ok() {
+                let id = u16::from_be_bytes([buffer[0], buffer[1]]);
+                let data = response(id, HOST, 0, &[]);
+                let mut message = (data.len() as u16).to_be_bytes().to_vec();
+                message.extend_from_slice(&data);
+                stream.write_all(&message[..5]).unwrap();
+                thread::sleep(Duration::from_millis(150));
+                stream.write_all(&message[5..]).unwrap();
+            }
+        });
+        let pinger = Pinger::new(addr)
+            .host(HOST)
+            .tcp(true)
+            .timeout(Some(Duration::from_secs(1)));
+        let config = PingConfig {
+            count: Some(3),
+            rate: Some(10.0),
+            ..PingConfig::new(pinger)
+        };
+        let (_tx, rx) = mpsc::channel();
+
+        let stats = run(config, rx);
+        assert_eq!((stats.sent, stats.received), (3, 3));
+    }
+
+    #[test]
+    fn run_rate_invalid() {
+        // The period of the rate does not fit a duration
+        let rw = MockRW::new(vec![]);
+        let pinger = Pinger::new(server()).host(HOST).rw(Box::new(rw));
+        let config = PingConfig {
+            count: Some(1),
+            rate: Some(1e-30),
+            ..PingConfig::new(pinger)
+        };
+        let (_tx, rx) = mpsc::channel();
+
+        let mut aborted = false;
+        let stats = run_events(
+            config,
+            rx,
+            |event| aborted |= matches!(event, PingEvent::Abort(None, ref e) if e.kind() == ErrorKind::InvalidInput),
+        );
+        assert!(aborted);
+        assert_eq!(stats.sent, 0);
+    }
+
+    #[test]
+    fn run_warmup() {
+        let rw = MockRW::new(
+            (1..=4)
+                .map(|id| {
+                    Step::new(
+                        Duration::from_millis(0),
+                        server(),
+                        response(id, HOST, 0, &[]),
+                    )
+                })
+                .collect(),
+        );
+        let pinger = Pinger::new(server()).host(HOST).rw(Box::new(rw));
+        let config = PingConfig {
+            interval: Duration::from_millis(0),
+            count: Some(2),
+            warmup: 2,
+            ..PingConfig::new(pinger)
+        };
+        let (_tx, rx) = mpsc::channel();
+
+        let mut warmups = 0;
+        let stats = run_events(config, rx, |event| {
+            if matches!(event, PingEvent::Reply(query, _) if query.warmup) {
+                warmups += 1;
+            }
+        });
+        assert_eq!(warmups, 2);
+        assert_eq!((stats.sent, stats.received), (2, 2));
+    }
+
+    #[test]
+    fn race_rounds() {
+        let fast = "127.0.0.2:53".parse().unwrap();
+        let slow = "127.0.0.3:53".parse().unwrap();
+        let config = |addr, delay, rcode| {
+            let rw = MockRW::new(
+                (1..=2)
+                    .map(|id| {
+                        Step::new(
+                            Duration::from_millis(delay),
+                            addr,
+                            response(id, HOST, rcode, &[]),
+                        )
+                    })
+                    .collect(),
+            );
+            let pinger = Pinger::new(addr).host(HOST).rw(Box::new(rw));
+            PingConfig {
+                interval: Duration::from_millis(0),
+                count: Some(2),
+                ..PingConfig::new(pinger)
+            }
+        };
+        let (_tx, rx) = mpsc::channel();
+
+        let mut winners = Vec::new();
+        let stats = race(vec![config(slow, 20, 0), config(fast, 0, 0)], rx, |event| {
+            if let RaceEvent::Round(_, winner) = event {
+                winners.push(winner.map(|(i, _)| i));
+            }
+        });
+        assert_eq!(winners, vec![Some(1), Some(1)]);
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].received, 2);
+
+        // A SERVFAIL reply never wins however fast it is
+        let (_tx, rx) = mpsc::channel();
+        let mut winners = Vec::new();
+        race(vec![config(slow, 20, 3), config(fast, 0, 2)], rx, |event| {
+            if let RaceEvent::Round(_, winner) = event {
+                winners.push(winner.map(|(i, _)| i));
+            }
+        });
+        assert_eq!(winners, vec![Some(0), Some(0)]);
+    }
+
+    #[test]
+    fn limits_finished() {
+        let limits = Limits {
+            count: Some(3),
+            count_received: false,
+            wait_for: None,
+        };
+        assert!(!limits.is_finished(2, 2, 2));
+        assert!(limits.is_finished(3, 0, 0));
+        let limits = Limits {
+            count_received: true,
+            ..limits
+        };
+        assert!(!limits.is_finished(5, 2, 2));
+        assert!(limits.is_finished(5, 3, 0));
+        let limits = Limits {
+            count: None,
+            count_received: false,
+            wait_for: Some(2),
+        };
+        assert!(!limits.is_finished(5, 5, 1));
+        assert!(limits.is_finished(5, 5, 2));
+        let limits = Limits {
+            wait_for: None,
+            ..limits
+        };
+        assert!(!limits.is_finished(usize::MAX, usize::MAX, usize::MAX));
+    }
+
+    #[test]
+    fn race_winner_pick() {
+        let ms = Duration::from_millis;
+        assert_eq!(race_winner(&[Some(ms(30)), None, Some(ms(10))]), Some(2));
+        assert_eq!(race_winner(&[Some(ms(10)), Some(ms(10))]), Some(0));
+        assert_eq!(race_winner(&[None, None]), None);
+        assert_eq!(race_winner(&[]), None);
+    }
+
+    #[test]
+    fn backoff_interval_cap() {
+        let ms = Duration::from_millis;
+        assert_eq!(backoff_interval(ms(1000), 0, ms(60000)), ms(1000));
+        assert_eq!(backoff_interval(ms(1000), 3, ms(60000)), ms(8000));
+        assert_eq!(backoff_interval(ms(1000), 10, ms(60000)), ms(60000));
+        assert_eq!(backoff_interval(ms(1000), 100, ms(60000)), ms(60000));
+        assert_eq!(backoff_interval(ms(1000), 1, ms(500)), ms(1000));
+        assert_eq!(backoff_interval(ms(0), 5, ms(60000)), ms(0));
+    }
+
+    #[test]
+    fn ipv6_unavailable_classify() {
+        let v6 = "[2001:4860:4860::8888]:53".parse().unwrap();
+        let v4 = "8.8.8.8:53".parse().unwrap();
+        let unreachable = Error::from(ErrorKind::NetworkUnreachable);
+        assert!(is_ipv6_unavailable(v6, &unreachable));
+        assert!(is_ipv6_unavailable(
+            v6,
+            &Error::from(ErrorKind::AddrNotAvailable)
+        ));
+        assert!(!is_ipv6_unavailable(v4, &unreachable));
+        assert!(!is_ipv6_unavailable(v6, &Error::from(ErrorKind::TimedOut)));
+    }
+
+    #[test]
+    fn network_error_classify() {
+        assert!(is_network_error(&Error::from(
+            ErrorKind::NetworkUnreachable
+        )));
+        assert!(is_network_error(&Error::from(ErrorKind::NetworkDown)));
+        #[cfg(unix)]
+        assert!(is_network_error(&Error::from_raw_os_error(libc::ENOBUFS)));
+        assert!(!is_network_error(&Error::from(ErrorKind::InvalidInput)));
+    }
+
+    #[test]
+    fn run_stop() {
+        let pinger = Pinger::new(server())
+            .host(HOST)
+            .rw(Box::new(MockRW::new(vec![])));
+        let config = PingConfig {
+            interval: Duration::from_secs(60),
+            ..PingConfig::new(pinger)
+        };
+        let (tx, rx) = mpsc::channel();
+        let handle = thread::spawn(move || run(config, rx));
+        thread::sleep(Duration::from_millis(10));
+        tx.send(()).unwrap();
+
+        let stats = handle.join().unwrap();
+        assert_eq!(stats.sent, 1);
+        assert_eq!(stats.received, 0);
+        assert_eq!(stats.avg, None);
+        assert_eq!(stats.loss, 100.0);
+    }
+
+    #[test]
+    fn pinger_ping_all() {
+        let group = "224.0.0.251:5353".parse().unwrap();
+        let others = ["127.0.0.2:5353", "127.0.0.3:5353"];
+        let rw = MockRW::new(
+            others
+                .iter()
+                .map(|other| {
+                    let other = other.parse().unwrap();
+                    Step::new(Duration::from_millis(0), other, response(1, HOST, 0, &[]))
+                })
+                .collect(),
+        );
+        let mut pinger = Pinger::new(group)
+            .host(HOST)
+            .multicast(true)
+            .timeout(Some(Duration::from_millis(10)))
+            .rw(Box::new(rw));
+
+        let replies = pinger.ping_all().unwrap();
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[1].source, others[1].parse().unwrap());
+        assert_eq!(pinger.ping_all().unwrap_err().kind(), ErrorKind::TimedOut);
     }
 }
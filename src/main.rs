@@ -1,16 +1,29 @@
-use ctrlc;
-use dns_parser::{Builder, QueryClass, QueryType};
-use dnsping as lib;
-use lib::{Datagram, Socket, RW};
+mod config;
+mod idna;
+
+use config::{Target, Transport};
+use dns_parser::{Packet, RData, ResponseCode};
+use dnsping::pcap::PcapWriter;
+use dnsping::{
+    Opcode, PingConfig, PingEvent, Pinger, Query, QueryType, RaceEvent, Reply, ResponseError,
+    MAX_QUERY_SIZE, NSID_CODE, PADDING_CODE, RANDOM_LABEL_LEN,
+};
 use std::clone::Clone;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
 use std::fmt::Display;
-use std::io;
-use std::net::{AddrParseError, IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::net::{
+    AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6,
+};
+use std::path::{Path, PathBuf};
+use std::process;
 use std::str::FromStr;
-use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
-use std::sync::{mpsc, Arc};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, UNIX_EPOCH};
 use structopt::StructOpt;
 
 #[derive(Debug)]
@@ -42,31 +55,70 @@ impl From<io::Error> for ResolvableAddrParseError {
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 struct ResolvableSocketAddr {
-    addr_v4: Option<SocketAddrV4>,
-    addr_v6: Option<SocketAddrV6>,
+    addrs: Vec<SocketAddr>,
     alias: Option<String>,
 }
 
 impl ResolvableSocketAddr {
     fn addr_v4(&self) -> Option<SocketAddrV4> {
-        self.addr_v4
+        self.addrs.iter().find_map(|addr| match addr {
+            SocketAddr::V4(addr_v4) => Some(*addr_v4),
+            SocketAddr::V6(_) => None,
+        })
     }
 
     fn addr_v6(&self) -> Option<SocketAddrV6> {
-        self.addr_v6
+        self.addrs.iter().find_map(|addr| match addr {
+            SocketAddr::V4(_) => None,
+            SocketAddr::V6(addr_v6) => Some(*addr_v6),
+        })
+    }
+
+    fn addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    fn is_resolved(&self) -> bool {
+        !self.addrs.is_empty()
+    }
+
+    fn resolve(&mut self) -> Result<(), ResolvableAddrParseError> {
+        if self.is_resolved() {
+            return Ok(());
+        }
+
+        let alias = self.alias.clone().unwrap();
+        let v = alias.split(':').collect::<Vec<_>>();
+        let port = v[1].parse().unwrap();
+
+        let mut addrs = Vec::new();
+        for ip in dns_lookup::lookup_host(v[0])? {
+            let addr = SocketAddr::new(ip, port);
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+
+        if addrs.is_empty() {
+            return Err(ResolvableAddrParseError::from(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No address of {} is found", v[0]),
+            )));
+        }
+
+        self.addrs = addrs;
+
+        Ok(())
     }
 }
 
 impl Display for ResolvableSocketAddr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if self.addr_v4.is_some() && self.addr_v6.is_some() {
-            write!(f, "{}/{}", self.addr_v4.unwrap(), self.addr_v6.unwrap())?;
-        } else if self.addr_v4.is_some() {
-            write!(f, "{}", self.addr_v4.unwrap())?;
-        } else if self.addr_v6.is_some() {
-            write!(f, "{}", self.addr_v6.unwrap())?;
-        } else {
-            unreachable!()
+        match (self.addr_v4(), self.addr_v6()) {
+            (Some(addr_v4), Some(addr_v6)) => write!(f, "{}/{}", addr_v4, addr_v6)?,
+            (Some(addr_v4), None) => write!(f, "{}", addr_v4)?,
+            (None, Some(addr_v6)) => write!(f, "{}", addr_v6)?,
+            (None, None) => return write!(f, "{}", self.alias.as_ref().unwrap()),
         }
         match &self.alias {
             Some(alias) => write!(f, " ({})", alias),
@@ -78,87 +130,499 @@ impl Display for ResolvableSocketAddr {
 impl FromStr for ResolvableSocketAddr {
     type Err = ResolvableAddrParseError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let has_alias;
-        let (addr_v4, addr_v6) = match s.parse() {
-            Ok(addr) => {
-                has_alias = false;
-
-                match addr {
-                    SocketAddr::V4(addr_v4) => (Some(addr_v4), None),
-                    SocketAddr::V6(addr_v6) => (None, Some(addr_v6)),
-                }
-            }
+        match s.parse() {
+            Ok(addr) => Ok(ResolvableSocketAddr {
+                addrs: vec![addr],
+                alias: None,
+            }),
             Err(e) => {
-                has_alias = true;
+                // IPv6 address with a zone
+                if let Some((ip, port)) = s.strip_prefix('[').and_then(|s| s.split_once("]:")) {
+                    if let (Ok((IpAddr::V6(ip), scope_id)), Ok(port)) =
+                        (config::parse_server(ip), port.parse())
+                    {
+                        return Ok(ResolvableSocketAddr {
+                            addrs: vec![SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id))],
+                            alias: None,
+                        });
+                    }
+                }
 
-                let v = s.split(":").collect::<Vec<_>>();
-                if v.len() != 2 {
+                // The name will be resolved later
+                let v = s.split(':').collect::<Vec<_>>();
+                if v.len() != 2 || v[1].parse::<u16>().is_err() {
                     return Err(ResolvableAddrParseError::from(e));
                 }
 
-                let port = match v[1].parse() {
-                    Ok(port) => port,
-                    Err(_) => return Err(ResolvableAddrParseError::from(e)),
-                };
+                Ok(ResolvableSocketAddr {
+                    addrs: Vec::new(),
+                    alias: Some(String::from(s)),
+                })
+            }
+        }
+    }
+}
 
-                let mut ip_v4 = None;
-                let mut ip_v6 = None;
-                match dns_lookup::lookup_host(v[0]) {
-                    Ok(addrs) => {
-                        for addr in addrs {
-                            match addr {
-                                IpAddr::V4(addr_v4) => {
-                                    if ip_v4.is_none() {
-                                        ip_v4 = Some(addr_v4);
-                                    }
-                                }
-                                IpAddr::V6(addr_v6) => {
-                                    if ip_v6.is_none() {
-                                        ip_v6 = Some(addr_v6);
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    Err(e) => return Err(ResolvableAddrParseError::from(e)),
-                };
+#[derive(Debug)]
+struct CountParseError(String);
 
-                if ip_v4.is_none() && ip_v6.is_none() {
-                    return Err(ResolvableAddrParseError::from(e));
+impl Display for CountParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid count {}, expected a positive integer, or 0, inf or infinite for no limit",
+            self.0
+        )
+    }
+}
+
+#[derive(Debug)]
+struct ColorParseError(String);
+
+impl Display for ColorParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid color {}, expected auto, always or never",
+            self.0
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum Color {
+    Auto,
+    Always,
+    Never,
+}
+
+impl Color {
+    fn is_enabled(&self) -> bool {
+        match self {
+            Color::Auto => io::stdout().is_terminal(),
+            Color::Always => true,
+            Color::Never => false,
+        }
+    }
+}
+
+impl FromStr for Color {
+    type Err = ColorParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Color::Auto),
+            "always" => Ok(Color::Always),
+            "never" => Ok(Color::Never),
+            _ => Err(ColorParseError(String::from(s))),
+        }
+    }
+}
+
+const GREEN: &str = "32";
+const YELLOW: &str = "33";
+const RED: &str = "31";
+
+fn opcode_to_string(opcode: Opcode) -> String {
+    match opcode {
+        Opcode::StandardQuery => String::from("QUERY"),
+        Opcode::InverseQuery => String::from("IQUERY"),
+        Opcode::ServerStatusRequest => String::from("STATUS"),
+        Opcode::Reserved(4) => String::from("NOTIFY"),
+        Opcode::Reserved(5) => String::from("UPDATE"),
+        Opcode::Reserved(code) => format!("OPCODE{}", code),
+    }
+}
+
+/// Formats the NSID as a string if it is printable, or in hex otherwise.
+fn format_nsid(nsid: &[u8]) -> String {
+    match nsid.iter().all(|c| c.is_ascii_graphic()) {
+        true => String::from_utf8_lossy(nsid).into_owned(),
+        false => nsid.iter().map(|c| format!("{:02x}", c)).collect(),
+    }
+}
+
+/// Formats the EDNS options in the data of an OPT pseudo-record, like `nsid padding=17`.
+fn format_edns_options(data: &[u8]) -> String {
+    dnsping::edns_options(data)
+        .into_iter()
+        .map(|(code, option)| match code {
+            NSID_CODE if option.is_empty() => String::from("nsid"),
+            NSID_CODE => format!("nsid={}", format_nsid(option)),
+            PADDING_CODE => format!("padding={}", option.len()),
+            code => format!("option{}={}", code, option.len()),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Describes the settings of the query in effect, like `A IN UDP EDNS=4096 DO`.
+fn describe_query(buffer: &[u8], transport: &str) -> String {
+    let packet = match Packet::parse(buffer) {
+        Ok(packet) => packet,
+        Err(_) => return String::from(transport),
+    };
+    let mut description = match packet.questions.first() {
+        Some(question) => format!("{:?} {:?} {}", question.qtype, question.qclass, transport),
+        None => String::from(transport),
+    };
+    if let Some(opt) = &packet.opt {
+        description.push_str(&format!(" EDNS={}", opt.udp));
+        if opt.flags >> 15 != 0 {
+            description.push_str(" DO");
+        }
+    }
+
+    description
+}
+
+/// Returns the name of the first question of the query.
+fn question_name(buffer: &[u8]) -> Option<String> {
+    let packet = Packet::parse(buffer).ok()?;
+    let question = packet.questions.first()?;
+
+    Some(question.qname.to_string())
+}
+
+fn print_query(name: &str, host: &str, buffer: &[u8]) {
+    println!(
+        "QUERY {} for {} {} bytes of data.",
+        name,
+        host,
+        buffer.len()
+    );
+    println!("{}", dnsping::hex_dump(buffer));
+    if let Ok(packet) = Packet::parse(buffer) {
+        let header = &packet.header;
+        println!(
+            "id={} opcode={} rd={} ad={} cd={} qdcount={} ancount={} nscount={} arcount={}",
+            header.id,
+            opcode_to_string(header.opcode),
+            header.recursion_desired as u8,
+            header.authenticated_data as u8,
+            header.checking_disabled as u8,
+            header.questions,
+            header.answers,
+            header.nameservers,
+            header.additional
+        );
+        for question in packet.questions.iter() {
+            println!(
+                "question {} {:?} {:?}",
+                question.qname, question.qtype, question.qclass
+            );
+        }
+        if let Some(opt) = &packet.opt {
+            let mut line = format!(
+                "opt udp={} version={} do={}",
+                opt.udp,
+                opt.version,
+                opt.flags >> 15
+            );
+            if let RData::Unknown(data) = opt.data {
+                if !data.is_empty() {
+                    line.push_str(&format!(" {}", format_edns_options(data)));
                 }
+            }
+            println!("{}", line);
+        }
+    }
+}
 
-                let addr_v4 = match ip_v4 {
-                    Some(ip_v4) => Some(SocketAddrV4::new(ip_v4, port)),
-                    None => None,
-                };
-                let addr_v6 = match ip_v6 {
-                    Some(ip_v6) => Some(SocketAddrV6::new(ip_v6, port, 0, 0)),
-                    None => None,
-                };
+fn reply_color(
+    rcode: ResponseCode,
+    duration: Duration,
+    warn_ms: Option<u64>,
+    crit_ms: Option<u64>,
+) -> &'static str {
+    let exceeds = |threshold: Option<u64>| match threshold {
+        Some(threshold) => duration >= Duration::from_millis(threshold),
+        None => false,
+    };
+
+    if rcode != ResponseCode::NoError {
+        YELLOW
+    } else if exceeds(crit_ms) {
+        RED
+    } else if exceeds(warn_ms) {
+        YELLOW
+    } else {
+        GREEN
+    }
+}
+
+fn paint(s: &str, color: &str, enabled: bool) -> String {
+    match enabled {
+        true => format!("\x1b[{}m{}\x1b[0m", color, s),
+        false => String::from(s),
+    }
+}
 
-                (addr_v4, addr_v6)
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum Count {
+    Infinite,
+    Finite(usize),
+}
+
+impl Count {
+    /// Returns the number of queries to send, `None` as sending without limit.
+    fn limit(&self) -> Option<usize> {
+        match self {
+            Count::Infinite => None,
+            Count::Finite(count) => Some(*count),
+        }
+    }
+}
+
+impl FromStr for Count {
+    type Err = CountParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" | "inf" | "infinite" => Ok(Count::Infinite),
+            // Only plain decimal digits, which `usize::from_str` relaxes by a sign or leading zeros
+            _ if s.starts_with('0') || !s.bytes().all(|b| b.is_ascii_digit()) => {
+                Err(CountParseError(String::from(s)))
             }
-        };
+            _ => match s.parse() {
+                Ok(count) => Ok(Count::Finite(count)),
+                Err(_) => Err(CountParseError(String::from(s))),
+            },
+        }
+    }
+}
 
-        let alias = match has_alias {
-            true => Some(String::from_str(s).unwrap()),
-            false => None,
-        };
-        Ok(ResolvableSocketAddr {
-            addr_v4,
-            addr_v6,
-            alias,
-        })
+#[derive(Debug)]
+struct CountModeParseError(String);
+
+impl Display for CountModeParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid count mode {}, expected sent or received",
+            self.0
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum CountMode {
+    Sent,
+    Received,
+}
+
+impl FromStr for CountMode {
+    type Err = CountModeParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sent" => Ok(CountMode::Sent),
+            "received" => Ok(CountMode::Received),
+            _ => Err(CountModeParseError(String::from(s))),
+        }
+    }
+}
+
+fn parse_opcode(s: &str) -> Result<Opcode, String> {
+    match s.to_ascii_uppercase().as_str() {
+        "QUERY" => Ok(Opcode::StandardQuery),
+        "IQUERY" => Ok(Opcode::InverseQuery),
+        "STATUS" => Ok(Opcode::ServerStatusRequest),
+        "NOTIFY" => Ok(Opcode::Reserved(4)),
+        "UPDATE" => Ok(Opcode::Reserved(5)),
+        _ => Err(format!(
+            "invalid opcode {}, expected QUERY, IQUERY, STATUS, NOTIFY or UPDATE",
+            s
+        )),
+    }
+}
+
+fn parse_dscp(s: &str) -> Result<u8, String> {
+    let upper = s.to_ascii_uppercase();
+    let dscp = match upper.as_str() {
+        "BE" | "DF" => Some(0),
+        "EF" => Some(46),
+        _ => {
+            if let Some(class) = upper.strip_prefix("CS") {
+                match class.parse::<u8>() {
+                    Ok(class) if class <= 7 => Some(class << 3),
+                    _ => None,
+                }
+            } else if let Some(class) = upper.strip_prefix("AF") {
+                match class.as_bytes() {
+                    [c, p] if (b'1'..=b'4').contains(c) && (b'1'..=b'3').contains(p) => {
+                        Some(((c - b'0') << 3) | ((p - b'0') << 1))
+                    }
+                    _ => None,
+                }
+            } else {
+                match s.parse::<u8>() {
+                    Ok(dscp) if dscp <= 63 => Some(dscp),
+                    _ => None,
+                }
+            }
+        }
+    };
+
+    match dscp {
+        Some(dscp) => Ok(dscp),
+        None => Err(format!(
+            "invalid DSCP {}, expected a value from 0 to 63, or a class like CS0, AF11 or EF",
+            s
+        )),
     }
 }
 
-#[derive(StructOpt, Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(StructOpt, Clone, Debug, PartialEq)]
 #[structopt(about)]
 struct Flags {
-    #[structopt(name = "ADDRESS", help = "Server")]
-    pub server: IpAddr,
-    #[structopt(long, short, help = "Do query iteratively")]
+    #[structopt(name = "ADDRESS", help = "Servers", required_unless_one(&["servers-file", "config", "mdns", "unix", "unix-socket"]))]
+    pub servers: Vec<Target>,
+    #[structopt(short = "4", help = "Use IPv4 only", conflicts_with("ipv6"))]
+    pub ipv4: bool,
+    #[structopt(short = "6", help = "Use IPv6 only")]
+    pub ipv6: bool,
+    #[structopt(long, short, help = "Do query iteratively, same as --no-recurse")]
     pub iterate: bool,
+    #[structopt(
+        long,
+        help = "Set the RD bit to request recursion",
+        conflicts_with_all(&["no-recurse", "iterate"])
+    )]
+    pub recurse: bool,
+    #[structopt(long = "no-recurse", help = "Clear the RD bit")]
+    pub no_recurse: bool,
+    #[structopt(long = "show-ttl", help = "Show minimum TTL of the answers")]
+    pub show_ttl: bool,
+    #[structopt(
+        long = "show-counts",
+        help = "Show the numbers of the answer, authority and additional records"
+    )]
+    pub show_counts: bool,
+    #[structopt(long, help = "Request and show the name server identifier")]
+    pub nsid: bool,
+    #[structopt(
+        long,
+        help = "Set the AD bit and show whether replies are authenticated"
+    )]
+    pub ad: bool,
+    #[structopt(long, help = "Set the CD bit to disable DNSSEC validation")]
+    pub cd: bool,
+    #[structopt(
+        long,
+        help = "Request DNSSEC records and show whether replies are authenticated"
+    )]
+    pub dnssec: bool,
+    #[structopt(
+        long = "accept-any-source",
+        help = "Accept replies from any source address"
+    )]
+    pub accept_any_source: bool,
+    #[structopt(
+        long,
+        help = "Query over TCP",
+        conflicts_with_all(&["mdns", "source-port"])
+    )]
+    pub tcp: bool,
+    #[structopt(long, short, help = "Ring the bell on each reply")]
+    pub audible: bool,
+    #[structopt(
+        long = "audible-transition",
+        help = "Ring the bell only when the server becomes reachable or unreachable"
+    )]
+    pub audible_transition: bool,
+    #[structopt(
+        long,
+        help = "Query the mDNS multicast group",
+        conflicts_with("socks-proxy")
+    )]
+    pub mdns: bool,
+    #[structopt(
+        long = "all-addresses",
+        help = "Ping through every resolved address of the proxy"
+    )]
+    pub all_addresses: bool,
+    #[structopt(long = "wait-all", help = "Wait for replies in flight on interrupt")]
+    pub wait_all: bool,
+    #[structopt(
+        long = "randomize-subdomain",
+        help = "Query a random subdomain of the host each time"
+    )]
+    pub randomize_subdomain: bool,
+    #[structopt(long = "random-id", help = "Use random transaction IDs")]
+    pub random_id: bool,
+    #[structopt(
+        long = "0x20",
+        help = "Randomize the case of the host each time and flag replies not echoing it",
+        conflicts_with_all(&["mdns", "qps", "payload-hex", "payload-file"])
+    )]
+    pub randomize_case: bool,
+    #[structopt(
+        long = "dry-run",
+        help = "Print the queries without sending",
+        conflicts_with("mdns")
+    )]
+    pub dry_run: bool,
+    #[structopt(long, help = "Fail on malformed lines in the servers file")]
+    pub strict: bool,
+    #[structopt(long, short, help = "Do not resolve any name")]
+    pub numeric: bool,
+    #[structopt(
+        long,
+        short,
+        help = "Show the time to the first byte and the total time of replies"
+    )]
+    pub verbose: bool,
+    #[structopt(
+        long = "resolve-names",
+        help = "Show names of servers",
+        conflicts_with("numeric")
+    )]
+    pub resolve_names: bool,
+    #[structopt(long, help = "Print results as CSV")]
+    pub csv: bool,
+    #[structopt(long, help = "Show percentiles of latency")]
+    pub percentiles: bool,
+    #[structopt(
+        long = "prometheus-file",
+        alias = "prometheus",
+        help = "File to write Prometheus metrics to on exit and every summary interval",
+        value_name = "PATH",
+        parse(from_os_str)
+    )]
+    pub prometheus_file: Option<PathBuf>,
+    #[structopt(long, help = "Show a histogram of latency")]
+    pub histogram: bool,
+    #[structopt(
+        long = "hist-bucket-ms",
+        help = "Width of each bucket of the histogram",
+        value_name = "VALUE",
+        default_value = "5"
+    )]
+    pub hist_bucket_ms: u64,
+    #[structopt(
+        long,
+        help = "Color the output",
+        value_name = "WHEN",
+        default_value = "auto",
+        possible_values(&["auto", "always", "never"])
+    )]
+    pub color: Color,
+    #[structopt(
+        long = "warn-ms",
+        help = "Latency to color a reply as warning",
+        value_name = "VALUE"
+    )]
+    pub warn_ms: Option<u64>,
+    #[structopt(
+        long = "crit-ms",
+        help = "Latency to color a reply as critical",
+        value_name = "VALUE"
+    )]
+    pub crit_ms: Option<u64>,
+    #[structopt(
+        long = "threshold-ms",
+        help = "Latency to count a reply as over the threshold",
+        value_name = "VALUE"
+    )]
+    pub threshold_ms: Option<u64>,
     #[structopt(
         long,
         short,
@@ -168,29 +632,120 @@ struct Flags {
         display_order(0)
     )]
     pub port: u16,
+    #[structopt(
+        long = "source-port",
+        alias = "bind-port",
+        help = "Local port",
+        value_name = "PORT",
+        default_value = "0",
+        display_order(1)
+    )]
+    pub source_port: u16,
+    #[structopt(
+        long,
+        help = "Network interface to bind (Linux only)",
+        value_name = "NAME",
+        conflicts_with("tcp"),
+        display_order(1)
+    )]
+    pub interface: Option<String>,
+    #[structopt(
+        long = "dual-stack",
+        help = "Bind a dual-stack socket for IPv6 servers",
+        conflicts_with("tcp"),
+        display_order(1)
+    )]
+    pub dual_stack: bool,
+    #[structopt(
+        long = "random-source-port",
+        help = "Bind a fresh ephemeral port for each query",
+        conflicts_with_all(&["source-port", "tcp", "qps", "mdns"]),
+        display_order(1)
+    )]
+    pub random_source_port: bool,
     #[structopt(
         long,
         short = "H",
-        help = "Host",
+        help = "Hosts separated by commas",
         value_name = "HOST",
         default_value = "www.google.com",
-        display_order(1)
+        use_delimiter(true),
+        display_order(2)
+    )]
+    pub host: Vec<String>,
+    #[structopt(
+        long,
+        help = "File of hosts, one per line",
+        value_name = "PATH",
+        parse(from_os_str),
+        conflicts_with("host"),
+        display_order(3)
+    )]
+    pub hostfile: Option<PathBuf>,
+    #[structopt(
+        long = "servers-file",
+        help = "File of servers, one per line",
+        value_name = "PATH",
+        parse(from_os_str),
+        display_order(4)
+    )]
+    pub servers_file: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Opcode of the query",
+        value_name = "OPCODE",
+        default_value = "QUERY",
+        parse(try_from_str = parse_opcode),
+        display_order(5)
+    )]
+    pub opcode: Opcode,
+    #[structopt(
+        long,
+        help = "IP ToS or IPv6 traffic class",
+        value_name = "VALUE",
+        display_order(6)
+    )]
+    pub tos: Option<u8>,
+    #[structopt(
+        long,
+        help = "DSCP",
+        value_name = "CLASS",
+        parse(try_from_str = parse_dscp),
+        conflicts_with("tos"),
+        display_order(7)
+    )]
+    pub dscp: Option<u8>,
+    #[structopt(
+        long,
+        help = "Config file of targets and options",
+        value_name = "PATH",
+        parse(from_os_str),
+        display_order(8)
     )]
-    pub host: String,
+    pub config: Option<PathBuf>,
     #[structopt(
         long = "socks-proxy",
         short = "s",
         help = "SOCKS proxy",
         value_name = "ADDRESS",
-        display_order(3)
+        display_order(9)
     )]
     pub proxy: Option<ResolvableSocketAddr>,
+    #[structopt(
+        long = "http-proxy",
+        help = "HTTP proxy",
+        value_name = "ADDRESS",
+        conflicts_with("socks-proxy"),
+        requires("tcp"),
+        display_order(10)
+    )]
+    pub http_proxy: Option<ResolvableSocketAddr>,
     #[structopt(
         long,
         help = "Username",
         value_name = "VALUE",
         requires("password"),
-        display_order(4)
+        display_order(11)
     )]
     pub username: Option<String>,
     #[structopt(
@@ -198,7 +753,7 @@ struct Flags {
         help = "Password",
         value_name = "VALUE",
         requires("username"),
-        display_order(5)
+        display_order(12)
     )]
     pub password: Option<String>,
     #[structopt(
@@ -206,227 +761,2350 @@ struct Flags {
         short,
         help = "Number of queries to send",
         value_name = "VALUE",
-        default_value = "0",
-        display_order(6)
+        default_value = "inf",
+        allow_hyphen_values(true),
+        display_order(13)
+    )]
+    pub count: Count,
+    #[structopt(
+        long = "count-mode",
+        help = "Whether --count counts the queries sent or the replies received",
+        value_name = "MODE",
+        default_value = "sent",
+        possible_values(&["sent", "received"]),
+        display_order(13)
+    )]
+    pub count_mode: CountMode,
+    #[structopt(
+        long = "wait-for",
+        help = "Number of NOERROR replies to wait for",
+        value_name = "VALUE",
+        parse(try_from_str = parse_wait_for),
+        display_order(14)
+    )]
+    pub wait_for: Option<usize>,
+    #[structopt(
+        long,
+        help = "Size the query is padded to",
+        value_name = "VALUE",
+        parse(try_from_str = parse_size),
+        display_order(15)
     )]
-    pub count: usize,
+    pub size: Option<usize>,
     #[structopt(
         long,
         short = "I",
         help = "Wait between sending each packet",
         value_name = "VALUE",
         default_value = "1000",
-        display_order(7)
+        display_order(16)
     )]
     pub interval: u64,
+    #[structopt(
+        long,
+        help = "Random delay added to each interval",
+        value_name = "VALUE",
+        default_value = "0",
+        display_order(17)
+    )]
+    pub jitter: u64,
+    #[structopt(
+        long = "summary-interval",
+        alias = "report-interval",
+        help = "Print a summary of the last interval every interval",
+        value_name = "VALUE",
+        default_value = "0",
+        display_order(18)
+    )]
+    pub summary_interval: u64,
     #[structopt(
         long,
         short = "w",
         help = "Timeout to wait for each response",
         value_name = "VALUE",
         default_value = "1000",
-        display_order(8)
+        display_order(19)
     )]
     pub timeout: u64,
-}
-
-fn main() {
+    #[structopt(
+        long,
+        help = "Size of the socket receive buffer",
+        value_name = "SIZE",
+        parse(try_from_str = parse_buffer_size),
+        display_order(20)
+    )]
+    pub rcvbuf: Option<usize>,
+    #[structopt(
+        long,
+        help = "Size of the socket send buffer",
+        value_name = "SIZE",
+        parse(try_from_str = parse_buffer_size),
+        display_order(21)
+    )]
+    pub sndbuf: Option<usize>,
+    #[structopt(
+        long,
+        help = "Number of queries to send before the statistics",
+        value_name = "VALUE",
+        default_value = "0",
+        display_order(22)
+    )]
+    pub warmup: usize,
+    #[structopt(
+        long,
+        help = "Unix datagram socket of a local resolver",
+        value_name = "PATH",
+        parse(from_os_str),
+        conflicts_with_all(&["tcp", "socks-proxy", "http-proxy", "mdns", "source-port"]),
+        display_order(23)
+    )]
+    pub unix: Option<PathBuf>,
+    #[structopt(
+        long = "unix-socket",
+        help = "Unix stream socket of a local resolver",
+        value_name = "PATH",
+        parse(from_os_str),
+        conflicts_with_all(&["unix", "socks-proxy", "http-proxy", "mdns", "source-port"]),
+        display_order(24)
+    )]
+    pub unix_socket: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Queries to send per second regardless of the replies",
+        value_name = "RATE",
+        parse(try_from_str = parse_qps),
+        conflicts_with("mdns"),
+        display_order(25)
+    )]
+    pub qps: Option<f64>,
+    #[structopt(
+        long,
+        help = "Race the servers and count the fastest responders",
+        conflicts_with_all(&["mdns", "qps", "csv", "warmup"]),
+        display_order(26)
+    )]
+    pub race: bool,
+    #[structopt(
+        long = "best-of",
+        help = "Number of queries to send at once of which the fastest is counted",
+        value_name = "VALUE",
+        parse(try_from_str = parse_best_of),
+        conflicts_with_all(&["mdns", "qps", "race"]),
+        display_order(27)
+    )]
+    pub best_of: Option<usize>,
+    #[structopt(
+        long = "type",
+        short = "t",
+        help = "Query types separated by commas",
+        value_name = "TYPE",
+        parse(try_from_str = config::parse_query_type),
+        use_delimiter(true),
+        display_order(28)
+    )]
+    pub query_types: Vec<QueryType>,
+    #[structopt(
+        long,
+        help = "Back off exponentially on REFUSED or SERVFAIL",
+        conflicts_with_all(&["qps", "race"]),
+        display_order(29)
+    )]
+    pub backoff: bool,
+    #[structopt(
+        long = "backoff-max",
+        help = "Maximum interval to back off to",
+        value_name = "VALUE",
+        default_value = "60000",
+        display_order(30)
+    )]
+    pub backoff_max: u64,
+    #[structopt(
+        long,
+        help = "Network namespace to ping in (Linux only)",
+        value_name = "NAME",
+        display_order(31)
+    )]
+    pub netns: Option<String>,
+    #[structopt(
+        long = "write-pcap",
+        alias = "pcap",
+        help = "File to write the queries and responses to in the pcap format",
+        value_name = "PATH",
+        parse(from_os_str),
+        display_order(32)
+    )]
+    pub write_pcap: Option<PathBuf>,
+    #[structopt(
+        long = "payload-hex",
+        help = "Raw query in hex to send as is",
+        value_name = "HEX",
+        conflicts_with_all(&[
+            "qps",
+            "best-of",
+            "randomize-subdomain",
+            "size",
+            "nsid",
+            "dnssec",
+            "query-types",
+            "no-recurse",
+            "iterate"
+        ]),
+        display_order(33)
+    )]
+    pub payload_hex: Option<Hex>,
+    #[structopt(
+        long = "payload-file",
+        help = "File of a raw query to send as is",
+        value_name = "PATH",
+        parse(from_os_str),
+        conflicts_with_all(&[
+            "payload-hex",
+            "qps",
+            "best-of",
+            "randomize-subdomain",
+            "size",
+            "nsid",
+            "dnssec",
+            "query-types",
+            "no-recurse",
+            "iterate"
+        ]),
+        display_order(34)
+    )]
+    pub payload_file: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Address the answers must contain for a reply to be correct",
+        value_name = "IP",
+        display_order(35)
+    )]
+    pub validate: Option<IpAddr>,
+}
+
+fn rcode_to_string(rcode: ResponseCode) -> String {
+    match rcode {
+        ResponseCode::NoError => String::from("NOERROR"),
+        ResponseCode::FormatError => String::from("FORMERR"),
+        ResponseCode::ServerFailure => String::from("SERVFAIL"),
+        ResponseCode::NameError => String::from("NXDOMAIN"),
+        ResponseCode::NotImplemented => String::from("NOTIMP"),
+        ResponseCode::Refused => String::from("REFUSED"),
+        ResponseCode::Reserved(code) => format!("RCODE{}", code),
+    }
+}
+
+/// Returns the HINFO record as its CPU, followed by its OS if any.
+fn format_hinfo(cpu: &str, os: &str) -> String {
+    match os.is_empty() {
+        true => String::from(cpu),
+        false => format!("{}/{}", cpu, os),
+    }
+}
+
+/// Returns whether the HINFO record indicates the server refuses ANY queries by RFC 8482.
+fn is_any_refused(hinfo: &Option<(String, String)>) -> bool {
+    matches!(hinfo, Some((cpu, _)) if cpu == "RFC8482")
+}
+
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+
+    sorted[rank.max(1).min(sorted.len()) - 1]
+}
+
+/// Width of the terminal if it is neither given by the terminal nor by `COLUMNS`.
+const DEFAULT_COLUMNS: usize = 80;
+
+/// Maximum number of buckets of a histogram, the last of which collects the latencies beyond.
+const MAX_HISTOGRAM_BUCKETS: usize = 40;
+
+/// Returns the width of the terminal of the standard output, `COLUMNS` or `DEFAULT_COLUMNS`.
+fn terminal_columns() -> usize {
+    #[cfg(unix)]
+    {
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        let ret = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+        if ret == 0 && size.ws_col != 0 {
+            return size.ws_col as usize;
+        }
+    }
+
+    env::var("COLUMNS")
+        .ok()
+        .and_then(|columns| columns.parse().ok())
+        .filter(|&columns| columns != 0)
+        .unwrap_or(DEFAULT_COLUMNS)
+}
+
+/// Returns the lines of a histogram of the sorted latencies in microseconds with buckets of the
+/// width in milliseconds, whose bars are scaled to fit the columns. At most
+/// `MAX_HISTOGRAM_BUCKETS` buckets are shown, the last of which is open-ended.
+fn histogram(sorted: &[u64], bucket_ms: u64, columns: usize) -> Vec<String> {
+    let bucket = bucket_ms * 1000;
+    let first = sorted[0] / bucket;
+    let last = (sorted[sorted.len() - 1] / bucket).min(first + MAX_HISTOGRAM_BUCKETS as u64 - 1);
+    let mut counts = vec![0usize; (last - first + 1) as usize];
+    for latency in sorted {
+        counts[((latency / bucket).min(last) - first) as usize] += 1;
+    }
+
+    let overflow = sorted[sorted.len() - 1] / bucket > last;
+    let labels = (first..=last)
+        .map(|i| {
+            if overflow && i == last {
+                format!(">={} ms", i * bucket_ms)
+            } else {
+                format!("{}-{} ms", i * bucket_ms, (i + 1) * bucket_ms)
+            }
+        })
+        .collect::<Vec<_>>();
+    let label_width = labels.iter().map(|label| label.len()).max().unwrap_or(0);
+    let max = counts.iter().copied().max().unwrap_or(0).max(1);
+    let count_width = max.to_string().len();
+    let bar_width = columns.saturating_sub(label_width + count_width + 3).max(1);
+
+    labels
+        .iter()
+        .zip(counts.iter())
+        .map(|(label, count)| {
+            format!(
+                "{:>label_width$} |{:<bar_width$} {}",
+                label,
+                "#".repeat(count * bar_width / max),
+                count,
+                label_width = label_width,
+                bar_width = bar_width
+            )
+        })
+        .collect()
+}
+
+/// Maximum size of a DNS message over UDP without EDNS.
+const MAX_UDP_SIZE: usize = 512;
+
+const MDNS_V4: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+const MDNS_PORT: u16 = 5353;
+
+/// Upper bounds of the buckets of the round-trip time in microseconds, which are exposed as a
+/// Prometheus histogram.
+const RTT_BUCKETS: [u64; 13] = [
+    500, 1000, 2500, 5000, 10000, 25000, 50000, 100000, 250000, 500000, 1000000, 2500000, 5000000,
+];
+
+#[derive(Debug)]
+struct Statistics {
+    /// Number of queries completed by a reply, a timeout or an error, so that the query in flight
+    /// is neither transmitted nor lost.
+    send: usize,
+    recv: usize,
+    unreachable: usize,
+    malformed: usize,
+    foreign: usize,
+    duplicates: usize,
+    out_of_order: usize,
+    suspicious: usize,
+    connects: usize,
+    connect_total: u64,
+    correct: usize,
+    incorrect: usize,
+    wins: usize,
+    over_threshold: usize,
+    latency_total: u64,
+    latency_min: u64,
+    latency_max: u64,
+    latencies: Vec<u64>,
+    /// Number of the replies in each bucket of `RTT_BUCKETS` and over the last one.
+    rtt_buckets: [usize; RTT_BUCKETS.len() + 1],
+    responders: HashSet<SocketAddr>,
+    samples: VecDeque<(Instant, Option<u64>)>,
+}
+
+impl Statistics {
+    fn new() -> Statistics {
+        Statistics {
+            send: 0,
+            recv: 0,
+            unreachable: 0,
+            malformed: 0,
+            foreign: 0,
+            duplicates: 0,
+            out_of_order: 0,
+            suspicious: 0,
+            connects: 0,
+            connect_total: 0,
+            correct: 0,
+            incorrect: 0,
+            wins: 0,
+            over_threshold: 0,
+            latency_total: 0,
+            latency_min: u64::MAX,
+            latency_max: 0,
+            latencies: Vec::new(),
+            rtt_buckets: [0; RTT_BUCKETS.len() + 1],
+            responders: HashSet::new(),
+            samples: VecDeque::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum Event {
+    Finished,
+    Interrupted,
+}
+
+fn parse_servers(s: &str, strict: bool) -> Result<Vec<Target>, String> {
+    let mut servers = Vec::new();
+    for (i, line) in s.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(index) => &line[..index],
+            None => line,
+        }
+        .trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        match line.parse() {
+            Ok(server) => servers.push(server),
+            Err(e) => {
+                let e = format!("Invalid server {} at line {}: {}", line, i + 1, e);
+                if strict {
+                    return Err(e);
+                }
+                eprintln!("{}", e);
+            }
+        }
+    }
+
+    Ok(servers)
+}
+
+fn print_summary(name: &str, stats: &mut Statistics, window: Duration) {
+    let samples = &mut stats.samples;
+    let now = Instant::now();
+    while let Some((instant, _)) = samples.front() {
+        if now.duration_since(*instant) <= window {
+            break;
+        }
+        samples.pop_front();
+    }
+
+    let send = samples.len();
+    let latencies = samples
+        .iter()
+        .filter_map(|(_, latency)| *latency)
+        .collect::<Vec<_>>();
+    let mut line = format!(
+        "{} last {}s: {}/{} replies, {:.2}% loss",
+        name,
+        window.as_secs(),
+        latencies.len(),
+        send,
+        loss_rate(send, latencies.len())
+    );
+    if !latencies.is_empty() {
+        let latency_avg = latencies.iter().sum::<u64>() / latencies.len() as u64;
+        line.push_str(&format!(", avg {:.3} ms", latency_avg as f64 / 1000.0));
+    }
+    eprintln!("{}", line);
+}
+
+/// Returns the packet loss in percentage. Queries still waiting for replies are not counted as
+/// lost.
+fn loss_rate(completed: usize, recv: usize) -> f64 {
+    match completed {
+        0 => 0.0,
+        _ => (completed.saturating_sub(recv) as f64) / (completed as f64) * 100.0,
+    }
+}
+
+/// Returns the line of the replies over the threshold in milliseconds.
+fn format_threshold(over: usize, recv: usize, threshold: u64) -> String {
+    let rate = match recv {
+        0 => 0.0,
+        _ => over as f64 / recv as f64 * 100.0,
+    };
+
+    format!(
+        "{}/{} replies over {}ms ({:.2}%)",
+        over, recv, threshold, rate
+    )
+}
+
+/// Returns whether the addresses of the answers contain the expected one, `None` if there is no
+/// expected address.
+fn validate(addresses: &[IpAddr], expected: Option<IpAddr>) -> Option<bool> {
+    expected.map(|expected| addresses.contains(&expected))
+}
+
+/// Returns the line of the correct and incorrect replies.
+fn format_validation(correct: usize, incorrect: usize) -> String {
+    format!("{} correct, {} incorrect", correct, incorrect)
+}
+
+/// Exits with a failure if any reply is incorrect.
+fn exit_on_incorrect(statistics: &[(String, String, Arc<Mutex<Statistics>>)]) {
+    if statistics
+        .iter()
+        .any(|(_, _, stats)| stats.lock().unwrap().incorrect != 0)
+    {
+        process::exit(1);
+    }
+}
+
+/// Returns the line of the time to connect in microseconds, which is also amortized over the
+/// queries sent over the connections.
+fn format_connect(connect_total: u64, connects: usize, send: usize) -> String {
+    let avg = connect_total as f64 / connects.max(1) as f64 / 1000.0;
+    let amortized = connect_total as f64 / send.max(1) as f64 / 1000.0;
+
+    format!(
+        "connect avg = {:.3} ms, {:.3} ms amortized over {} queries",
+        avg, amortized, send
+    )
+}
+
+fn print_statistics(
+    name: &str,
+    stats: &Statistics,
+    elapsed: Duration,
+    prefix: &str,
+    flags: &Flags,
+) {
+    let send = stats.send;
+    let recv = stats.recv;
+    let loss_rate = loss_rate(send, recv);
+    let latency_total = stats.latency_total;
+    let latency_min = stats.latency_min;
+    let latency_max = stats.latency_max;
+    let rate = match elapsed.as_micros() {
+        0 => 0.0,
+        elapsed => (send as f64) / (elapsed as f64) * 1000000.0,
+    };
+
+    let unreachable = stats.unreachable;
+    let malformed = stats.malformed;
+    let mut errors = String::new();
+    if unreachable != 0 {
+        errors.push_str(&format!(" +{} errors,", unreachable));
+    }
+    if malformed != 0 {
+        errors.push_str(&format!(" +{} malformed,", malformed));
+    }
+    let foreign = stats.foreign;
+    if foreign != 0 {
+        errors.push_str(&format!(" +{} foreign,", foreign));
+    }
+    let duplicates = stats.duplicates;
+    if duplicates != 0 {
+        errors.push_str(&format!(" +{} duplicates,", duplicates));
+    }
+    let out_of_order = stats.out_of_order;
+    if out_of_order != 0 {
+        errors.push_str(&format!(" +{} out of order,", out_of_order));
+    }
+    let suspicious = stats.suspicious;
+    if suspicious != 0 {
+        errors.push_str(&format!(" +{} suspicious,", suspicious));
+    }
+
+    println!("{}--- {} ping statistics ---", prefix, name);
+    println!(
+        "{}{} packets transmitted, {} received,{} {:.2}% packet loss, time {} ms",
+        prefix,
+        send,
+        recv,
+        errors,
+        loss_rate,
+        elapsed.as_millis()
+    );
+    println!("{}send rate = {:.3} packets/s", prefix, rate);
+
+    if recv != 0 {
+        let latency_avg = latency_total / recv as u64;
+        println!(
+            "{}rtt min/avg/max = {:.3}/{:.3}/{:.3} ms",
+            prefix,
+            latency_min as f64 / 1000.0,
+            latency_avg as f64 / 1000.0,
+            latency_max as f64 / 1000.0
+        );
+    }
+
+    let connects = stats.connects;
+    if connects != 0 {
+        let connect_total = stats.connect_total;
+        println!(
+            "{}{}",
+            prefix,
+            format_connect(connect_total, connects, send)
+        );
+    }
+
+    if flags.validate.is_some() {
+        let correct = stats.correct;
+        let incorrect = stats.incorrect;
+        println!("{}{}", prefix, format_validation(correct, incorrect));
+    }
+
+    if let Some(threshold) = flags.threshold_ms {
+        let over = stats.over_threshold;
+        println!("{}{}", prefix, format_threshold(over, recv, threshold));
+    }
+
+    if flags.race {
+        let wins = stats.wins;
+        println!("{}{} wins in {} rounds", prefix, wins, send);
+    }
+
+    let responders = stats.responders.len();
+    if responders != 0 {
+        println!("{}{} distinct responders", prefix, responders);
+    }
+
+    let mut latencies = stats.latencies.clone();
+    if !latencies.is_empty() {
+        latencies.sort_unstable();
+        if flags.percentiles {
+            println!(
+                "{}rtt p50/p90/p99 = {:.3}/{:.3}/{:.3} ms",
+                prefix,
+                percentile(&latencies, 50.0) as f64 / 1000.0,
+                percentile(&latencies, 90.0) as f64 / 1000.0,
+                percentile(&latencies, 99.0) as f64 / 1000.0
+            );
+        }
+        if flags.histogram {
+            for line in histogram(&latencies, flags.hist_bucket_ms, terminal_columns()) {
+                println!("{}{}", prefix, line);
+            }
+        }
+    }
+}
+
+/// Escapes a label value of Prometheus metrics.
+fn escape_label(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Formats the statistics of the servers and their hosts as Prometheus metrics in the text
+/// exposition format.
+fn prometheus_metrics(statistics: &[(String, String, Arc<Mutex<Statistics>>)]) -> String {
+    let labels = |name: &str, hosts: &str| {
+        format!(
+            "server=\"{}\",host=\"{}\"",
+            escape_label(name),
+            escape_label(hosts)
+        )
+    };
+
+    let mut s = String::new();
+    s.push_str("# HELP dnsping_sent_total Number of queries sent.\n");
+    s.push_str("# TYPE dnsping_sent_total counter\n");
+    for (name, hosts, stats) in statistics.iter() {
+        let send = stats.lock().unwrap().send;
+        s.push_str(&format!(
+            "dnsping_sent_total{{{}}} {}\n",
+            labels(name, hosts),
+            send
+        ));
+    }
+    s.push_str("# HELP dnsping_received_total Number of replies received.\n");
+    s.push_str("# TYPE dnsping_received_total counter\n");
+    for (name, hosts, stats) in statistics.iter() {
+        let recv = stats.lock().unwrap().recv;
+        s.push_str(&format!(
+            "dnsping_received_total{{{}}} {}\n",
+            labels(name, hosts),
+            recv
+        ));
+    }
+    s.push_str("# HELP dnsping_rtt_seconds Round-trip time of the replies.\n");
+    s.push_str("# TYPE dnsping_rtt_seconds summary\n");
+    for (name, hosts, stats) in statistics.iter() {
+        let labels = labels(name, hosts);
+        let stats = stats.lock().unwrap();
+        let mut latencies = stats.latencies.clone();
+        latencies.sort_unstable();
+        if !latencies.is_empty() {
+            for quantile in [0.5, 0.9, 0.99] {
+                s.push_str(&format!(
+                    "dnsping_rtt_seconds{{{},quantile=\"{}\"}} {}\n",
+                    labels,
+                    quantile,
+                    percentile(&latencies, quantile * 100.0) as f64 / 1000000.0
+                ));
+            }
+        }
+        let latency_total = stats.latency_total;
+        s.push_str(&format!(
+            "dnsping_rtt_seconds_sum{{{}}} {}\n",
+            labels,
+            latency_total as f64 / 1000000.0
+        ));
+        s.push_str(&format!(
+            "dnsping_rtt_seconds_count{{{}}} {}\n",
+            labels, stats.recv
+        ));
+    }
+    s.push_str("# HELP dnsping_rtt_histogram_seconds Round-trip time of the replies in buckets.\n");
+    s.push_str("# TYPE dnsping_rtt_histogram_seconds histogram\n");
+    for (name, hosts, stats) in statistics.iter() {
+        let labels = labels(name, hosts);
+        let stats = stats.lock().unwrap();
+        let mut count = 0;
+        for (i, bucket) in stats.rtt_buckets.iter().enumerate() {
+            count += bucket;
+            let le = match RTT_BUCKETS.get(i) {
+                Some(&bound) => (bound as f64 / 1000000.0).to_string(),
+                None => String::from("+Inf"),
+            };
+            s.push_str(&format!(
+                "dnsping_rtt_histogram_seconds_bucket{{{},le=\"{}\"}} {}\n",
+                labels, le, count
+            ));
+        }
+        let latency_total = stats.latency_total;
+        s.push_str(&format!(
+            "dnsping_rtt_histogram_seconds_sum{{{}}} {}\n",
+            labels,
+            latency_total as f64 / 1000000.0
+        ));
+        s.push_str(&format!(
+            "dnsping_rtt_histogram_seconds_count{{{}}} {}\n",
+            labels, stats.recv
+        ));
+    }
+    s.push_str("# HELP dnsping_loss_ratio Ratio of the queries without replies.\n");
+    s.push_str("# TYPE dnsping_loss_ratio gauge\n");
+    for (name, hosts, stats) in statistics.iter() {
+        let stats = stats.lock().unwrap();
+        let send = stats.send;
+        let recv = stats.recv;
+        s.push_str(&format!(
+            "dnsping_loss_ratio{{{}}} {}\n",
+            labels(name, hosts),
+            loss_rate(send, recv) / 100.0
+        ));
+    }
+
+    s
+}
+
+/// Writes the file by renaming a temporary file written next to it, so that a reader never sees a
+/// partial file.
+fn write_atomically(path: &Path, s: &str) -> io::Result<()> {
+    // Instances writing the same file do not share the temporary file
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!(".{}.tmp", process::id()));
+    fs::write(&temp, s)?;
+
+    fs::rename(&temp, path)
+}
+
+fn write_prometheus_file(statistics: &[(String, String, Arc<Mutex<Statistics>>)], flags: &Flags) {
+    if let Some(path) = &flags.prometheus_file {
+        if let Err(ref e) = write_atomically(path, &prometheus_metrics(statistics)) {
+            eprintln!("{}", e);
+        }
+    }
+}
+
+/// Prints the error of the first query to the server, which is explained if the host has no IPv6
+/// connectivity.
+fn print_first_error(addr: SocketAddr, e: &io::Error) {
+    match dnsping::is_ipv6_unavailable(addr, e) {
+        true => eprintln!(
+            "IPv6 is not available on this host ({}), try an IPv4 server or -4",
+            e
+        ),
+        false => eprintln!("{}", e),
+    }
+}
+
+/// Enters the named network namespace in `/var/run/netns`, which is created like by `ip netns add`.
+/// Sockets bound afterwards by the thread and threads it spawns belong to the namespace.
+#[cfg(target_os = "linux")]
+fn enter_netns(name: &str) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = fs::File::open(Path::new("/var/run/netns").join(name)).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Cannot open the network namespace {}: {}", name, e),
+        )
+    })?;
+    match unsafe { libc::setns(file.as_raw_fd(), libc::CLONE_NEWNET) } {
+        0 => Ok(()),
+        _ => {
+            let e = io::Error::last_os_error();
+            match e.kind() {
+                io::ErrorKind::PermissionDenied => Err(io::Error::new(
+                    e.kind(),
+                    "Entering a network namespace requires CAP_SYS_ADMIN",
+                )),
+                _ => Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn enter_netns(_name: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Network namespaces are only supported on Linux",
+    ))
+}
+
+fn parse_qps(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        // The period between queries must fit a duration
+        Ok(qps)
+            if qps > 0.0 && qps.is_finite() && Duration::try_from_secs_f64(1.0 / qps).is_ok() =>
+        {
+            Ok(qps)
+        }
+        _ => Err(format!("invalid rate {}", s)),
+    }
+}
+
+/// Represents bytes given in hex.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Hex(Vec<u8>);
+
+impl FromStr for Hex {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_hex(s).map(Hex)
+    }
+}
+
+/// Parses the hex of bytes, which may be separated by whitespaces.
+fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    let digits = s
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| c.to_digit(16).map(|digit| digit as u8))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| format!("invalid hex {}", s))?;
+    if digits.len() % 2 != 0 {
+        return Err(format!("odd number of hex digits {}", s));
+    }
+
+    Ok(digits
+        .chunks(2)
+        .map(|pair| pair[0] << 4 | pair[1])
+        .collect())
+}
+
+fn parse_best_of(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("invalid number of queries {}", s)),
+    }
+}
+
+fn parse_size(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(size) if size <= MAX_QUERY_SIZE => Ok(size),
+        Ok(_) => Err(format!(
+            "invalid size {}, expected at most {}",
+            s, MAX_QUERY_SIZE
+        )),
+        Err(_) => Err(format!("invalid size {}", s)),
+    }
+}
+
+fn parse_buffer_size(s: &str) -> Result<usize, String> {
+    // The size is set as an `int` socket option
+    match s.parse::<usize>() {
+        Ok(size) if size <= i32::MAX as usize => Ok(size),
+        _ => Err(format!("invalid buffer size {}", s)),
+    }
+}
+
+fn parse_wait_for(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("invalid number of replies {}", s)),
+    }
+}
+
+fn record_reply(stats: &mut Statistics, reply: &Reply, flags: &Flags) {
+    stats.recv += 1;
+    let duration = reply.duration.as_micros() as u64;
+    stats.latency_total += duration;
+    stats.latency_max = stats.latency_max.max(duration);
+    stats.latency_min = stats.latency_min.min(duration);
+    match validate(&reply.addresses, flags.validate) {
+        Some(true) => {
+            stats.correct += 1;
+        }
+        Some(false) => {
+            stats.incorrect += 1;
+        }
+        None => {}
+    }
+    if matches!(flags.threshold_ms, Some(threshold) if duration > threshold * 1000) {
+        stats.over_threshold += 1;
+    }
+    if flags.percentiles || flags.histogram || flags.prometheus_file.is_some() {
+        stats.latencies.push(duration);
+    }
+    let bucket = RTT_BUCKETS.partition_point(|&bound| bound < duration);
+    stats.rtt_buckets[bucket] += 1;
+    if flags.summary_interval != 0 {
+        stats.samples.push_back((Instant::now(), Some(duration)));
+    }
+}
+
+/// Prints the events of pinging a server, and records them in the statistics of the server.
+struct Printer {
+    name: String,
+    addr: SocketAddr,
+    hosts: Vec<String>,
+    transport: &'static str,
+    flags: Flags,
+    stats: Arc<Mutex<Statistics>>,
+    colored: bool,
+    multiple: bool,
+    recorded: Option<usize>,
+    rung: Option<usize>,
+    reachable: Option<bool>,
+    warned: bool,
+    warned_truncated: bool,
+    ttls: HashMap<String, (u32, Instant)>,
+    answer_counts: HashMap<String, u16>,
+    out: Box<dyn Write + Send>,
+}
+
+impl Printer {
+    fn new(
+        name: &str,
+        pinger: &Pinger,
+        hosts: &[String],
+        flags: &Flags,
+        multiple: bool,
+    ) -> Printer {
+        Printer {
+            name: String::from(name),
+            addr: pinger.addr(),
+            hosts: hosts.to_vec(),
+            transport: pinger.transport(),
+            flags: flags.clone(),
+            stats: Arc::new(Mutex::new(Statistics::new())),
+            colored: !flags.csv && flags.color.is_enabled(),
+            multiple,
+            recorded: None,
+            rung: None,
+            reachable: None,
+            warned: false,
+            warned_truncated: false,
+            ttls: HashMap::new(),
+            answer_counts: HashMap::new(),
+            out: Box::new(io::stdout()),
+        }
+    }
+
+    /// Prints the line of an event to the output, which is the standard output.
+    fn println(&mut self, line: &str) {
+        let _ = writeln!(self.out, "{}", line);
+    }
+
+    fn handle(&mut self, event: PingEvent) {
+        self.record(&event);
+        self.print(&event);
+    }
+
+    /// Records the event in the statistics, except for the warmup queries.
+    fn record(&mut self, event: &PingEvent) {
+        let mut stats = self.stats.lock().unwrap();
+        match event {
+            PingEvent::Reply(query, _) | PingEvent::Timeout(query) | PingEvent::Error(query, _)
+                if query.warmup => {}
+            PingEvent::Reply(query, reply) => {
+                if is_suspicious(&self.flags, query, reply) {
+                    stats.suspicious += 1;
+                }
+                if let Some(connect) = reply.connect {
+                    stats.connects += 1;
+                    stats.connect_total += connect.as_micros() as u64;
+                }
+                if self.flags.mdns {
+                    stats.responders.insert(reply.source);
+                }
+
+                // Only the first reply of a query to a multicast server is counted
+                if self.recorded != Some(query.seq) {
+                    self.recorded = Some(query.seq);
+                    stats.send += 1;
+                    if reply.out_of_order {
+                        stats.out_of_order += 1;
+                    }
+                    record_reply(&mut stats, reply, &self.flags);
+                }
+            }
+            PingEvent::Duplicate(_, _) => {
+                stats.duplicates += 1;
+            }
+            PingEvent::Timeout(_) | PingEvent::Error(_, _) => {
+                if let PingEvent::Error(_, e) = event {
+                    record_error(&mut stats, e);
+                }
+                stats.send += 1;
+                if self.flags.summary_interval != 0 {
+                    stats.samples.push_back((Instant::now(), None));
+                }
+            }
+            PingEvent::Warning(e) => record_error(&mut stats, e),
+            // The query is counted as lost in the partial statistics
+            PingEvent::Abort(Some(query), _) if !query.warmup => {
+                stats.send += 1;
+            }
+            PingEvent::Abort(_, _) | PingEvent::Backoff(_, _) | PingEvent::Summary(_) => {}
+        }
+    }
+
+    fn print(&mut self, event: &PingEvent) {
+        match event {
+            PingEvent::Reply(query, reply) => {
+                self.print_reply(query, reply);
+                self.ring(query.seq, true);
+            }
+            PingEvent::Duplicate(seq, reply) => self.print_duplicate(*seq, reply),
+            PingEvent::Timeout(query) => {
+                self.print_failure(query, "timed out");
+                self.ring(query.seq, false);
+            }
+            PingEvent::Error(query, e) => {
+                let response_error = e.get_ref().and_then(|e| e.downcast_ref::<ResponseError>());
+                let message = match (e.kind(), response_error) {
+                    (_, Some(response_error)) => response_error.to_string(),
+                    (io::ErrorKind::ConnectionRefused, _)
+                    | (io::ErrorKind::ConnectionReset, _)
+                    | (io::ErrorKind::HostUnreachable, _)
+                    | (io::ErrorKind::NetworkUnreachable, _) => {
+                        String::from("Destination unreachable")
+                    }
+                    _ => e.to_string(),
+                };
+                self.print_failure(query, &message);
+                self.ring(query.seq, false);
+            }
+            PingEvent::Backoff(interval, failures) => match failures {
+                0 => eprintln!("{}: Recovered to {} ms", self.name, interval.as_millis()),
+                _ => eprintln!(
+                    "{}: Backing off to {} ms after {} failures",
+                    self.name,
+                    interval.as_millis(),
+                    failures
+                ),
+            },
+            PingEvent::Abort(_, e) => print_first_error(self.addr, e),
+            PingEvent::Warning(e) => {
+                let response_error = e.get_ref().and_then(|e| e.downcast_ref::<ResponseError>());
+                if let Some(ResponseError::Foreign(source)) = response_error {
+                    if !self.warned {
+                        eprintln!(
+                            "Ignored a reply from an unexpected source {}, try --accept-any-source",
+                            source
+                        );
+                        self.warned = true;
+                    }
+                }
+            }
+            PingEvent::Summary(_) => {}
+        }
+    }
+
+    fn print_reply(&mut self, query: &Query, reply: &Reply) {
+        let flags = &self.flags;
+        let seq = query.seq;
+        let host = &self.hosts[seq % self.hosts.len()];
+        if reply.source != self.addr && !flags.mdns && !self.warned {
+            eprintln!(
+                "Received a reply from an unexpected source {}",
+                reply.source
+            );
+            self.warned = true;
+        }
+        // Large responses like the ones of ANY queries are truncated over UDP
+        if reply.truncated && !self.warned_truncated && self.transport == "UDP" {
+            eprintln!(
+                "The reply of {} is truncated, try --tcp for the full one",
+                query.host
+            );
+            self.warned_truncated = true;
+        }
+        // A reply without the expected address fails even if it succeeds
+        let incorrect = validate(&reply.addresses, flags.validate) == Some(false);
+
+        if flags.csv {
+            let row = format!(
+                "{},{:.3},{},{:.3},{},{},{}",
+                seq,
+                timestamp(query),
+                self.addr,
+                reply.duration.as_micros() as f64 / 1000.0,
+                reply.size,
+                rcode_to_string(reply.rcode),
+                !incorrect
+            );
+            self.println(&row);
+            return;
+        }
+
+        let from = match flags.mdns {
+            true => reply.source.to_string(),
+            false => self.name.clone(),
+        };
+        let mut line = format!(
+            "{} bytes from {}: seq={} id={}",
+            reply.size, from, seq, reply.id
+        );
+        if self.hosts.len() > 1 || flags.randomize_subdomain || flags.randomize_case {
+            line.push_str(&format!(" host={}", query.host));
+        }
+        if flags.show_ttl {
+            match reply.ttl {
+                Some(ttl) => {
+                    line.push_str(&format!(" ttl={}", ttl));
+
+                    // A TTL not decreasing over a second indicates the record is fetched freshly
+                    // instead of from the cache
+                    let now = Instant::now();
+                    if let Some((last_ttl, last)) = self.ttls.get(host) {
+                        if ttl >= *last_ttl && now.duration_since(*last) >= Duration::from_secs(1) {
+                            line.push_str(" (fresh)");
+                        }
+                    }
+                    self.ttls.insert(host.clone(), (ttl, now));
+                }
+                None => line.push_str(" ttl=-"),
+            }
+        }
+        if flags.show_counts {
+            line.push_str(&format!(
+                " an={} ns={} ar={}",
+                reply.answers, reply.nameservers, reply.additional
+            ));
+
+            // A changing number of answers indicates the pool of a load balancer changes
+            if !flags.mdns {
+                let last = self.answer_counts.insert(host.clone(), reply.answers);
+                if matches!(last, Some(last) if last != reply.answers) {
+                    line.push_str(" (answers changed)");
+                }
+            }
+        }
+        if flags.ad || flags.dnssec {
+            line.push_str(&format!(" ad={}", reply.authenticated_data as u8));
+        }
+        if flags.nsid {
+            match &reply.nsid {
+                Some(nsid) => line.push_str(&format!(" nsid={}", format_nsid(nsid))),
+                None => line.push_str(" nsid=-"),
+            }
+        }
+        if reply.rcode != ResponseCode::NoError {
+            line.push_str(&format!(" rcode={}", rcode_to_string(reply.rcode)));
+        }
+        if let Some((cpu, os)) = &reply.hinfo {
+            line.push_str(&format!(" hinfo={}", format_hinfo(cpu, os)));
+        }
+        if let Some(connect) = reply.connect {
+            line.push_str(&format!(
+                " connect={:.2} ms",
+                connect.as_micros() as f64 / 1000.0
+            ));
+        }
+        match flags.verbose {
+            true => {
+                // The first byte of a datagram arrives with the whole one
+                let first_byte = reply.first_byte.unwrap_or(reply.duration);
+                line.push_str(&format!(
+                    " ttfb={:.2} ms total={:.2} ms",
+                    first_byte.as_micros() as f64 / 1000.0,
+                    reply.duration.as_micros() as f64 / 1000.0
+                ));
+            }
+            false => line.push_str(&format!(
+                " time={:.2} ms",
+                reply.duration.as_micros() as f64 / 1000.0
+            )),
+        }
+        if reply.truncated {
+            line.push_str(" (truncated)");
+        }
+        if reply.out_of_order {
+            line.push_str(" (out of order)");
+        }
+        if is_suspicious(flags, query, reply) {
+            line.push_str(" (suspicious)");
+        }
+        if is_any_refused(&reply.hinfo) {
+            line.push_str(" (ANY refused)");
+        }
+        if incorrect {
+            line.push_str(" (incorrect)");
+        }
+        if query.warmup {
+            line.push_str(" (warmup)");
+        }
+        let color = match incorrect {
+            true => RED,
+            false => reply_color(reply.rcode, reply.duration, flags.warn_ms, flags.crit_ms),
+        };
+        let line = paint(&line, color, self.colored);
+        self.println(&line);
+    }
+
+    fn print_duplicate(&mut self, seq: Option<usize>, reply: &Reply) {
+        if self.flags.csv {
+            return;
+        }
+        let seq = match seq {
+            Some(seq) => seq.to_string(),
+            None => String::from("-"),
+        };
+        let line = format!(
+            "{} bytes from {}: seq={} id={} time={:.2} ms (DUP!)",
+            reply.size,
+            self.name,
+            seq,
+            reply.id,
+            reply.duration.as_micros() as f64 / 1000.0
+        );
+        let line = paint(&line, YELLOW, self.colored);
+        self.println(&line);
+    }
+
+    fn print_failure(&mut self, query: &Query, message: &str) {
+        if self.flags.csv {
+            let row = format!(
+                "{},{:.3},{},,,,false",
+                query.seq,
+                timestamp(query),
+                self.addr
+            );
+            self.println(&row);
+            return;
+        }
+        let mut line = format!("{} seq={}", message, query.seq);
+        if self.multiple {
+            line = format!("{}: {}", self.name, line);
+        }
+        if query.warmup {
+            line.push_str(" (warmup)");
+        }
+        let line = paint(&line, RED, self.colored);
+        self.println(&line);
+    }
+
+    /// Rings the bell once for each query, except for the machine-readable output.
+    fn ring(&mut self, seq: usize, success: bool) {
+        if self.rung == Some(seq) {
+            return;
+        }
+        self.rung = Some(seq);
+        let bell = !self.flags.csv
+            && match self.reachable {
+                Some(reachable) if self.flags.audible_transition => reachable != success,
+                _ => self.flags.audible && success,
+            };
+        if bell {
+            eprint!("\x07");
+        }
+        self.reachable = Some(success);
+    }
+}
+
+/// Returns the time the query is sent in seconds since the Unix epoch.
+fn timestamp(query: &Query) -> f64 {
+    query
+        .timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// Returns whether the reply does not echo the case of the host, which may be spoofed.
+fn is_suspicious(flags: &Flags, query: &Query, reply: &Reply) -> bool {
+    flags.randomize_case
+        && matches!(&reply.question, Some(question)
+            if !dnsping::is_case_echoed(&query.host, question))
+}
+
+/// Records the error of a failed query or of the transport, which is a malformed response or
+/// caused by the network.
+fn record_error(stats: &mut Statistics, e: &io::Error) {
+    match e.get_ref().and_then(|e| e.downcast_ref::<ResponseError>()) {
+        Some(ResponseError::Malformed(_)) => stats.malformed += 1,
+        Some(ResponseError::Foreign(_)) => stats.foreign += 1,
+        None => stats.unreachable += 1,
+    };
+}
+
+/// Returns the configuration of pinging the server with the pinger for the hosts.
+fn ping_config(pinger: Pinger, hosts: &[String], flags: &Flags) -> PingConfig {
+    PingConfig {
+        hosts: hosts.to_vec(),
+        interval: Duration::from_millis(flags.interval),
+        jitter: Duration::from_millis(flags.jitter),
+        count: flags.count.limit(),
+        count_received: flags.count_mode == CountMode::Received,
+        wait_for: flags.wait_for,
+        warmup: flags.warmup,
+        rate: flags.qps,
+        best_of: flags.best_of,
+        backoff: match flags.backoff {
+            true => Some(Duration::from_millis(flags.backoff_max)),
+            false => None,
+        },
+        randomize_subdomain: flags.randomize_subdomain,
+        randomize_case: flags.randomize_case,
+        drain: flags.wait_all,
+        ..PingConfig::new(pinger)
+    }
+}
+
+fn main() {
     // Parse arguments
-    let flags = Flags::from_args();
-    let proxy = match &flags.proxy {
-        Some(proxy) => match flags.server {
-            IpAddr::V4(server) => match proxy.addr_v4() {
-                Some(addr_v4) => Some(SocketAddr::V4(addr_v4)),
-                None => {
+    let matches = Flags::clap().get_matches();
+    let mut flags = Flags::from_clap(&matches);
+    let mut servers = flags.servers.clone();
+    if let Some(path) = &flags.servers_file {
+        let s = match fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(ref e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        };
+        match parse_servers(&s, flags.strict) {
+            Ok(v) => servers.extend(v),
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        }
+    }
+    let mut query_type = None;
+    if let Some(path) = &flags.config {
+        let s = match fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(ref e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        };
+        let config = match config::parse(&s) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        };
+
+        // Options given in the command line take precedence
+        if let Some(interval) = config.interval {
+            if matches.occurrences_of("interval") == 0 {
+                flags.interval = interval;
+            }
+        }
+        if let Some(timeout) = config.timeout {
+            if matches.occurrences_of("timeout") == 0 {
+                flags.timeout = timeout;
+            }
+        }
+        if let Some(host) = config.host {
+            if matches.occurrences_of("host") == 0 && flags.hostfile.is_none() {
+                flags.host = vec![host];
+            }
+        }
+        query_type = config.query_type;
+        servers.extend(config.targets);
+    }
+    if flags.mdns && servers.is_empty() {
+        let group = match flags.ipv6 {
+            true => IpAddr::V6(MDNS_V6),
+            false => IpAddr::V4(MDNS_V4),
+        };
+        servers.push(Target::new(group));
+    }
+    let unix = flags.unix.as_ref().or(flags.unix_socket.as_ref());
+    if unix.is_some() && servers.is_empty() {
+        servers.push(Target::new(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+    }
+    if servers.is_empty() {
+        eprintln!("No server is given");
+        return;
+    }
+    // Each server is pinged on its own socket, which cannot share the port
+    if flags.source_port != 0 && servers.len() > 1 {
+        eprintln!(
+            "The source port {} cannot be bound for multiple servers",
+            flags.source_port
+        );
+        return;
+    }
+    // A Unix socket is a single local resolver
+    if unix.is_some() && servers.len() > 1 {
+        eprintln!("A Unix socket cannot be used for multiple servers");
+        return;
+    }
+    if flags.ipv4 || flags.ipv6 {
+        if let Some(target) = servers
+            .iter()
+            .find(|target| target.server.is_ipv6() != flags.ipv6)
+        {
+            let family = match flags.ipv6 {
+                true => "IPv6",
+                false => "IPv4",
+            };
+            eprintln!("The server {} is not an {} address", target.server, family);
+            return;
+        }
+    }
+    let mut proxy = flags.proxy.clone().or_else(|| flags.http_proxy.clone());
+    if let Some(proxy) = &mut proxy {
+        if flags.numeric {
+            if !proxy.is_resolved() {
+                eprintln!("The proxy {} is not a numeric address", proxy);
+                return;
+            }
+        } else if let Err(ref e) = proxy.resolve() {
+            eprintln!("{}", e);
+            return;
+        }
+    }
+    if flags.histogram && flags.hist_bucket_ms == 0 {
+        eprintln!("The width of each bucket of the histogram cannot be 0");
+        return;
+    }
+    if flags.mdns && flags.timeout == 0 {
+        eprintln!("A timeout is required to collect replies of mDNS");
+        return;
+    }
+    if flags.best_of.is_some() && flags.timeout == 0 {
+        eprintln!("A timeout is required to collect replies of --best-of");
+        return;
+    }
+    // The interval is backed off exponentially, which stays 0
+    if flags.backoff && flags.interval == 0 {
+        eprintln!("An interval is required to back off from");
+        return;
+    }
+    // The opcode has a default value, so its conflict with a payload is checked here
+    if (flags.payload_hex.is_some() || flags.payload_file.is_some())
+        && matches.occurrences_of("opcode") != 0
+    {
+        eprintln!("The opcode cannot be set with a raw payload");
+        return;
+    }
+    let hosts: Vec<String> = match &flags.hostfile {
+        Some(path) => match fs::read_to_string(path) {
+            Ok(s) => s
+                .lines()
+                .map(|line| line.trim())
+                .filter(|line| !line.is_empty())
+                .map(String::from)
+                .collect(),
+            Err(ref e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        },
+        None => flags.host.clone(),
+    };
+    if hosts.is_empty() {
+        eprintln!("No host is given");
+        return;
+    }
+    // Hosts are queried and shown in their ASCII forms on the wire
+    let hosts = match hosts
+        .iter()
+        .map(|host| idna::to_ascii(host))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(hosts) => hosts,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+
+    // Enter the network namespace before binding sockets
+    if let Some(netns) = &flags.netns {
+        if let Err(ref e) = enter_netns(netns) {
+            eprintln!("{}", e);
+            return;
+        }
+    }
+
+    // The raw payload replaces the queries built
+    let payload = match (&flags.payload_hex, &flags.payload_file) {
+        (Some(Hex(payload)), _) => Some(payload.clone()),
+        (None, Some(path)) => match fs::read(path) {
+            Ok(payload) => Some(payload),
+            Err(ref e) => {
+                eprintln!("{}: {}", path.display(), e);
+                return;
+            }
+        },
+        (None, None) => None,
+    };
+
+    // Open the capture, which is shared by all the pingers
+    let pcap = match (&flags.write_pcap, flags.dry_run) {
+        (Some(path), false) => match fs::File::create(path).and_then(PcapWriter::new) {
+            Ok(pcap) => Some(Arc::new(Mutex::new(pcap))),
+            Err(ref e) => {
+                eprintln!("{}: {}", path.display(), e);
+                return;
+            }
+        },
+        _ => None,
+    };
+
+    // Bind sockets
+    let label_len = match flags.randomize_subdomain {
+        true => RANDOM_LABEL_LEN + 1,
+        false => 0,
+    };
+    let mut warned_oversized = false;
+    let mut targets = Vec::new();
+    for target in servers.iter() {
+        let server = &target.server;
+        let proxies = match &proxy {
+            Some(proxy) => {
+                let addrs = proxy
+                    .addrs()
+                    .iter()
+                    .filter(|addr| addr.is_ipv6() == server.is_ipv6())
+                    .map(|addr| Some(*addr));
+                let proxies: Vec<_> = match flags.all_addresses {
+                    true => addrs.collect(),
+                    false => addrs.take(1).collect(),
+                };
+                if proxies.is_empty() {
                     eprintln!(
                         "The IP protocol numbers of the server {} and the proxy {} do not match",
                         server, proxy
                     );
                     return;
                 }
+
+                proxies
+            }
+            None => vec![None],
+        };
+        // The scheme and the port of a server URL override the flags
+        let port = match (flags.mdns, target.port) {
+            (true, _) => MDNS_PORT,
+            (false, Some(port)) => port,
+            (false, None) => flags.port,
+        };
+        let tcp = match target.transport {
+            Some(transport) => transport == Transport::Tcp,
+            None => flags.tcp,
+        };
+        let addr = match server {
+            IpAddr::V4(server) => SocketAddr::V4(SocketAddrV4::new(*server, port)),
+            IpAddr::V6(server) => {
+                SocketAddr::V6(SocketAddrV6::new(*server, port, 0, target.scope_id))
+            }
+        };
+
+        let name = match (unix, flags.resolve_names) {
+            (Some(path), _) => path.display().to_string(),
+            (None, true) => match dns_lookup::lookup_addr(server) {
+                Ok(name) => format!("{} ({})", name, addr),
+                Err(_) => addr.to_string(),
             },
-            IpAddr::V6(server) => match proxy.addr_v6() {
-                Some(addr_v6) => Some(SocketAddr::V6(addr_v6)),
-                None => {
-                    eprintln!(
-                        "The IP protocol numbers of the server {} and the proxy {} do not match",
-                        server, proxy
-                    );
+            (None, false) => addr.to_string(),
+        };
+
+        // Each query type is pinged separately with its own statistics
+        let query_types = match (target.query_type, flags.query_types.is_empty()) {
+            (Some(query_type), _) => vec![Some(query_type)],
+            (None, false) => flags.query_types.iter().copied().map(Some).collect(),
+            (None, true) => vec![query_type],
+        };
+        let hosts = match &target.host {
+            Some(host) => match idna::to_ascii(host) {
+                Ok(host) => vec![host],
+                Err(e) => {
+                    eprintln!("{}", e);
                     return;
                 }
             },
-        },
-        None => None,
-    };
-    let addr = SocketAddr::new(flags.server, flags.port);
+            None => hosts.clone(),
+        };
 
-    // Bind socket
-    let local: SocketAddr = match flags.server {
-        IpAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
-        IpAddr::V6(_) => "[::]:0".parse().unwrap(),
-    };
-    let rw: Box<dyn RW> = match proxy {
-        Some(proxy) => {
-            let auth = match flags.username.clone() {
-                Some(username) => Some((username, flags.password.clone().unwrap())),
-                None => None,
-            };
-            match Datagram::bind(proxy, local, auth) {
-                Ok(datagram) => Box::new(datagram),
+        for (query_type, proxy) in query_types
+            .iter()
+            .flat_map(|query_type| proxies.iter().map(move |proxy| (*query_type, *proxy)))
+        {
+            let mut pinger = Pinger::new(addr)
+                .recursion_desired(!flags.iterate && !flags.no_recurse)
+                .opcode(flags.opcode)
+                .tos(flags.tos.or_else(|| flags.dscp.map(|dscp| dscp << 2)))
+                .nsid(flags.nsid)
+                .authenticated_data(flags.ad || flags.dnssec)
+                .checking_disabled(flags.cd)
+                .dnssec_ok(flags.dnssec)
+                .recv_buffer_size(flags.rcvbuf)
+                .send_buffer_size(flags.sndbuf)
+                .accept_any_source(flags.accept_any_source)
+                .tcp(tcp)
+                .multicast(flags.mdns)
+                .source_port(flags.source_port)
+                .interface(flags.interface.clone())
+                .random_source_port(flags.random_source_port)
+                .dual_stack(flags.dual_stack)
+                .payload(payload.clone())
+                .random_id(flags.random_id)
+                .size(flags.size)
+                .report_malformed(true);
+            let mut name = name.clone();
+            if let Some(proxy) = proxy {
+                let auth = match flags.username.clone() {
+                    Some(username) => Some((username, flags.password.clone().unwrap())),
+                    None => None,
+                };
+                pinger = match flags.http_proxy {
+                    Some(_) => pinger.http_proxy(proxy, auth),
+                    None => pinger.proxy(proxy, auth),
+                };
+                if flags.all_addresses {
+                    name = format!("{} via {}", name, proxy);
+                }
+            }
+            if let (true, Some(query_type)) = (query_types.len() > 1, query_type) {
+                name = format!("{} {:?}", name, query_type);
+            }
+            if let Some(path) = unix {
+                pinger = pinger.unix(path).tcp(flags.unix_socket.is_some());
+            }
+            if let Some(pcap) = &pcap {
+                pinger = pinger.pcap(Arc::clone(pcap));
+            }
+            if flags.timeout != 0 {
+                pinger = pinger.timeout(Some(Duration::from_millis(flags.timeout)));
+            }
+
+            if let Some(query_type) = query_type {
+                pinger = pinger.query_type(query_type);
+            }
+            // A host which cannot be queried is reported with the reason before pinging
+            let queries = match hosts
+                .iter()
+                .map(|host| pinger.build_query(host))
+                .collect::<io::Result<Vec<_>>>()
+            {
+                Ok(queries) => queries,
                 Err(ref e) => {
                     eprintln!("{}", e);
                     return;
                 }
+            };
+            if !tcp && flags.unix_socket.is_none() && !warned_oversized {
+                if let Some((host, _)) = hosts
+                    .iter()
+                    .zip(queries.iter())
+                    .find(|(_, query)| query.len() + label_len > MAX_UDP_SIZE)
+                {
+                    eprintln!(
+                        "The query of {} exceeds {} bytes and may be dropped by the server, try --tcp",
+                        host, MAX_UDP_SIZE
+                    );
+                    warned_oversized = true;
+                }
             }
-        }
-        None => match Socket::bind(local) {
-            Ok(socket) => Box::new(socket),
-            Err(ref e) => {
-                eprintln!("{}", e);
+            if flags.dry_run {
+                for (host, query) in hosts.iter().zip(queries.iter()) {
+                    print_query(&name, host, query);
+                }
+                continue;
+            }
+            if let Err(ref e) = pinger.bind() {
+                print_first_error(addr, e);
                 return;
             }
-        },
-    };
-    if flags.timeout != 0 {
-        if let Err(ref e) = rw.set_read_timeout(Some(Duration::from_millis(flags.timeout))) {
-            eprintln!("{}", e);
-            return;
+
+            targets.push((pinger, name, hosts.clone()));
         }
     }
 
+    if flags.dry_run {
+        return;
+    }
+
     // Handle Ctrl+C
-    let (tx, rx) = mpsc::channel::<()>();
+    let (tx, rx) = mpsc::channel::<Event>();
     let tx_cloned = tx.clone();
     ctrlc::set_handler(move || {
-        let _ = tx_cloned.send(());
+        let _ = tx_cloned.send(Event::Interrupted);
     })
     .unwrap();
 
     // Ping
-    let send = Arc::new(AtomicUsize::new(0));
-    let send_cloned = Arc::clone(&send);
-    let recv = Arc::new(AtomicUsize::new(0));
-    let recv_cloned = Arc::clone(&recv);
-    let latency_total = Arc::new(AtomicU64::new(0));
-    let latency_total_cloned = Arc::clone(&latency_total);
-    let latency_min = Arc::new(AtomicU64::new(u64::MAX));
-    let latency_min_cloned = Arc::clone(&latency_min);
-    let latency_max = Arc::new(AtomicU64::new(0));
-    let latency_max_cloned = Arc::clone(&latency_max);
-    thread::spawn(move || {
-        // Psuedo DNS query
-        let is_ipv6 = match flags.server {
-            IpAddr::V4(_) => false,
-            IpAddr::V6(_) => true,
-        };
-        let mut query = Builder::new_query(0, true);
-        if is_ipv6 {
-            query.add_question(&flags.host, false, QueryType::AAAA, QueryClass::IN);
-        } else {
-            query.add_question(&flags.host, false, QueryType::A, QueryClass::IN);
-        }
-        let buffer = match query.build() {
-            Ok(buffer) => buffer,
-            Err(_) => {
-                eprintln!("{}", io::Error::from(io::ErrorKind::InvalidData));
-                let _ = tx.send(());
-                return;
-            }
+    let start = Instant::now();
+    let prefix = match flags.csv {
+        true => "# ",
+        false => "",
+    };
+    if flags.csv {
+        println!("seq,timestamp,server,rtt_ms,size,rcode,success");
+    }
+    let multiple = targets.len() > 1;
+    let mut printers = Vec::new();
+    let mut configs = Vec::new();
+    for (pinger, name, hosts) in targets {
+        // Psuedo DNS query, whose question is the one of the payload if any
+        let query = pinger.build_query(&hosts[0]).unwrap_or_default();
+        let question = match &payload {
+            Some(_) => question_name(&query).unwrap_or_else(|| hosts.join(", ")),
+            None => hosts.join(", "),
         };
-        println!(
-            "PING {} for {} {} bytes of data.",
-            addr,
-            flags.host,
-            buffer.len()
-        );
-
-        loop {
-            let id = send
-                .fetch_add(1, Ordering::Relaxed)
-                .checked_add(1)
-                .unwrap_or(0);
-            let instant = Instant::now();
-
-            // Ping
-            match lib::ping(&rw, addr, id as u16, flags.iterate, &flags.host) {
-                Ok((size, duration)) => {
-                    println!(
-                        "{} bytes from {}: id={} time={:.2} ms",
-                        size,
-                        addr,
-                        id,
+        if !flags.csv {
+            println!(
+                "PING {} for {} {} {} bytes of data.",
+                name,
+                question,
+                describe_query(&query, pinger.transport()),
+                query.len() + label_len
+            );
+        }
+        printers.push(Printer::new(&name, &pinger, &hosts, &flags, multiple));
+        configs.push(ping_config(pinger, &hosts, &flags));
+    }
+    let statistics = printers
+        .iter()
+        .map(|printer| {
+            (
+                printer.name.clone(),
+                printer.hosts.join(","),
+                Arc::clone(&printer.stats),
+            )
+        })
+        .collect::<Vec<_>>();
+    // Each pinging thread is stopped on interrupt
+    let mut stops = Vec::new();
+    if flags.race {
+        let (stop_tx, stop_rx) = mpsc::channel();
+        stops.push(stop_tx);
+        let tx = tx.clone();
+        let colored = flags.color.is_enabled();
+        thread::spawn(move || {
+            dnsping::race(configs, stop_rx, |event| match event {
+                RaceEvent::Ping(i, event) => printers[i].record(&event),
+                RaceEvent::Round(seq, Some((i, duration))) => {
+                    printers[i].stats.lock().unwrap().wins += 1;
+                    let line = format!(
+                        "seq={} winner={} time={:.2} ms",
+                        seq,
+                        printers[i].name,
                         duration.as_micros() as f64 / 1000.0
                     );
+                    println!("{}", paint(&line, GREEN, colored));
+                }
+                RaceEvent::Round(seq, None) => {
+                    println!("{}", paint(&format!("No reply seq={}", seq), RED, colored))
+                }
+            });
+            let _ = tx.send(Event::Finished);
+        });
+    } else {
+        for (mut printer, config) in printers.into_iter().zip(configs) {
+            let (stop_tx, stop_rx) = mpsc::channel();
+            stops.push(stop_tx);
+            let tx = tx.clone();
+            thread::spawn(move || {
+                dnsping::run_events(config, stop_rx, |event| printer.handle(event));
+                let _ = tx.send(Event::Finished);
+            });
+        }
+    }
 
-                    recv.fetch_add(1, Ordering::Relaxed);
-                    let duration = duration.as_micros() as u64;
-                    latency_total.fetch_add(duration, Ordering::Relaxed);
-                    if latency_max.load(Ordering::Relaxed) < duration {
-                        latency_max.store(duration, Ordering::Relaxed);
-                    }
-                    if latency_min.load(Ordering::Relaxed) > duration {
-                        latency_min.store(duration, Ordering::Relaxed);
+    // Close gracefully
+    let mut remain = stops.len();
+    let summary_interval = Duration::from_secs(flags.summary_interval);
+    let mut next_summary = start + summary_interval;
+    let mut drain_deadline = None;
+    let mut interrupted = false;
+    while remain > 0 {
+        let deadline = match (flags.summary_interval, drain_deadline) {
+            (0, drain_deadline) => drain_deadline,
+            (_, Some(drain_deadline)) => Some(next_summary.min(drain_deadline)),
+            (_, None) => Some(next_summary),
+        };
+        let event = match deadline {
+            None => rx.recv().ok(),
+            Some(deadline) => {
+                match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(event) => Some(event),
+                    Err(RecvTimeoutError::Timeout) => {
+                        let now = Instant::now();
+                        if matches!(drain_deadline, Some(drain_deadline) if now >= drain_deadline) {
+                            break;
+                        }
+                        if flags.summary_interval != 0 && now >= next_summary {
+                            for (name, _, stats) in statistics.iter() {
+                                print_summary(name, &mut stats.lock().unwrap(), summary_interval);
+                            }
+                            write_prometheus_file(&statistics, &flags);
+                            next_summary += summary_interval;
+                        }
+                        continue;
                     }
+                    Err(RecvTimeoutError::Disconnected) => None,
+                }
+            }
+        };
+        match event {
+            Some(Event::Finished) => remain -= 1,
+            Some(Event::Interrupted) => {
+                // Wait for the replies in flight until the timeout unless interrupted again
+                if !flags.wait_all || interrupted {
+                    break;
+                }
+                interrupted = true;
+                for stop in stops.iter() {
+                    let _ = stop.send(());
+                }
+                if flags.timeout != 0 {
+                    drain_deadline = Some(Instant::now() + Duration::from_millis(flags.timeout));
                 }
-                Err(e) => match e.kind() {
-                    io::ErrorKind::TimedOut => {
-                        println!("{}", e);
-                    }
-                    _ => {
-                        eprintln!("{}", e);
-                        let _ = tx.send(());
-                        return;
-                    }
-                },
-            };
-
-            // Reach max send count
-            if id == flags.count {
-                let _ = tx.send(());
-                return;
             }
+            None => unreachable!(),
+        }
+    }
+    let elapsed = start.elapsed();
+    // The pinging threads are not joined, whose Unix sockets are not dropped before exiting
+    #[cfg(unix)]
+    if unix.is_some() {
+        let _ = dnsping::Unix::remove_temporary_paths();
+    }
+    // The capture stops at its first error, which does not fail the queries
+    if let (Some(pcap), Some(path)) = (&pcap, &flags.write_pcap) {
+        if let Some(e) = pcap.lock().unwrap().take_error() {
+            eprintln!("{}: {}", path.display(), e);
+        }
+    }
+    for (name, _, stats) in statistics.iter() {
+        print_statistics(name, &stats.lock().unwrap(), elapsed, prefix, &flags);
+    }
+    write_prometheus_file(&statistics, &flags);
+    exit_on_incorrect(&statistics);
+}
 
-            // Sleep until interval
-            let elapsed = instant.elapsed();
-            let remain = Duration::from_millis(flags.interval)
-                .checked_sub(Duration::from_millis(elapsed.as_millis() as u64))
-                .unwrap_or(Duration::from_millis(0));
-            thread::sleep(remain);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn best_of_parse() {
+        assert_eq!(parse_best_of("3"), Ok(3));
+        assert!(parse_best_of("0").is_err());
+        assert!(parse_best_of("-1").is_err());
+    }
+
+    #[test]
+    fn payload_conflicts() {
+        let payload = ["dnsping", "127.0.0.1", "--payload-hex", "abcd"];
+        assert!(Flags::from_iter_safe(&payload).is_ok());
+        for flag in &["--nsid", "--dnssec", "--no-recurse", "--iterate"] {
+            let args = payload.iter().chain(std::iter::once(flag));
+            assert!(Flags::from_iter_safe(args).is_err(), "{}", flag);
+        }
+        for (flag, value) in &[("--size", "128"), ("--type", "A")] {
+            let option = [*flag, *value];
+            let args = payload.iter().chain(&option);
+            assert!(Flags::from_iter_safe(args).is_err(), "{}", flag);
         }
-    });
+    }
 
-    // Close gracefully
-    match rx.recv() {
-        Ok(_) => {
-            let send = send_cloned.load(Ordering::Relaxed);
-            let recv = recv_cloned.load(Ordering::Relaxed);
-            let lost = send
-                .checked_sub(recv)
-                .unwrap_or_else(|| send + (usize::MAX - recv));
-            let loss_rate = match send {
-                0 => 0.0,
-                _ => (lost as f64) / (send as f64) * 100.0,
-            };
-            let latency_total = latency_total_cloned.load(Ordering::Relaxed);
-            let latency_avg = latency_total / send as u64;
-            let latency_min = latency_min_cloned.load(Ordering::Relaxed);
-            let latency_max = latency_max_cloned.load(Ordering::Relaxed);
+    #[test]
+    fn question_name_payload() {
+        let pinger = Pinger::new("127.0.0.1:53".parse().unwrap());
+        let query = pinger.build_query("example.com").unwrap();
+        assert_eq!(question_name(&query).unwrap(), "example.com");
+        assert_eq!(question_name(&[0xab, 0xcd]), None);
+    }
 
-            println!("--- {} ping statistics ---", addr);
-            println!(
-                "{} packets transmitted, {} received, {:.2}% packet loss",
-                send, recv, loss_rate
-            );
+    #[test]
+    fn size_parse() {
+        assert_eq!(parse_size("128"), Ok(128));
+        assert_eq!(parse_size("65507"), Ok(65507));
+        assert!(parse_size("65508").is_err());
+        assert!(parse_size("100000").is_err());
+    }
 
-            if recv != 0 {
-                println!(
-                    "rtt min/avg/max = {:.3}/{:.3}/{:.3} ms",
-                    latency_min as f64 / 1000.0,
-                    latency_avg as f64 / 1000.0,
-                    latency_max as f64 / 1000.0
-                );
-            }
+    #[test]
+    fn buffer_size_parse() {
+        assert_eq!(parse_buffer_size("65536"), Ok(65536));
+        assert_eq!(parse_buffer_size("2147483647"), Ok(i32::MAX as usize));
+        assert!(parse_buffer_size("2147483648").is_err());
+        assert!(parse_buffer_size("-1").is_err());
+    }
+
+    #[test]
+    fn wait_for_parse() {
+        let flags = Flags::from_iter(&["dnsping", "127.0.0.1", "--wait-for", "2"]);
+        assert_eq!(flags.wait_for, Some(2));
+        assert!(Flags::from_iter_safe(&["dnsping", "127.0.0.1", "--wait-for", "0"]).is_err());
+    }
+
+    #[test]
+    fn count_parse() {
+        assert_eq!("0".parse::<Count>().unwrap(), Count::Infinite);
+        assert_eq!("inf".parse::<Count>().unwrap(), Count::Infinite);
+        assert_eq!("infinite".parse::<Count>().unwrap(), Count::Infinite);
+        assert_eq!("3".parse::<Count>().unwrap(), Count::Finite(3));
+        assert!("-1".parse::<Count>().is_err());
+        assert!("three".parse::<Count>().is_err());
+        assert!("00".parse::<Count>().is_err());
+        assert!("03".parse::<Count>().is_err());
+        assert!("+3".parse::<Count>().is_err());
+        assert!("".parse::<Count>().is_err());
+    }
+
+    #[test]
+    fn percentile_nearest_rank() {
+        let sorted = (1..=100).collect::<Vec<u64>>();
+        assert_eq!(percentile(&sorted, 50.0), 50);
+        assert_eq!(percentile(&sorted, 90.0), 90);
+        assert_eq!(percentile(&sorted, 99.0), 99);
+        assert_eq!(percentile(&[7], 99.0), 7);
+    }
+
+    #[test]
+    fn histogram_buckets() {
+        let lines = histogram(&[1000, 2000, 6000, 12000, 13000, 14000], 5, 30);
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "  0-5 ms |############       2");
+        assert_eq!(lines[1], " 5-10 ms |######             1");
+        assert_eq!(lines[2], "10-15 ms |################## 3");
+        assert!(lines.iter().all(|line| line.len() == 30));
+    }
+
+    #[test]
+    fn histogram_empty_buckets() {
+        // Cache hits and misses leave the buckets between them empty
+        let lines = histogram(&[1000, 2000, 16000], 5, 20);
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "  0-5 ms |######## 2");
+        assert_eq!(lines[1], " 5-10 ms |         0");
+        assert_eq!(lines[2], "10-15 ms |         0");
+        assert_eq!(lines[3], "15-20 ms |####     1");
+    }
+
+    #[test]
+    fn histogram_overflow() {
+        // Outliers are collected by the last bucket instead of growing the histogram
+        let lines = histogram(&[1000, 2000, 100000, 3600000], 1, 30);
+        assert_eq!(lines.len(), MAX_HISTOGRAM_BUCKETS);
+        assert!(lines[0].contains("1-2 ms"));
+        assert!(lines[MAX_HISTOGRAM_BUCKETS - 1]
+            .trim_start()
+            .starts_with(">=40 ms"));
+        assert!(lines[MAX_HISTOGRAM_BUCKETS - 1].ends_with(" 2"));
+    }
+
+    #[test]
+    fn servers_parse() {
+        let s = "8.8.8.8\n\n# comment\n1.1.1.1 # inline\n::1\nfe80::1%2\n";
+        let servers = parse_servers(s, true).unwrap();
+        assert_eq!(servers.len(), 4);
+        assert_eq!(servers[1].server, "1.1.1.1".parse::<IpAddr>().unwrap());
+        assert_eq!(servers[2].server, "::1".parse::<IpAddr>().unwrap());
+        assert_eq!(servers[2].scope_id, 0);
+        assert_eq!(servers[3].scope_id, 2);
+
+        let s = "8.8.8.8\nmalformed\n1.1.1.1\n";
+        assert_eq!(parse_servers(s, false).unwrap().len(), 2);
+        assert!(parse_servers(s, true).unwrap_err().contains("line 2"));
+    }
+
+    #[test]
+    fn opcode_parse() {
+        assert_eq!(parse_opcode("query").unwrap(), Opcode::StandardQuery);
+        assert_eq!(parse_opcode("STATUS").unwrap(), Opcode::ServerStatusRequest);
+        assert_eq!(parse_opcode("notify").unwrap(), Opcode::Reserved(4));
+        assert!(parse_opcode("3").is_err());
+    }
+
+    #[test]
+    fn dscp_parse() {
+        assert_eq!(parse_dscp("ef").unwrap(), 46);
+        assert_eq!(parse_dscp("CS0").unwrap(), 0);
+        assert_eq!(parse_dscp("CS6").unwrap(), 48);
+        assert_eq!(parse_dscp("AF41").unwrap(), 34);
+        assert_eq!(parse_dscp("10").unwrap(), 10);
+        assert!(parse_dscp("AF44").is_err());
+        assert!(parse_dscp("64").is_err());
+    }
+
+    #[test]
+    fn resolvable_addr_parse() {
+        let addr = "127.0.0.1:1080".parse::<ResolvableSocketAddr>().unwrap();
+        assert!(addr.is_resolved());
+        assert_eq!(addr.addrs().len(), 1);
+        assert_eq!(addr.addr_v6(), None);
+
+        let addr = "localhost:1080".parse::<ResolvableSocketAddr>().unwrap();
+        assert!(!addr.is_resolved());
+        assert!("localhost".parse::<ResolvableSocketAddr>().is_err());
+
+        let addr = "[fe80::1%2]:1080".parse::<ResolvableSocketAddr>().unwrap();
+        assert_eq!(addr.addr_v6().unwrap().scope_id(), 2);
+    }
+
+    #[test]
+    fn nsid_format() {
+        assert_eq!(format_nsid(b"gpdns-fra"), "gpdns-fra");
+        assert_eq!(format_nsid(&[0x01, 0xab]), "01ab");
+    }
+
+    #[test]
+    fn edns_options_format() {
+        assert_eq!(format_edns_options(&[]), "");
+        assert_eq!(
+            format_edns_options(&[0, 3, 0, 0, 0, 12, 0, 2, 0, 0]),
+            "nsid padding=2"
+        );
+        assert_eq!(format_edns_options(&[0, 3, 0, 1, b'a']), "nsid=a");
+        assert_eq!(format_edns_options(&[0, 10, 0, 2, 1, 2]), "option10=2");
+        assert_eq!(format_edns_options(&[0, 3, 0, 0, 0, 10, 0, 8]), "nsid");
+    }
+
+    #[test]
+    fn query_describe() {
+        let query = Pinger::new("8.8.8.8:53".parse().unwrap())
+            .build_query("a.com")
+            .unwrap();
+        assert_eq!(describe_query(&query, "UDP"), "A IN UDP");
+        let query = Pinger::new("8.8.8.8:53".parse().unwrap())
+            .dnssec_ok(true)
+            .build_query("a.com")
+            .unwrap();
+        assert_eq!(describe_query(&query, "TCP"), "A IN TCP EDNS=4096 DO");
+        assert_eq!(describe_query(&[], "UDP"), "UDP");
+    }
+
+    #[test]
+    fn prometheus_format() {
+        let mut stats = Statistics::new();
+        stats.send = 2;
+        stats.recv = 1;
+        stats.latency_total = 1500;
+        stats.latencies.push(1500);
+        stats.rtt_buckets[2] = 1;
+        let s = prometheus_metrics(&[(
+            String::from("8.8.8.8:53"),
+            String::from("a\"b.com"),
+            Arc::new(Mutex::new(stats)),
+        )]);
+        let labels = r#"server="8.8.8.8:53",host="a\"b.com""#;
+        assert!(s.contains(&format!("dnsping_sent_total{{{}}} 2\n", labels)));
+        assert!(s.contains(&format!("dnsping_received_total{{{}}} 1\n", labels)));
+        assert!(s.contains(&format!(
+            "dnsping_rtt_seconds{{{},quantile=\"0.99\"}} 0.0015\n",
+            labels
+        )));
+        assert!(s.contains(&format!("dnsping_rtt_seconds_count{{{}}} 1\n", labels)));
+        assert!(s.contains(&format!(
+            "dnsping_rtt_histogram_seconds_bucket{{{},le=\"0.001\"}} 0\n",
+            labels
+        )));
+        assert!(s.contains(&format!(
+            "dnsping_rtt_histogram_seconds_bucket{{{},le=\"0.0025\"}} 1\n",
+            labels
+        )));
+        assert!(s.contains(&format!(
+            "dnsping_rtt_histogram_seconds_bucket{{{},le=\"+Inf\"}} 1\n",
+            labels
+        )));
+        assert!(s.contains(&format!(
+            "dnsping_rtt_histogram_seconds_count{{{}}} 1\n",
+            labels
+        )));
+        assert!(s.contains(&format!("dnsping_loss_ratio{{{}}} 0.5\n", labels)));
+    }
+
+    #[test]
+    fn netns_not_found() {
+        let e = enter_netns("dnsping-nonexistent").unwrap_err();
+        #[cfg(target_os = "linux")]
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        #[cfg(not(target_os = "linux"))]
+        assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn hex_parse() {
+        assert_eq!(parse_hex("12ab CD").unwrap(), vec![0x12, 0xab, 0xcd]);
+        assert!(parse_hex("123").is_err());
+        assert!(parse_hex("zz").is_err());
+    }
+
+    #[test]
+    fn qps_parse() {
+        assert_eq!(parse_qps("100").unwrap(), 100.0);
+        assert_eq!(parse_qps("0.5").unwrap(), 0.5);
+        assert!(parse_qps("0").is_err());
+        assert!(parse_qps("-1").is_err());
+        assert!(parse_qps("inf").is_err());
+        assert!(parse_qps("1e-30").is_err());
+    }
+
+    #[test]
+    fn audible_short() {
+        let flags = Flags::from_iter(&["dnsping", "127.0.0.1", "-a"]);
+        assert!(flags.audible);
+    }
+
+    #[test]
+    fn tcp_default_source_port() {
+        let flags = Flags::from_iter(&["dnsping", "127.0.0.1", "--tcp"]);
+        assert!(flags.tcp);
+        let flags =
+            Flags::from_iter_safe(&["dnsping", "127.0.0.1", "--tcp", "--source-port", "5300"]);
+        assert!(flags.is_err());
+    }
+
+    #[test]
+    fn query_types_parse() {
+        let flags = Flags::from_iter(&["dnsping", "127.0.0.1", "-t", "a,AAAA,mx"]);
+        assert_eq!(
+            flags.query_types,
+            vec![QueryType::A, QueryType::AAAA, QueryType::MX]
+        );
+        assert!(Flags::from_iter_safe(&["dnsping", "127.0.0.1", "-t", "a,b"]).is_err());
+    }
+
+    #[test]
+    fn prometheus_alias() {
+        let flags = Flags::from_iter(&["dnsping", "127.0.0.1", "--prometheus", "a.prom"]);
+        assert_eq!(flags.prometheus_file, Some(PathBuf::from("a.prom")));
+    }
+
+    #[test]
+    fn randomize_case_flag() {
+        let flags = Flags::from_iter(&["dnsping", "127.0.0.1", "--0x20"]);
+        assert!(flags.randomize_case);
+        let flags = Flags::from_iter_safe(&["dnsping", "127.0.0.1", "--0x20", "--mdns"]);
+        assert!(flags.is_err());
+    }
+
+    #[test]
+    fn bind_port_alias() {
+        let flags = Flags::from_iter(&["dnsping", "127.0.0.1", "--bind-port", "5300"]);
+        assert_eq!(flags.source_port, 5300);
+    }
+
+    #[test]
+    fn color_paint() {
+        assert_eq!("never".parse::<Color>().unwrap(), Color::Never);
+        assert!("sometimes".parse::<Color>().is_err());
+        assert_eq!(paint("ok", GREEN, true), "\x1b[32mok\x1b[0m");
+        assert_eq!(paint("ok", GREEN, false), "ok");
+    }
+
+    #[test]
+    fn reply_color_thresholds() {
+        let duration = Duration::from_millis(150);
+        let color =
+            |warn_ms, crit_ms| reply_color(ResponseCode::NoError, duration, warn_ms, crit_ms);
+        assert_eq!(color(None, None), GREEN);
+        assert_eq!(color(Some(100), None), YELLOW);
+        assert_eq!(color(Some(100), Some(150)), RED);
+        assert_eq!(color(Some(200), Some(300)), GREEN);
+        assert_eq!(
+            reply_color(ResponseCode::NameError, duration, None, None),
+            YELLOW
+        );
+    }
+
+    #[test]
+    fn threshold_format() {
+        assert_eq!(format_threshold(0, 0, 50), "0/0 replies over 50ms (0.00%)");
+        assert_eq!(format_threshold(1, 4, 50), "1/4 replies over 50ms (25.00%)");
+    }
+
+    #[test]
+    fn connect_format() {
+        assert_eq!(
+            format_connect(15200, 1, 4),
+            "connect avg = 15.200 ms, 3.800 ms amortized over 4 queries"
+        );
+        assert_eq!(
+            format_connect(0, 0, 0),
+            "connect avg = 0.000 ms, 0.000 ms amortized over 0 queries"
+        );
+    }
+
+    #[test]
+    fn validate_addresses() {
+        let expected = "93.184.216.34".parse::<IpAddr>().unwrap();
+        let other = "93.184.216.35".parse::<IpAddr>().unwrap();
+        assert_eq!(validate(&[other, expected], Some(expected)), Some(true));
+        assert_eq!(validate(&[other], Some(expected)), Some(false));
+        assert_eq!(validate(&[], Some(expected)), Some(false));
+        assert_eq!(validate(&[other], None), None);
+        assert_eq!(format_validation(3, 1), "3 correct, 1 incorrect");
+    }
+
+    #[test]
+    fn hinfo_format() {
+        assert_eq!(format_hinfo("RFC8482", ""), "RFC8482");
+        assert_eq!(format_hinfo("x86_64", "Linux"), "x86_64/Linux");
+        assert!(is_any_refused(&Some((
+            String::from("RFC8482"),
+            String::new()
+        ))));
+        assert!(!is_any_refused(&Some((
+            String::from("x86_64"),
+            String::from("Linux")
+        ))));
+        assert!(!is_any_refused(&None));
+    }
+
+    #[test]
+    fn statistics_empty() {
+        let flags = Flags::from_iter(&["dnsping", "127.0.0.1", "--percentiles", "--histogram"]);
+        print_statistics("a", &Statistics::new(), Duration::from_secs(0), "", &flags);
+        print_summary("a", &mut Statistics::new(), Duration::from_secs(1));
+        assert!(prometheus_metrics(&[(
+            String::from("a"),
+            String::from("b"),
+            Arc::new(Mutex::new(Statistics::new()))
+        )])
+        .contains("dnsping_loss_ratio{server=\"a\",host=\"b\"} 0\n"));
+    }
+
+    #[test]
+    fn loss_rate_completed() {
+        assert_eq!(loss_rate(0, 0), 0.0);
+        assert_eq!(loss_rate(4, 3), 25.0);
+        assert_eq!(loss_rate(3, 3), 0.0);
+    }
+
+    #[test]
+    fn statistics_completed() {
+        let pinger = Pinger::new("127.0.0.1:53".parse().unwrap());
+        let flags = Flags::from_iter(&["dnsping", "127.0.0.1"]);
+        let mut printer = Printer::new("127.0.0.1:53", &pinger, &flags.host, &flags, false);
+        let query = |seq, warmup| Query {
+            seq,
+            host: String::from("www.google.com"),
+            timestamp: std::time::SystemTime::now(),
+            warmup,
+        };
+        printer.record(&PingEvent::Timeout(query(0, true)));
+        printer.record(&PingEvent::Timeout(query(1, false)));
+        printer.record(&PingEvent::Abort(
+            None,
+            io::Error::from(io::ErrorKind::ConnectionRefused),
+        ));
+        let stats = printer.stats.lock().unwrap();
+        assert_eq!(stats.send, 1);
+        assert_eq!(loss_rate(stats.send, stats.recv), 100.0);
+        drop(stats);
+
+        printer.record(&PingEvent::Abort(
+            Some(query(2, false)),
+            io::Error::from(io::ErrorKind::ConnectionRefused),
+        ));
+        assert_eq!(printer.stats.lock().unwrap().send, 2);
+    }
+
+    /// Represents an output shared with the test to read the lines printed.
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
         }
-        Err(_) => unreachable!(),
+    }
+
+    #[test]
+    fn printer_rate_csv() {
+        // The server answers the first query only
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buffer = [0u8; 512];
+            let (size, client) = server.recv_from(&mut buffer).unwrap();
+            buffer[2] |= 0x80;
+            server.send_to(&buffer[..size], client).unwrap();
+        });
+        let flags = Flags::from_iter(&[
+            "dnsping",
+            "127.0.0.1",
+            "--qps",
+            "20",
+            "--count",
+            "2",
+            "--csv",
+            "--timeout",
+            "200",
+        ]);
+        let pinger = Pinger::new(addr)
+            .host(&flags.host[0])
+            .timeout(Some(Duration::from_millis(flags.timeout)));
+        let name = addr.to_string();
+        let mut printer = Printer::new(&name, &pinger, &flags.host, &flags, false);
+        let output = Output::default();
+        printer.out = Box::new(output.clone());
+        let stats = Arc::clone(&printer.stats);
+        let (_stop_tx, stop_rx) = mpsc::channel();
+
+        let config = ping_config(pinger, &flags.host, &flags);
+        dnsping::run_events(config, stop_rx, |event| printer.handle(event));
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        let rows = output.lines().collect::<Vec<_>>();
+        assert_eq!(rows.len(), 2);
+        assert!(rows[0].starts_with("0,"));
+        assert!(rows[0].ends_with(",NOERROR,true"));
+        assert!(rows[1].starts_with("1,"));
+        assert!(rows[1].ends_with(&format!(",{},,,,false", addr)));
+        let stats = stats.lock().unwrap();
+        assert_eq!(stats.send, 2);
+        assert_eq!(stats.recv, 1);
+    }
+
+    #[test]
+    fn count_config() {
+        let pinger = || Pinger::new("127.0.0.1:53".parse().unwrap());
+        let flags = Flags::from_iter(&["dnsping", "127.0.0.1", "--count", "3"]);
+        let config = ping_config(pinger(), &flags.host, &flags);
+        assert_eq!(config.count, Some(3));
+        assert!(!config.count_received);
+        let flags = Flags::from_iter(&[
+            "dnsping",
+            "127.0.0.1",
+            "--count",
+            "inf",
+            "--count-mode",
+            "received",
+        ]);
+        let config = ping_config(pinger(), &flags.host, &flags);
+        assert_eq!(config.count, None);
+        assert!(config.count_received);
+        assert!("lost".parse::<CountMode>().is_err());
     }
 }
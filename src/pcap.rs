@@ -0,0 +1,391 @@
+//! Capture of the queries and responses in the pcap format for offline analysis.
+//!
+//! Each query and response is written as a raw IP packet with synthetic IP and UDP headers, so
+//! that the capture can be opened in Wireshark as a DNS exchange regardless of the transport.
+
+use super::RW;
+use std::io::{Error, Result, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Link type of raw IP packets.
+const LINKTYPE_RAW: u32 = 101;
+/// Protocol number of UDP.
+const UDP: u8 = 17;
+
+/// Represents a writer of datagrams in the pcap format.
+pub struct PcapWriter {
+    writer: Box<dyn Write + Send>,
+    failed: bool,
+    error: Option<Error>,
+}
+
+impl PcapWriter {
+    /// Creates a new `PcapWriter` and writes the global header.
+    pub fn new<W: Write + Send + 'static>(mut writer: W) -> Result<PcapWriter> {
+        let mut header = Vec::new();
+        header.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&(u16::MAX as u32).to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        writer.write_all(&header)?;
+        writer.flush()?;
+
+        Ok(PcapWriter {
+            writer: Box::new(writer),
+            failed: false,
+            error: None,
+        })
+    }
+
+    /// Returns the error which stopped the capture of a `Capture`, only once.
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.error.take()
+    }
+
+    /// Writes a record of the datagram for a `Capture` unless a record failed to be written
+    /// before. The first error is kept for `take_error` instead of failing the transport, and the
+    /// capture stops there as the file may be corrupted.
+    fn capture(&mut self, source: SocketAddr, destination: SocketAddr, data: &[u8]) {
+        if self.failed {
+            return;
+        }
+        if let Err(e) = self.write_datagram(source, destination, data) {
+            self.failed = true;
+            self.error = Some(e);
+        }
+    }
+
+    /// Writes a record of the datagram from the source to the destination, which is flushed
+    /// immediately so the capture is complete even if pinging stops abruptly.
+    pub fn write_datagram(
+        &mut self,
+        source: SocketAddr,
+        destination: SocketAddr,
+        data: &[u8],
+    ) -> Result<()> {
+        let packet = ip_packet(source, destination, data);
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::from_secs(0));
+
+        let mut record = Vec::with_capacity(16 + packet.len());
+        record.extend_from_slice(&(timestamp.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&timestamp.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&packet);
+        self.writer.write_all(&record)?;
+
+        self.writer.flush()
+    }
+}
+
+fn checksum(data: &[u8], initial: u32) -> u16 {
+    let mut sum = initial;
+    for chunk in data.chunks(2) {
+        let word = match chunk {
+            [high, low] => u16::from_be_bytes([*high, *low]),
+            [high] => u16::from_be_bytes([*high, 0]),
+            _ => unreachable!(),
+        };
+        sum += word as u32;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+/// Builds a raw IP packet of the datagram with an IPv4 or IPv6 header and a UDP header.
+fn ip_packet(source: SocketAddr, destination: SocketAddr, data: &[u8]) -> Vec<u8> {
+    let udp_len = 8 + data.len();
+    let mut udp = Vec::with_capacity(udp_len);
+    udp.extend_from_slice(&source.port().to_be_bytes());
+    udp.extend_from_slice(&destination.port().to_be_bytes());
+    udp.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    udp.extend_from_slice(&0u16.to_be_bytes());
+    udp.extend_from_slice(data);
+
+    // The UDP checksum covers a pseudo header of the addresses
+    let (source_ip, destination_ip) = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            (IpAddr::V4(source), IpAddr::V4(destination))
+        }
+        (source, destination) => (
+            IpAddr::V6(to_ipv6(source)),
+            IpAddr::V6(to_ipv6(destination)),
+        ),
+    };
+    let mut pseudo = Vec::new();
+    match (source_ip, destination_ip) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            pseudo.extend_from_slice(&source.octets());
+            pseudo.extend_from_slice(&destination.octets());
+        }
+        (IpAddr::V6(source), IpAddr::V6(destination)) => {
+            pseudo.extend_from_slice(&source.octets());
+            pseudo.extend_from_slice(&destination.octets());
+        }
+        _ => unreachable!(),
+    }
+    pseudo.extend_from_slice(&(udp_len as u32).to_be_bytes());
+    pseudo.extend_from_slice(&(UDP as u32).to_be_bytes());
+    let initial = pseudo
+        .chunks(2)
+        .map(|chunk| u16::from_be_bytes([chunk[0], chunk[1]]) as u32)
+        .sum();
+    let udp_checksum = match checksum(&udp, initial) {
+        0 => 0xffff,
+        udp_checksum => udp_checksum,
+    };
+    udp[6..8].copy_from_slice(&udp_checksum.to_be_bytes());
+
+    let mut packet = Vec::new();
+    match (source_ip, destination_ip) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            packet.push(0x45);
+            packet.push(0);
+            packet.extend_from_slice(&((20 + udp_len) as u16).to_be_bytes());
+            packet.extend_from_slice(&0u16.to_be_bytes());
+            packet.extend_from_slice(&0x4000u16.to_be_bytes());
+            packet.push(64);
+            packet.push(UDP);
+            packet.extend_from_slice(&0u16.to_be_bytes());
+            packet.extend_from_slice(&source.octets());
+            packet.extend_from_slice(&destination.octets());
+            let header_checksum = checksum(&packet, 0);
+            packet[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+        }
+        (IpAddr::V6(source), IpAddr::V6(destination)) => {
+            packet.extend_from_slice(&0x6000_0000u32.to_be_bytes());
+            packet.extend_from_slice(&(udp_len as u16).to_be_bytes());
+            packet.push(UDP);
+            packet.push(64);
+            packet.extend_from_slice(&source.octets());
+            packet.extend_from_slice(&destination.octets());
+        }
+        _ => unreachable!(),
+    }
+    packet.extend_from_slice(&udp);
+
+    packet
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Represents a transport writing the datagrams sent and received to a pcap writer, which may be
+/// shared by several transports.
+pub struct Capture {
+    rw: Box<dyn RW>,
+    local: SocketAddr,
+    pcap: Arc<Mutex<PcapWriter>>,
+}
+
+impl Capture {
+    /// Creates a new `Capture` of the transport. The local address of the transport is shown in
+    /// the capture, or an unspecified address of the port if the transport has none.
+    pub fn new(
+        rw: Box<dyn RW>,
+        addr: SocketAddr,
+        port: u16,
+        pcap: Arc<Mutex<PcapWriter>>,
+    ) -> Capture {
+        let local = match (rw.local_addr(), addr) {
+            (Ok(local), _) => local,
+            (Err(_), SocketAddr::V4(_)) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port),
+            (Err(_), SocketAddr::V6(_)) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port),
+        };
+
+        Capture { rw, local, pcap }
+    }
+}
+
+impl RW for Capture {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize> {
+        let size = self.rw.send_to(buf, addr)?;
+        self.pcap
+            .lock()
+            .unwrap()
+            .capture(self.local, addr, &buf[..size]);
+
+        Ok(size)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        let (size, addr) = self.rw.recv_from(buf)?;
+        self.pcap
+            .lock()
+            .unwrap()
+            .capture(addr, self.local, &buf[..size]);
+
+        Ok((size, addr))
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> Result<()> {
+        self.rw.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> Result<()> {
+        self.rw.set_write_timeout(dur)
+    }
+
+    fn read_timeout(&self) -> Result<Option<Duration>> {
+        self.rw.read_timeout()
+    }
+
+    fn write_timeout(&self) -> Result<Option<Duration>> {
+        self.rw.write_timeout()
+    }
+
+    fn set_ttl(&self, ttl: u32) -> Result<()> {
+        self.rw.set_ttl(ttl)
+    }
+
+    fn set_tos(&self, tos: u8) -> Result<()> {
+        self.rw.set_tos(tos)
+    }
+
+    fn set_recv_buffer_size(&self, size: usize) -> Result<()> {
+        self.rw.set_recv_buffer_size(size)
+    }
+
+    fn set_send_buffer_size(&self, size: usize) -> Result<()> {
+        self.rw.set_send_buffer_size(size)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.local)
+    }
+
+    fn first_byte(&self) -> Result<Instant> {
+        self.rw.first_byte()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Represents a writer to a buffer shared with the test.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn pcap_ipv4_record() {
+        let buffer = Shared::default();
+        let mut pcap = PcapWriter::new(buffer.clone()).unwrap();
+        let source = "10.0.0.1:5353".parse().unwrap();
+        let destination = "10.0.0.2:53".parse().unwrap();
+        pcap.write_datagram(source, destination, b"dns").unwrap();
+
+        let data = buffer.0.lock().unwrap().clone();
+        assert_eq!(&data[..4], &[0xd4, 0xc3, 0xb2, 0xa1]);
+        assert_eq!(&data[20..24], &LINKTYPE_RAW.to_le_bytes());
+        let packet = &data[24 + 16..];
+        assert_eq!(packet.len(), 20 + 8 + 3);
+        assert_eq!(&data[32..36], &(packet.len() as u32).to_le_bytes());
+        assert_eq!(packet[9], UDP);
+        assert_eq!(checksum(&packet[..20], 0), 0);
+        assert_eq!(&packet[20..22], &5353u16.to_be_bytes());
+        assert_eq!(&packet[22..24], &53u16.to_be_bytes());
+        assert_eq!(&packet[28..], b"dns");
+    }
+
+    #[test]
+    fn capture_exchange() {
+        use crate::testing::{MockRW, Step};
+
+        let buffer = Shared::default();
+        let pcap = Arc::new(Mutex::new(PcapWriter::new(buffer.clone()).unwrap()));
+        let server = "10.0.0.2:53".parse().unwrap();
+        let rw = MockRW::new(vec![Step::new(Duration::ZERO, server, b"reply".to_vec())]);
+        let capture = Capture::new(Box::new(rw), server, 5353, pcap);
+        capture.send_to(b"query", server).unwrap();
+        let mut buf = [0; 16];
+        capture.recv_from(&mut buf).unwrap();
+
+        let data = buffer.0.lock().unwrap().clone();
+        let record_len = 16 + 20 + 8 + 5;
+        assert_eq!(data.len(), 24 + 2 * record_len);
+        let query = &data[24 + 16..24 + record_len];
+        assert_eq!(&query[16..20], &[10, 0, 0, 2]);
+        assert_eq!(&query[20..22], &5353u16.to_be_bytes());
+        assert_eq!(&query[28..], b"query");
+        let reply = &data[24 + record_len + 16..];
+        assert_eq!(&reply[12..16], &[10, 0, 0, 2]);
+        assert_eq!(&reply[22..24], &5353u16.to_be_bytes());
+        assert_eq!(&reply[28..], b"reply");
+    }
+
+    /// Represents a writer failing after the global header.
+    struct Full(usize);
+
+    impl Write for Full {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            match self.0 {
+                0 => Err(Error::from(std::io::ErrorKind::WriteZero)),
+                _ => {
+                    self.0 -= 1;
+                    Ok(buf.len())
+                }
+            }
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn capture_write_error() {
+        use crate::testing::{MockRW, Step};
+
+        let pcap = Arc::new(Mutex::new(PcapWriter::new(Full(1)).unwrap()));
+        let server = "10.0.0.2:53".parse().unwrap();
+        let rw = MockRW::new(vec![Step::new(Duration::ZERO, server, b"reply".to_vec())]);
+        let capture = Capture::new(Box::new(rw), server, 5353, Arc::clone(&pcap));
+        assert_eq!(capture.send_to(b"query", server).unwrap(), 5);
+        let mut buf = [0; 16];
+        assert_eq!(capture.recv_from(&mut buf).unwrap(), (5, server));
+
+        let e = pcap.lock().unwrap().take_error().unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::WriteZero);
+        assert!(pcap.lock().unwrap().take_error().is_none());
+    }
+
+    #[test]
+    fn pcap_ipv6_record() {
+        let packet = ip_packet(
+            "[::1]:5353".parse().unwrap(),
+            "[::2]:53".parse().unwrap(),
+            b"dns",
+        );
+        assert_eq!(packet.len(), 40 + 8 + 3);
+        assert_eq!(packet[0] >> 4, 6);
+        assert_eq!(&packet[4..6], &11u16.to_be_bytes());
+        assert_ne!(&packet[46..48], &[0, 0]);
+    }
+}
@@ -0,0 +1,169 @@
+//! Utilities for testing without a real socket.
+
+use super::RW;
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// Represents a canned response of a `MockRW`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Step {
+    /// Delay before the response arrives.
+    pub delay: Duration,
+    /// Source address of the response.
+    pub addr: SocketAddr,
+    /// Data of the response.
+    pub data: Vec<u8>,
+    /// Error returned instead of the response.
+    pub error: Option<ErrorKind>,
+}
+
+impl Step {
+    /// Creates a new `Step`.
+    pub fn new(delay: Duration, addr: SocketAddr, data: Vec<u8>) -> Step {
+        Step {
+            delay,
+            addr,
+            data,
+            error: None,
+        }
+    }
+
+    /// Creates a new `Step` returning an error of the kind after the delay.
+    pub fn error(delay: Duration, kind: ErrorKind) -> Step {
+        Step {
+            delay,
+            addr: SocketAddr::from(([0, 0, 0, 0], 0)),
+            data: Vec::new(),
+            error: Some(kind),
+        }
+    }
+}
+
+/// Represents a socket replaying a script of canned responses. A read times out if the script is
+/// exhausted or the delay of the next response exceeds the read timeout.
+#[derive(Debug, Default)]
+pub struct MockRW {
+    script: Mutex<VecDeque<Step>>,
+    sent: Mutex<Vec<(Vec<u8>, SocketAddr)>>,
+    read_timeout: Mutex<Option<Duration>>,
+    write_timeout: Mutex<Option<Duration>>,
+}
+
+impl MockRW {
+    /// Creates a new `MockRW` with the given script.
+    pub fn new(script: Vec<Step>) -> MockRW {
+        MockRW {
+            script: Mutex::new(script.into_iter().collect()),
+            ..Default::default()
+        }
+    }
+
+    /// Appends a canned response to the script.
+    pub fn push(&self, step: Step) {
+        self.script.lock().unwrap().push_back(step);
+    }
+
+    /// Returns the datagrams sent on the socket.
+    pub fn sent(&self) -> Vec<(Vec<u8>, SocketAddr)> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+impl RW for MockRW {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize> {
+        self.sent.lock().unwrap().push((buf.to_vec(), addr));
+
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        let timeout = *self.read_timeout.lock().unwrap();
+        let step = self.script.lock().unwrap().pop_front();
+
+        match step {
+            Some(step) => {
+                if let Some(timeout) = timeout {
+                    if step.delay > timeout {
+                        thread::sleep(timeout);
+                        return Err(Error::from(ErrorKind::TimedOut));
+                    }
+                }
+                thread::sleep(step.delay);
+                if let Some(kind) = step.error {
+                    return Err(Error::from(kind));
+                }
+
+                let size = step.data.len().min(buf.len());
+                buf[..size].copy_from_slice(&step.data[..size]);
+
+                Ok((size, step.addr))
+            }
+            None => {
+                if let Some(timeout) = timeout {
+                    thread::sleep(timeout);
+                }
+
+                Err(Error::from(ErrorKind::TimedOut))
+            }
+        }
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> Result<()> {
+        *self.read_timeout.lock().unwrap() = dur;
+
+        Ok(())
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> Result<()> {
+        *self.write_timeout.lock().unwrap() = dur;
+
+        Ok(())
+    }
+
+    fn read_timeout(&self) -> Result<Option<Duration>> {
+        Ok(*self.read_timeout.lock().unwrap())
+    }
+
+    fn write_timeout(&self) -> Result<Option<Duration>> {
+        Ok(*self.write_timeout.lock().unwrap())
+    }
+}
+
+/// Builds a DNS response to an A query of the host with the given response code and answers of
+/// addresses and TTLs.
+pub fn response(id: u16, host: &str, rcode: u8, answers: &[(Ipv4Addr, u32)]) -> Vec<u8> {
+    let mut buffer = Vec::new();
+
+    // Header
+    buffer.extend_from_slice(&id.to_be_bytes());
+    buffer.extend_from_slice(&(0x8180 | rcode as u16).to_be_bytes());
+    buffer.extend_from_slice(&1u16.to_be_bytes());
+    buffer.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(&0u16.to_be_bytes());
+    buffer.extend_from_slice(&0u16.to_be_bytes());
+
+    // Question
+    for label in host.split('.').filter(|label| !label.is_empty()) {
+        buffer.push(label.len() as u8);
+        buffer.extend_from_slice(label.as_bytes());
+    }
+    buffer.push(0);
+    buffer.extend_from_slice(&1u16.to_be_bytes());
+    buffer.extend_from_slice(&1u16.to_be_bytes());
+
+    // Answers
+    for (ip, ttl) in answers {
+        buffer.extend_from_slice(&0xc00cu16.to_be_bytes());
+        buffer.extend_from_slice(&1u16.to_be_bytes());
+        buffer.extend_from_slice(&1u16.to_be_bytes());
+        buffer.extend_from_slice(&ttl.to_be_bytes());
+        buffer.extend_from_slice(&4u16.to_be_bytes());
+        buffer.extend_from_slice(&ip.octets());
+    }
+
+    buffer
+}